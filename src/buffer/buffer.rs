@@ -1,8 +1,30 @@
-use std::path::PathBuf;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use unicode_segmentation::UnicodeSegmentation;
+
+use super::search::Pattern;
+use crate::syntax::{Highlighter, TokenKind};
+
+/// Distinct lines kept by the highlight cache before it starts over.
+const MAX_HIGHLIGHTED_LINES: usize = 10_000;
+/// Lines looked at when guessing a file's indentation.
+const INDENT_SAMPLE_LINES: usize = 1000;
+/// Files larger than this are read in chunks, so the first screen shows up
+/// before the rest of the file is in memory.
+const LARGE_FILE_BYTES: u64 = 16 * 1024 * 1024;
+/// Bytes read per chunk of a large file.
+const LOAD_CHUNK_BYTES: usize = 4 * 1024 * 1024;
 
 #[derive(Clone)]
 pub struct GapBuffer {
     before: Vec<u8>,
+    /// The text after the gap, last byte first, so moving the gap either way
+    /// only pushes and pops at the ends of the two vectors.
     after: Vec<u8>,
     line_offsets: Vec<usize>,
 }
@@ -12,10 +34,12 @@ impl GapBuffer {
         Self {
             before: Vec::new(),
             after: Vec::new(),
-            line_offsets: vec![0],
+            line_offsets: vec![0, 0],
         }
     }
 
+    /// Rebuilds `line_offsets` from scratch: the start of every line, then
+    /// the total length. Edits update it incrementally instead.
     fn build_cache(&mut self) {
         self.line_offsets.clear();
         self.line_offsets.push(0);
@@ -27,7 +51,7 @@ impl GapBuffer {
         }
 
         let after_start = self.before.len();
-        for (i, &byte) in self.after.iter().enumerate() {
+        for (i, &byte) in self.after.iter().rev().enumerate() {
             if byte == b'\n' {
                 self.line_offsets.push(after_start + i + 1);
             }
@@ -38,8 +62,13 @@ impl GapBuffer {
 
     #[allow(dead_code)]
     pub fn from_string(s: &str) -> Self {
+        Self::from_text(s.to_string())
+    }
+
+    /// Takes ownership of `text` so large files aren't copied again.
+    pub fn from_text(text: String) -> Self {
         let mut buf = Self::new();
-        buf.before = s.as_bytes().to_vec();
+        buf.before = text.into_bytes();
         buf.build_cache();
         buf
     }
@@ -64,17 +93,32 @@ impl GapBuffer {
 
         let before_end = self.before.len();
 
-        if start < before_end && end <= before_end {
+        if end <= before_end {
             String::from_utf8_lossy(&self.before[start..end]).to_string()
         } else if start >= before_end {
-            let after_start = start - before_end;
-            let after_end = (end - before_end).min(self.after.len());
-            String::from_utf8_lossy(&self.after[after_start..after_end]).to_string()
+            String::from_utf8_lossy(&self.after_bytes(start - before_end, end - before_end))
+                .to_string()
         } else {
-            let mut result = String::from_utf8_lossy(&self.before[start..]).to_string();
-            let after_end = (end - before_end).min(self.after.len());
-            result.push_str(&String::from_utf8_lossy(&self.after[..after_end]));
-            result
+            let mut bytes = self.before[start..].to_vec();
+            bytes.extend(self.after_bytes(0, end - before_end));
+            String::from_utf8_lossy(&bytes).to_string()
+        }
+    }
+
+    /// Bytes `start..end` of the text after the gap, counted from the gap.
+    fn after_bytes(&self, start: usize, end: usize) -> Vec<u8> {
+        let len = self.after.len();
+        self.after[len - end..len - start]
+            .iter()
+            .rev()
+            .copied()
+            .collect()
+    }
+
+    fn byte_at(&self, pos: usize) -> u8 {
+        match pos.checked_sub(self.before.len()) {
+            None => self.before[pos],
+            Some(i) => self.after[self.after.len() - 1 - i],
         }
     }
 
@@ -83,117 +127,105 @@ impl GapBuffer {
         let gap_pos = self.before.len();
 
         if pos < gap_pos {
-            let mut tmp = self.before[pos..].to_vec();
-            self.after.append(&mut tmp);
-            self.before.truncate(pos);
+            self.after.extend(self.before.drain(pos..).rev());
         } else if pos > gap_pos {
-            let mut tmp = self.after[..pos - gap_pos].to_vec();
-            tmp.append(&mut self.before);
-            self.before = tmp;
-            self.after.clear();
+            let keep = self.after.len() - (pos - gap_pos);
+            self.before.extend(self.after.drain(keep..).rev());
         }
     }
 
     pub fn insert(&mut self, pos: usize, text: &str) {
         self.move_gap(pos);
+        let pos = self.before.len();
         self.before.extend_from_slice(text.as_bytes());
-        self.build_cache();
+
+        // Lines starting after `pos` move by the inserted length, and every
+        // newline inserted starts a line of its own.
+        let first = self.first_line_after(pos);
+        for offset in &mut self.line_offsets[first..] {
+            *offset += text.len();
+        }
+        let starts = text
+            .bytes()
+            .enumerate()
+            .filter(|&(_, b)| b == b'\n')
+            .map(|(i, _)| pos + i + 1);
+        self.line_offsets.splice(first..first, starts);
     }
 
     pub fn delete(&mut self, pos: usize, len: usize) {
         self.move_gap(pos);
+        let pos = self.before.len();
         let del_len = len.min(self.after.len());
-        self.after.drain(..del_len);
+        self.after.truncate(self.after.len() - del_len);
+
+        // Lines starting inside the deleted range are gone; later ones move.
+        let first = self.first_line_after(pos);
+        let end = self.first_line_after(pos + del_len);
+        self.line_offsets.drain(first..end);
+        for offset in &mut self.line_offsets[first..] {
+            *offset -= del_len;
+        }
+    }
+
+    /// Replaces `(pos, len, text)` ranges, given last first, in one sweep of
+    /// the gap towards the start. Line starts are rebuilt once at the end
+    /// rather than shifted for every edit.
+    pub fn replace_ranges<'a>(&mut self, edits: impl IntoIterator<Item = (usize, usize, &'a str)>) {
+        for (pos, len, text) in edits {
+            self.move_gap(pos + len);
+            self.before.truncate(pos);
+            self.after.extend(text.bytes().rev());
+        }
         self.build_cache();
     }
 
+    /// Index in `line_offsets` of the first line start after `pos`. The
+    /// trailing total length always counts as after.
+    fn first_line_after(&self, pos: usize) -> usize {
+        let sentinel = self.line_offsets.len() - 1;
+        1 + self.line_offsets[1..sentinel].partition_point(|&o| o <= pos)
+    }
+
     pub fn get_line(&self, line_num: usize) -> String {
-        if self.line_offsets.len() <= 1 {
+        if line_num + 1 >= self.line_offsets.len() {
             return String::new();
         }
+        let start = self.line_offsets[line_num];
+        self.get_range(start, start + self.line_len(line_num))
+    }
 
-        if line_num >= self.line_offsets.len() - 1 {
-            return String::new();
+    /// Length of a line in bytes, not counting its trailing newline.
+    pub fn line_len(&self, line_num: usize) -> usize {
+        if line_num + 1 >= self.line_offsets.len() {
+            return 0;
         }
-
         let start = self.line_offsets[line_num];
         let mut end = self.line_offsets[line_num + 1];
-
-        if end == 0 {
-            return String::new();
+        if end > start && self.byte_at(end - 1) == b'\n' {
+            end -= 1;
         }
-
-        if start < self.before.len() {
-            let before_end = self.before.len();
-            if end <= before_end {
-                let actual_end = if end > 0 && self.before[end.saturating_sub(1)] == b'\n' {
-                    end - 1
-                } else {
-                    end
-                };
-                if actual_end > start {
-                    return String::from_utf8_lossy(&self.before[start..actual_end]).to_string();
-                }
-                return String::new();
-            } else {
-                let before_part_end = before_end.min(end);
-                let actual_before_end = if before_part_end > start
-                    && before_part_end <= self.before.len()
-                    && self.before[before_part_end.saturating_sub(1)] == b'\n'
-                {
-                    before_part_end - 1
-                } else {
-                    before_part_end
-                };
-                let before_part = &self.before[start..actual_before_end];
-                let after_end = (end - before_end).min(self.after.len());
-                let actual_after_end =
-                    if after_end > 0 && self.after[after_end.saturating_sub(1)] == b'\n' {
-                        after_end - 1
-                    } else {
-                        after_end
-                    };
-                let after_part = &self.after[..actual_after_end];
-                let mut result = Vec::with_capacity(before_part.len() + after_part.len());
-                result.extend_from_slice(before_part);
-                result.extend_from_slice(after_part);
-                return String::from_utf8_lossy(&result).to_string();
-            }
-        } else {
-            let after_start = start - self.before.len();
-            let after_end = (end - self.before.len()).min(self.after.len());
-            if after_start < self.after.len() {
-                let actual_end = if after_end > after_start
-                    && self.after[after_end.saturating_sub(1)] == b'\n'
-                {
-                    after_end - 1
-                } else {
-                    after_end
-                };
-                if actual_end > after_start {
-                    return String::from_utf8_lossy(&self.after[after_start..actual_end])
-                        .to_string();
-                }
-            }
-        }
-
-        String::new()
+        end - start
     }
 
+    /// Number of lines in the buffer. An empty buffer still has one (empty)
+    /// line so callers can always address line 0.
     pub fn num_lines(&self) -> usize {
         if self.line_offsets.len() <= 1 {
             return 1;
         }
         let len = self.line_offsets.len();
-        if self.line_offsets[len - 1] == self.line_offsets[len - 2] {
+        let lines = if self.line_offsets[len - 1] == self.line_offsets[len - 2] {
             len - 2
         } else {
             len - 1
-        }
+        };
+        lines.max(1)
     }
 
-    pub fn get_line_offsets(&self) -> Vec<usize> {
-        self.line_offsets.clone()
+    /// Start of every line, followed by the total length.
+    pub fn line_offsets(&self) -> &[usize] {
+        &self.line_offsets
     }
 
     #[allow(dead_code)]
@@ -216,7 +248,7 @@ impl GapBuffer {
             return offset;
         }
 
-        for (i, &byte) in self.after.iter().enumerate() {
+        for (i, &byte) in self.after.iter().rev().enumerate() {
             if current == line_num {
                 return before_len + i;
             }
@@ -229,12 +261,6 @@ impl GapBuffer {
         self.len()
     }
 
-    pub fn to_string(&self) -> String {
-        let mut result = String::from_utf8_lossy(&self.before).to_string();
-        result.push_str(&String::from_utf8_lossy(&self.after));
-        result
-    }
-
     #[allow(dead_code)]
     pub fn to_lines(&self) -> Vec<String> {
         self.to_string().lines().map(|s| s.to_string()).collect()
@@ -247,74 +273,522 @@ impl Default for GapBuffer {
     }
 }
 
+impl fmt::Display for GapBuffer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&String::from_utf8_lossy(&self.before))?;
+        f.write_str(&String::from_utf8_lossy(
+            &self.after_bytes(0, self.after.len()),
+        ))
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SaveStatus {
+    Saved,
+    /// The buffer has no file yet and needs a "save as".
+    NoPath,
+}
+
+/// Line terminator a file is saved with. The text itself always uses `\n`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LineEnding {
+    Lf,
+    CrLf,
+}
+
+impl LineEnding {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            LineEnding::Lf => "LF",
+            LineEnding::CrLf => "CRLF",
+        }
+    }
+}
+
+/// How a file's text is stored on disk, told apart by its byte order mark.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Encoding {
+    Utf8,
+    Utf8Bom,
+    Utf16Le,
+    Utf16Be,
+}
+
+impl Encoding {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Encoding::Utf8 => "UTF-8",
+            Encoding::Utf8Bom => "UTF-8 BOM",
+            Encoding::Utf16Le => "UTF-16LE",
+            Encoding::Utf16Be => "UTF-16BE",
+        }
+    }
+
+    /// `text` as bytes in this encoding, byte order mark first.
+    pub fn encode(self, text: &str) -> Vec<u8> {
+        match self {
+            Encoding::Utf8 => text.as_bytes().to_vec(),
+            Encoding::Utf8Bom => [UTF8_BOM, text.as_bytes()].concat(),
+            Encoding::Utf16Le => UTF16LE_BOM
+                .iter()
+                .copied()
+                .chain(text.encode_utf16().flat_map(u16::to_le_bytes))
+                .collect(),
+            Encoding::Utf16Be => UTF16BE_BOM
+                .iter()
+                .copied()
+                .chain(text.encode_utf16().flat_map(u16::to_be_bytes))
+                .collect(),
+        }
+    }
+}
+
+const UTF8_BOM: &[u8] = &[0xEF, 0xBB, 0xBF];
+const UTF16LE_BOM: &[u8] = &[0xFF, 0xFE];
+const UTF16BE_BOM: &[u8] = &[0xFE, 0xFF];
+
+/// Decodes a file's bytes, going by its byte order mark and assuming UTF-8
+/// without one. Text that doesn't decode or contains NUL characters is
+/// refused, since editing it would corrupt it on save.
+pub fn decode(mut bytes: Vec<u8>) -> std::io::Result<(Encoding, String)> {
+    let invalid = |message: String| std::io::Error::new(std::io::ErrorKind::InvalidData, message);
+    let (encoding, content) = if bytes.starts_with(UTF16LE_BOM) || bytes.starts_with(UTF16BE_BOM) {
+        let little_endian = bytes.starts_with(UTF16LE_BOM);
+        let encoding = if little_endian {
+            Encoding::Utf16Le
+        } else {
+            Encoding::Utf16Be
+        };
+        let not_valid = || invalid(format!("not valid {}", encoding.as_str()));
+        let pairs = bytes[2..].chunks_exact(2);
+        if !pairs.remainder().is_empty() {
+            return Err(not_valid());
+        }
+        let units: Vec<u16> = pairs
+            .map(|pair| {
+                let pair = [pair[0], pair[1]];
+                if little_endian {
+                    u16::from_le_bytes(pair)
+                } else {
+                    u16::from_be_bytes(pair)
+                }
+            })
+            .collect();
+        (
+            encoding,
+            String::from_utf16(&units).map_err(|_| not_valid())?,
+        )
+    } else {
+        let encoding = if bytes.starts_with(UTF8_BOM) {
+            bytes.drain(..UTF8_BOM.len());
+            Encoding::Utf8Bom
+        } else {
+            Encoding::Utf8
+        };
+        if bytes.contains(&0) {
+            return Err(invalid("binary file".into()));
+        }
+        let content = String::from_utf8(bytes).map_err(|e| {
+            invalid(format!(
+                "not valid UTF-8 (byte {})",
+                e.utf8_error().valid_up_to()
+            ))
+        })?;
+        (encoding, content)
+    };
+    if content.contains('\0') {
+        return Err(invalid("binary file".into()));
+    }
+    Ok((encoding, content))
+}
+
+/// Whether the current user can write to `path`, found by opening it for
+/// writing. Permission bits alone don't tell, e.g. for root or ACLs.
+fn is_writable(path: &Path) -> bool {
+    std::fs::OpenOptions::new().write(true).open(path).is_ok()
+}
+
+/// One level of indentation.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Indent {
+    Tabs,
+    Spaces(usize),
+}
+
+impl fmt::Display for Indent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Indent::Tabs => f.write_str("Tabs"),
+            Indent::Spaces(n) => write!(f, "Spaces: {}", n),
+        }
+    }
+}
+
 #[derive(Clone)]
 pub struct Buffer {
     pub text: GapBuffer,
     pub path: Option<PathBuf>,
     pub is_modified: bool,
     pub language: String,
-    pub line_offsets: Vec<usize>,
+    /// Bumped on every edit, so callers can tell whether the text changed.
+    pub revision: u64,
+    /// Modification time of the file when it was last loaded or saved.
+    pub disk_mtime: Option<SystemTime>,
+    /// Whether the file ended with a newline when loaded. The text always
+    /// keeps one, so saving drops it again when the file had none.
+    pub final_newline: bool,
+    pub line_ending: LineEnding,
+    /// How the file was decoded, and is saved again.
+    pub encoding: Encoding,
+    /// The file mixed `\n` and `\r\n` endings; saving writes only
+    /// `line_ending`.
+    pub mixed_line_endings: bool,
+    /// The file on disk wasn't writable when it was loaded.
+    pub read_only: bool,
+    /// Indentation the text used when loaded, if it had enough to tell.
+    pub indent: Option<Indent>,
+    /// Byte offsets of marked lines, kept in order and moved along by edits.
+    marks: Vec<usize>,
+    highlights: RefCell<HighlightCache>,
+    /// Where reading a large file left off. The buffer holds only part of
+    /// the file meanwhile, so it must not be edited or saved.
+    loading: Option<Loader>,
+}
+
+/// Progress through a file read in chunks.
+#[derive(Clone, Debug, Default)]
+struct Loader {
+    /// Bytes of the file read so far.
+    offset: u64,
+    /// Size of the file when loading started, for reporting progress.
+    size: u64,
+    /// Bytes held back from the last chunk: the start of a character split
+    /// by the chunk boundary, or a `\r` that may begin a `\r\n`.
+    carry: Vec<u8>,
+    crlf: usize,
+    lf: usize,
+    /// Reading failed part way, leaving the buffer partial for good.
+    stopped: bool,
+}
+
+/// Syntax spans by line text, for the language they were highlighted as.
+#[derive(Clone, Default)]
+struct HighlightCache {
+    language: String,
+    lines: HashMap<String, Vec<(Range<usize>, TokenKind)>>,
 }
 
 impl Buffer {
     pub fn new() -> Self {
         let mut text = GapBuffer::new();
         text.insert(0, "\n");
-        let offsets = text.get_line_offsets();
-        let buf = Self {
+
+        Self {
             text,
             path: None,
             is_modified: false,
             language: "plaintext".to_string(),
-            line_offsets: offsets,
+            revision: 0,
+            disk_mtime: None,
+            final_newline: false,
+            line_ending: LineEnding::Lf,
+            encoding: Encoding::Utf8,
+            mixed_line_endings: false,
+            read_only: false,
+            indent: None,
+            marks: Vec::new(),
+            highlights: RefCell::default(),
+            loading: None,
+        }
+    }
+
+    /// Loads a text file, decoded as `decode` does. Large UTF-8 files are
+    /// read in chunks; UTF-16 ones are always read whole.
+    pub fn from_file(path: PathBuf) -> std::io::Result<Self> {
+        if std::fs::metadata(&path)?.len() > LARGE_FILE_BYTES && !starts_with_utf16_bom(&path)? {
+            return Self::from_file_in_chunks(path, LOAD_CHUNK_BYTES);
+        }
+        let (encoding, content) = decode(std::fs::read(&path)?)?;
+        Ok(Self {
+            language: detect_language(&path),
+            disk_mtime: file_mtime(&path),
+            read_only: !is_writable(&path),
+            path: Some(path),
+            encoding,
+            ..Self::with_text(content)
+        })
+    }
+
+    /// Starts loading a large file, reading only its first `chunk_size`
+    /// bytes. `load_more` reads the rest.
+    fn from_file_in_chunks(path: PathBuf, chunk_size: usize) -> std::io::Result<Self> {
+        let metadata = std::fs::metadata(&path)?;
+        let mut buffer = Self {
+            text: GapBuffer::new(),
+            language: detect_language(&path),
+            disk_mtime: file_mtime(&path),
+            read_only: !is_writable(&path),
+            path: Some(path),
+            loading: Some(Loader {
+                size: metadata.len(),
+                ..Loader::default()
+            }),
+            ..Self::new()
         };
-        buf
+        buffer.load_chunk(chunk_size)?;
+        buffer.indent = detect_indent(&buffer.text.to_string());
+        buffer.revision = 0;
+        Ok(buffer)
     }
 
-    pub fn from_file(path: PathBuf) -> Option<Self> {
-        let content = std::fs::read_to_string(&path).ok()?;
-        let content = if content.ends_with('\n') {
-            content
+    /// Whether the buffer holds only part of its file, because it is still
+    /// loading or reading it failed part way.
+    pub fn is_partial(&self) -> bool {
+        self.loading.is_some()
+    }
+
+    /// Whether `load_more` has more of the file to read.
+    pub fn is_loading(&self) -> bool {
+        self.loading.as_ref().is_some_and(|l| !l.stopped)
+    }
+
+    /// Percentage of the file read so far, while it is loading.
+    pub fn load_progress(&self) -> Option<u64> {
+        let loader = self.loading.as_ref()?;
+        Some(
+            (loader.offset * 100)
+                .checked_div(loader.size)
+                .unwrap_or(100),
+        )
+    }
+
+    /// Reads the next chunk of a file that is loading. An error stops the
+    /// loading for good, leaving the buffer partial.
+    pub fn load_more(&mut self) -> std::io::Result<()> {
+        if !self.is_loading() {
+            return Ok(());
+        }
+        let result = self.load_chunk(LOAD_CHUNK_BYTES);
+        if result.is_err()
+            && let Some(loader) = &mut self.loading
+        {
+            loader.stopped = true;
+        }
+        result
+    }
+
+    fn load_chunk(&mut self, chunk_size: usize) -> std::io::Result<()> {
+        use std::io::{Read, Seek, SeekFrom};
+
+        let (Some(path), Some(loader)) = (&self.path, &mut self.loading) else {
+            return Ok(());
+        };
+        let mut file = std::fs::File::open(path)?;
+        file.seek(SeekFrom::Start(loader.offset))?;
+        let mut bytes = std::mem::take(&mut loader.carry);
+        let mut start = loader.offset - bytes.len() as u64;
+        let read = file.take(chunk_size as u64).read_to_end(&mut bytes)?;
+        loader.offset += read as u64;
+        let at_end = read < chunk_size;
+        if start == 0 && bytes.starts_with(UTF8_BOM) {
+            bytes.drain(..UTF8_BOM.len());
+            start = UTF8_BOM.len() as u64;
+            self.encoding = Encoding::Utf8Bom;
+        }
+
+        if let Some(i) = bytes.iter().position(|&b| b == 0) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("binary file (NUL at byte {})", start + i as u64),
+            ));
+        }
+        let mut keep = match std::str::from_utf8(&bytes) {
+            Ok(_) => bytes.len(),
+            // A character cut off by the end of the chunk.
+            Err(e) if e.error_len().is_none() && !at_end => e.valid_up_to(),
+            Err(e) => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!("not valid UTF-8 (byte {})", start + e.valid_up_to() as u64),
+                ));
+            }
+        };
+        if !at_end && keep > 0 && bytes[keep - 1] == b'\r' {
+            keep -= 1;
+        }
+        loader.carry = bytes.split_off(keep);
+        let mut content = String::from_utf8(bytes).expect("checked above");
+        let crlf = content.matches("\r\n").count();
+        loader.crlf += crlf;
+        loader.lf += content.matches('\n').count() - crlf;
+        if crlf > 0 {
+            content = content.replace("\r\n", "\n");
+        }
+        self.text.insert(self.text.len(), &content);
+        self.revision += 1;
+
+        if at_end {
+            let (crlf, lf) = (loader.crlf, loader.lf);
+            self.loading = None;
+            self.line_ending = if crlf > lf {
+                LineEnding::CrLf
+            } else {
+                LineEnding::Lf
+            };
+            self.mixed_line_endings = crlf > 0 && lf > 0;
+            let len = self.text.len();
+            self.final_newline = len > 0 && self.text.byte_at(len - 1) == b'\n';
+            if !self.final_newline {
+                self.text.insert(len, "\n");
+            }
+        }
+        Ok(())
+    }
+
+    /// An unnamed buffer holding `content`, such as text piped to the editor.
+    pub fn with_text(content: String) -> Self {
+        let crlf = content.matches("\r\n").count();
+        let lf = content.matches('\n').count() - crlf;
+        let line_ending = if crlf > lf {
+            LineEnding::CrLf
         } else {
-            format!("{}\n", content)
+            LineEnding::Lf
         };
+        let mut content = if crlf > 0 {
+            content.replace("\r\n", "\n")
+        } else {
+            content
+        };
+        let final_newline = content.ends_with('\n');
+        if !final_newline {
+            content.push('\n');
+        }
 
-        let text = GapBuffer::from_string(&content);
-        let offsets = text.get_line_offsets();
-        let buf = Self {
-            text,
-            path: Some(path),
+        Self {
+            indent: detect_indent(&content),
+            text: GapBuffer::from_text(content),
+            path: None,
             is_modified: false,
             language: "plaintext".to_string(),
-            line_offsets: offsets,
-        };
-        Some(buf)
+            revision: 0,
+            disk_mtime: None,
+            final_newline,
+            line_ending,
+            encoding: Encoding::Utf8,
+            mixed_line_endings: crlf > 0 && lf > 0,
+            read_only: false,
+            marks: Vec::new(),
+            highlights: RefCell::default(),
+            loading: None,
+        }
     }
 
     pub fn for_new_file(path: PathBuf) -> Self {
         let mut text = GapBuffer::new();
         text.insert(0, "\n");
-        let mut buf = Self {
+        Self {
             text,
+            language: detect_language(&path),
             path: Some(path),
             is_modified: false,
-            language: "plaintext".to_string(),
-            line_offsets: Vec::new(),
-        };
-        buf.line_offsets = buf.text.get_line_offsets();
-        buf
+            revision: 0,
+            disk_mtime: None,
+            final_newline: false,
+            line_ending: LineEnding::Lf,
+            encoding: Encoding::Utf8,
+            mixed_line_endings: false,
+            read_only: false,
+            indent: None,
+            marks: Vec::new(),
+            highlights: RefCell::default(),
+            loading: None,
+        }
     }
 
     pub fn insert(&mut self, pos: usize, text: &str) {
         self.text.insert(pos, text);
-        self.line_offsets = self.text.get_line_offsets();
+        self.move_marks_for_insert(pos, text.len());
         self.is_modified = true;
+        self.revision += 1;
     }
 
     pub fn delete(&mut self, pos: usize, len: usize) {
+        let end = pos + len;
+        let joins_lines = self.marks.iter().any(|&m| (pos..end).contains(&m))
+            && self.text.get_range(pos, end).contains('\n');
         self.text.delete(pos, len);
-        self.line_offsets = self.text.get_line_offsets();
+        self.move_marks_for_delete(pos, len, joins_lines);
         self.is_modified = true;
+        self.revision += 1;
+    }
+
+    /// A mark at `pos` stays with the text that was there.
+    fn move_marks_for_insert(&mut self, pos: usize, len: usize) {
+        for mark in self.marks.iter_mut().filter(|m| **m >= pos) {
+            *mark += len;
+        }
+    }
+
+    /// Marks on deleted lines go; others in the range move to `pos`.
+    fn move_marks_for_delete(&mut self, pos: usize, len: usize, joins_lines: bool) {
+        let end = pos + len;
+        if joins_lines {
+            self.marks.retain(|m| !(pos..end).contains(m));
+        }
+        for mark in &mut self.marks {
+            *mark = if *mark >= end {
+                *mark - len
+            } else {
+                (*mark).min(pos)
+            };
+        }
+        self.marks.dedup();
+    }
+
+    /// Marks `line`, or clears its mark if it has one.
+    pub fn toggle_mark(&mut self, line: usize) {
+        let others: Vec<usize> = self
+            .marks
+            .iter()
+            .copied()
+            .filter(|&m| self.get_line_col(m).0 != line)
+            .collect();
+        if others.len() < self.marks.len() {
+            self.marks = others;
+        } else {
+            let pos = self.get_cursor_pos(line, 0);
+            let index = self.marks.partition_point(|&m| m < pos);
+            self.marks.insert(index, pos);
+        }
+    }
+
+    /// Syntax spans of a line. Each distinct line text is tokenized once,
+    /// so drawing a frame only tokenizes lines that were edited.
+    pub fn highlight_line(
+        &self,
+        line: usize,
+        highlighter: &Highlighter,
+    ) -> Vec<(Range<usize>, TokenKind)> {
+        let text = self.get_line(line);
+        let mut cache = self.highlights.borrow_mut();
+        if cache.language != highlighter.language
+            || (cache.lines.len() >= MAX_HIGHLIGHTED_LINES && !cache.lines.contains_key(&text))
+        {
+            cache.language.clone_from(&highlighter.language);
+            cache.lines.clear();
+        }
+        cache
+            .lines
+            .entry(text)
+            .or_insert_with_key(|text| highlighter.highlight_line(text))
+            .clone()
+    }
+
+    /// Lines with a mark, in order.
+    pub fn marked_lines(&self) -> Vec<usize> {
+        let mut lines: Vec<usize> = self.marks.iter().map(|&m| self.get_line_col(m).0).collect();
+        lines.dedup();
+        lines
     }
 
     pub fn get_line(&self, line: usize) -> String {
@@ -325,37 +799,33 @@ impl Buffer {
         self.text.num_lines()
     }
 
+    /// Length of a line in bytes, not counting its trailing newline.
     pub fn line_len(&self, line: usize) -> usize {
-        if line >= self.line_offsets.len() {
-            return 0;
-        }
-        let start = self.line_offsets[line];
-        let end = if line + 1 < self.line_offsets.len() {
-            self.line_offsets[line + 1]
-        } else {
-            self.text.len()
-        };
-        end.saturating_sub(start)
+        self.text.line_len(line)
     }
 
     pub fn total_len(&self) -> usize {
         self.text.len()
     }
 
-    pub fn insert_newline(&mut self, line: usize, col: usize) {
-        let pos = self.get_cursor_pos(line, col);
-        self.text.insert(pos, "\n");
-        self.line_offsets = self.text.get_line_offsets();
-        self.is_modified = true;
+    /// Runs of non-whitespace in the buffer.
+    pub fn word_count(&self) -> usize {
+        self.text.to_string().split_whitespace().count()
+    }
+
+    /// Characters as the user sees them: grapheme clusters, not bytes.
+    pub fn char_count(&self) -> usize {
+        self.text.to_string().graphemes(true).count()
     }
 
     pub fn get_cursor_pos(&self, line: usize, col: usize) -> usize {
-        if line >= self.line_offsets.len() {
+        let offsets = self.text.line_offsets();
+        if line >= offsets.len() {
             return self.text.len();
         }
-        let offset = self.line_offsets[line];
-        let line_len = if line + 1 < self.line_offsets.len() {
-            self.line_offsets[line + 1].saturating_sub(offset)
+        let offset = offsets[line];
+        let line_len = if line + 1 < offsets.len() {
+            offsets[line + 1].saturating_sub(offset)
         } else {
             0
         };
@@ -364,46 +834,81 @@ impl Buffer {
     }
 
     pub fn get_line_col(&self, pos: usize) -> (usize, usize) {
-        let text_len = self.text.len();
-        let pos = pos.min(text_len);
-
-        let mut line = 0;
-        for (i, &offset) in self.line_offsets.iter().enumerate() {
-            if i + 1 < self.line_offsets.len() {
-                if pos < self.line_offsets[i + 1] {
-                    return (line, pos - offset);
-                }
-            }
-            line += 1;
-        }
+        let pos = pos.min(self.text.len());
+        let offsets = self.text.line_offsets();
+        let line = offsets[1..].partition_point(|&o| o <= pos);
+        (line, pos - offsets[line])
+    }
 
-        if let Some(&last_offset) = self.line_offsets.last() {
-            (line.saturating_sub(1), pos.saturating_sub(last_offset))
-        } else {
-            (0, pos)
+    /// Removes spaces and tabs from the ends of lines, bottom line first so
+    /// each position stays valid for the deletions after it. Returns the
+    /// deletions made, as `(position, removed text)`.
+    pub fn trim_trailing_whitespace(&mut self) -> Vec<(usize, String)> {
+        let mut removed = Vec::new();
+        for line in (0..self.num_lines()).rev() {
+            let text = self.get_line(line);
+            let keep = text.trim_end_matches([' ', '\t']).len();
+            if keep < text.len() {
+                let pos = self.get_cursor_pos(line, keep);
+                self.delete(pos, text.len() - keep);
+                removed.push((pos, text[keep..].to_string()));
+            }
         }
+        removed
     }
 
-    pub fn save(&mut self) -> std::io::Result<()> {
-        if let Some(ref path) = self.path {
-            let content = self.text.to_string();
-            let content = content.trim_end_matches('\n');
-            std::fs::write(path, content)?;
-            self.is_modified = false;
-        }
-        Ok(())
+    /// Writes the buffer to its path. Returns `SaveStatus::NoPath` without
+    /// touching anything if the buffer has never been saved.
+    pub fn save(&mut self) -> std::io::Result<SaveStatus> {
+        let Some(ref path) = self.path else {
+            return Ok(SaveStatus::NoPath);
+        };
+        self.check_complete()?;
+        std::fs::write(path, self.encoding.encode(&self.disk_content()))?;
+        self.disk_mtime = file_mtime(path);
+        self.is_modified = false;
+        Ok(SaveStatus::Saved)
     }
 
     pub fn save_as(&mut self, path: PathBuf) -> std::io::Result<()> {
-        let content = self.text.to_string();
-        let content = content.trim_end_matches('\n');
-        std::fs::write(&path, content)?;
+        self.check_complete()?;
+        std::fs::write(&path, self.encoding.encode(&self.disk_content()))?;
+        self.disk_mtime = file_mtime(&path);
         self.path = Some(path);
-        self.language = detect_language(&self.path.as_ref().unwrap());
+        self.language = detect_language(self.path.as_ref().unwrap());
         self.is_modified = false;
         Ok(())
     }
 
+    /// Refuses to write a partly loaded file, which would cut it short.
+    fn check_complete(&self) -> std::io::Result<()> {
+        if self.is_partial() {
+            return Err(std::io::Error::other("file is not fully loaded"));
+        }
+        Ok(())
+    }
+
+    /// The text as it is written to disk.
+    pub fn disk_content(&self) -> String {
+        let content = self.text.to_string();
+        let content = match content.strip_suffix('\n') {
+            Some(stripped) if !self.final_newline => stripped.to_string(),
+            _ => content,
+        };
+        match self.line_ending {
+            LineEnding::Lf => content,
+            LineEnding::CrLf => content.replace('\n', "\r\n"),
+        }
+    }
+
+    /// Whether the file was modified on disk since it was loaded or saved.
+    pub fn changed_on_disk(&self) -> bool {
+        let Some(path) = &self.path else {
+            return false;
+        };
+        self.disk_mtime.is_some() && file_mtime(path) != self.disk_mtime
+    }
+
     pub fn file_name(&self) -> String {
         self.path
             .as_ref()
@@ -413,56 +918,333 @@ impl Buffer {
             .to_string()
     }
 
+    #[allow(dead_code)]
     pub fn find(&self, query: &str, from_line: usize, from_col: usize) -> Option<(usize, usize)> {
-        if query.is_empty() {
-            return None;
-        }
+        self.find_opts(&Pattern::literal(query, true), from_line, from_col)
+    }
 
-        let text = self.text.to_string();
-        let mut search_start = 0;
+    pub fn find_opts(
+        &self,
+        pattern: &Pattern,
+        from_line: usize,
+        from_col: usize,
+    ) -> Option<(usize, usize)> {
+        let search_start = self.search_start(from_line, from_col);
+        let matches = pattern.ranges(&self.text.to_string());
+
+        let (pos, _) = matches
+            .iter()
+            .find(|&&(pos, _)| pos >= search_start)
+            .or(matches.first())?;
+        Some(self.get_line_col(*pos))
+    }
 
-        for (i, &offset) in self.line_offsets.iter().enumerate() {
-            if i == from_line {
-                search_start = offset + from_col.min(self.get_line(i).len());
-                break;
+    /// Like `find`, but returns the last match starting before the position,
+    /// wrapping around to the end of the buffer.
+    #[allow(dead_code)]
+    pub fn rfind(&self, query: &str, from_line: usize, from_col: usize) -> Option<(usize, usize)> {
+        self.rfind_opts(&Pattern::literal(query, true), from_line, from_col)
+    }
+
+    pub fn rfind_opts(
+        &self,
+        pattern: &Pattern,
+        from_line: usize,
+        from_col: usize,
+    ) -> Option<(usize, usize)> {
+        let search_start = self.search_start(from_line, from_col);
+        let matches = pattern.ranges(&self.text.to_string());
+
+        let (pos, _) = matches
+            .iter()
+            .rev()
+            .find(|&&(pos, _)| pos < search_start)
+            .or(matches.last())?;
+        Some(self.get_line_col(*pos))
+    }
+
+    /// Byte offsets of every match of `pattern`.
+    pub fn find_all(&self, pattern: &Pattern) -> Vec<usize> {
+        pattern
+            .ranges(&self.text.to_string())
+            .into_iter()
+            .map(|(start, _)| start)
+            .collect()
+    }
+
+    /// Byte ranges of the matches of `pattern` starting at or after `from`,
+    /// without wrapping around.
+    pub fn matches_from(
+        &self,
+        pattern: &Pattern,
+        from: usize,
+    ) -> impl Iterator<Item = (usize, usize)> + use<> {
+        pattern
+            .ranges(&self.text.to_string())
+            .into_iter()
+            .filter(move |&(start, _)| start >= from)
+    }
+
+    /// Column ranges `(start, end)` of every match of `pattern` on `line`.
+    pub fn line_matches(&self, line: usize, pattern: &Pattern) -> Vec<(usize, usize)> {
+        pattern.ranges(&self.get_line(line))
+    }
+
+    /// Byte column of the grapheme before `col` on `line`, so the cursor
+    /// steps over accented letters and emoji as a whole.
+    pub fn prev_grapheme(&self, line: usize, col: usize) -> usize {
+        let text = self.get_line(line);
+        text.grapheme_indices(true)
+            .map(|(i, _)| i)
+            .take_while(|&i| i < col)
+            .last()
+            .unwrap_or(0)
+    }
+
+    /// Byte column just past the grapheme at `col` on `line`.
+    pub fn next_grapheme(&self, line: usize, col: usize) -> usize {
+        let text = self.get_line(line);
+        text.grapheme_indices(true)
+            .map(|(i, g)| i + g.len())
+            .find(|&end| end > col)
+            .unwrap_or(text.len())
+    }
+
+    /// Byte column of the first grapheme on `line` that isn't whitespace,
+    /// or the line's length if it is blank.
+    pub fn first_non_blank(&self, line: usize) -> usize {
+        let text = self.get_line(line);
+        text.grapheme_indices(true)
+            .find(|(_, g)| !g.chars().all(char::is_whitespace))
+            .map_or(text.len(), |(i, _)| i)
+    }
+
+    /// `col` moved back to the nearest grapheme boundary on `line`, and
+    /// clamped to the line's length.
+    pub fn grapheme_floor(&self, line: usize, col: usize) -> usize {
+        let text = self.get_line(line);
+        text.grapheme_indices(true)
+            .map(|(i, _)| i)
+            .chain([text.len()])
+            .take_while(|&i| i <= col)
+            .last()
+            .unwrap_or(0)
+    }
+
+    /// Start of the word before byte `pos`, for word-wise movement and
+    /// deletion. Skips blanks, then a run of word or punctuation characters.
+    /// A line break is a stop of its own, so lines are only joined one at a time.
+    pub fn prev_word_boundary(&self, pos: usize) -> usize {
+        let text = self.text.get_range(0, pos);
+        let mut chars = text.char_indices().rev().peekable();
+        if let Some(&(i, '\n')) = chars.peek() {
+            return i;
+        }
+        let mut boundary = text.len();
+        while let Some((i, _)) = chars.next_if(|&(_, c)| c == ' ' || c == '\t') {
+            boundary = i;
+        }
+        if let Some(&(_, c)) = chars.peek()
+            && c != '\n'
+        {
+            let class = char_class(c);
+            while let Some((i, _)) = chars.next_if(|&(_, c)| c != '\n' && char_class(c) == class) {
+                boundary = i;
             }
         }
+        boundary
+    }
 
-        if let Some(pos) = text[search_start..].find(query) {
-            return Some(self.get_line_col(search_start + pos));
+    /// End of the word after byte `pos`; the mirror of `prev_word_boundary`.
+    pub fn next_word_boundary(&self, pos: usize) -> usize {
+        let text = self.text.get_range(pos, self.text.len());
+        let mut chars = text.char_indices().peekable();
+        if let Some(&(_, '\n')) = chars.peek() {
+            return pos + 1;
         }
-
-        if search_start > 0 {
-            if let Some(pos) = text[..search_start].find(query) {
-                return Some(self.get_line_col(pos));
+        let mut boundary = 0;
+        while let Some((i, c)) = chars.next_if(|&(_, c)| c == ' ' || c == '\t') {
+            boundary = i + c.len_utf8();
+        }
+        if let Some(&(_, c)) = chars.peek()
+            && c != '\n'
+        {
+            let class = char_class(c);
+            while let Some((i, c)) = chars.next_if(|&(_, c)| c != '\n' && char_class(c) == class) {
+                boundary = i + c.len_utf8();
             }
         }
+        pos + boundary
+    }
 
-        None
+    /// Position of the bracket matching the one at byte `pos`, honoring
+    /// nesting of `()`, `[]` and `{}`. Returns `None` if there is no bracket
+    /// at `pos` or it is unbalanced.
+    pub fn match_bracket(&self, pos: usize) -> Option<usize> {
+        self.match_bracket_in(pos, 0..self.text.len())
     }
 
-    pub fn replace(&mut self, old: &str, new: &str) -> usize {
-        let text = self.text.to_string();
-        let count = text.matches(old).count();
-        let new_text = text.replace(old, new);
+    /// Like `match_bracket`, but only looks for the partner within `range`,
+    /// e.g. the visible lines.
+    pub fn match_bracket_in(&self, pos: usize, range: Range<usize>) -> Option<usize> {
+        let end = range.end.min(self.text.len());
+        if pos < range.start || pos >= end {
+            return None;
+        }
+        let (open, close, forward) = match self.text.byte_at(pos) {
+            b'(' => (b'(', b')', true),
+            b'[' => (b'[', b']', true),
+            b'{' => (b'{', b'}', true),
+            b')' => (b'(', b')', false),
+            b']' => (b'[', b']', false),
+            b'}' => (b'{', b'}', false),
+            _ => return None,
+        };
+
+        // Opening brackets nest when scanning forward, closing ones backward.
+        let mut depth = 0i32;
+        let mut step = |i: usize| {
+            let byte = self.text.byte_at(i);
+            let nest = if byte == open {
+                1
+            } else if byte == close {
+                -1
+            } else {
+                0
+            };
+            depth += if forward { nest } else { -nest };
+            depth == 0
+        };
+        if forward {
+            (pos..end).find(|&i| step(i))
+        } else {
+            (range.start..=pos).rev().find(|&i| step(i))
+        }
+    }
 
-        self.text = GapBuffer::from_string(&new_text);
-        if !new_text.ends_with('\n') {
-            self.text.insert(self.text.len(), "\n");
+    /// Byte offset of `from_col`, a byte column like the cursor's, on
+    /// `from_line`. Columns past the end of the line stop at its end, and a
+    /// column inside a character moves on to the next one.
+    fn search_start(&self, from_line: usize, from_col: usize) -> usize {
+        let offsets = self.text.line_offsets();
+        match offsets.get(from_line) {
+            Some(&offset) if from_line + 1 < offsets.len() => {
+                let line = self.get_line(from_line);
+                let mut col = from_col.min(line.len());
+                while !line.is_char_boundary(col) {
+                    col += 1;
+                }
+                offset + col
+            }
+            _ => self.text.len(),
         }
-        self.line_offsets = self.text.get_line_offsets();
+    }
+
+    /// Replaces every match of `pattern` starting at or after `from` with
+    /// `new`, only taking matches that lie wholly inside `within` if given.
+    /// Returns the edits as `(pos, old, new)`, last match first, so every
+    /// `pos` refers to the text as it was before the replacement.
+    pub fn replace(
+        &mut self,
+        pattern: &Pattern,
+        new: &str,
+        from: usize,
+        within: Option<(usize, usize)>,
+    ) -> Vec<(usize, String, String)> {
+        let edits: Vec<_> = pattern
+            .replacements(&self.text.to_string(), new)
+            .into_iter()
+            .filter(|&(start, _, _)| start >= from)
+            .filter(|&(start, end, _)| within.is_none_or(|(s, e)| start >= s && end <= e))
+            .rev()
+            .map(|(start, end, new)| (start, self.text.get_range(start, end), new))
+            .collect();
+        if edits.is_empty() {
+            return edits;
+        }
+        for (pos, old, new) in &edits {
+            self.move_marks_for_delete(*pos, old.len(), old.contains('\n'));
+            self.move_marks_for_insert(*pos, new.len());
+        }
+        self.text.replace_ranges(
+            edits
+                .iter()
+                .map(|(pos, old, new)| (*pos, old.len(), new.as_str())),
+        );
         self.is_modified = true;
-        count
+        self.revision += 1;
+        edits
     }
 }
 
+fn starts_with_utf16_bom(path: &Path) -> std::io::Result<bool> {
+    use std::io::Read;
+    let mut start = Vec::new();
+    std::fs::File::open(path)?.take(2).read_to_end(&mut start)?;
+    Ok(start == UTF16LE_BOM || start == UTF16BE_BOM)
+}
+
+fn file_mtime(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
 impl Default for Buffer {
     fn default() -> Self {
         Self::new()
     }
 }
 
-fn detect_language(path: &PathBuf) -> String {
+/// Groups characters for word boundaries: words are alphanumerics and `_`,
+/// everything else that isn't blank is punctuation.
+fn char_class(c: char) -> u8 {
+    if c.is_whitespace() {
+        0
+    } else if c.is_alphanumeric() || c == '_' {
+        1
+    } else {
+        2
+    }
+}
+
+/// Guesses the indentation of `content` from its first lines: tabs if more
+/// lines start with a tab than with spaces, otherwise the most common step
+/// from one line's indent to a deeper one. `None` if no line is indented.
+fn detect_indent(content: &str) -> Option<Indent> {
+    let mut tabs = 0;
+    let mut spaces = 0;
+    // How often each step in indentation was seen, up to 8 columns.
+    let mut steps = [0; 9];
+    let mut previous = 0;
+    for line in content.lines().take(INDENT_SAMPLE_LINES) {
+        if line.starts_with('\t') {
+            tabs += 1;
+            continue;
+        }
+        let rest = line.trim_start_matches(' ');
+        // Blank lines and the ` * ` of block comments say nothing.
+        if rest.trim().is_empty() || rest.starts_with('*') {
+            continue;
+        }
+        let width = line.len() - rest.len();
+        if width > 0 {
+            spaces += 1;
+        }
+        if let Some(count) = width.checked_sub(previous).and_then(|d| steps.get_mut(d)) {
+            *count += 1;
+        }
+        previous = width;
+    }
+    if tabs > spaces {
+        return Some(Indent::Tabs);
+    }
+    // Ties go to the smaller step.
+    (1..steps.len())
+        .filter(|&step| steps[step] > 0)
+        .max_by_key(|&step| (steps[step], std::cmp::Reverse(step)))
+        .map(Indent::Spaces)
+}
+
+fn detect_language(path: &Path) -> String {
     let ext = path.extension().and_then(|e| e.to_str()).unwrap_or("");
 
     match ext {
@@ -493,3 +1275,243 @@ fn detect_language(path: &PathBuf) -> String {
     }
     .to_string()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("nova-test-{}-{}", std::process::id(), name))
+    }
+
+    #[test]
+    fn edits_keep_line_offsets_in_sync() {
+        let mut text = GapBuffer::new();
+        text.insert(0, "one\ntwo\n");
+        text.insert(4, "a\nb");
+        text.insert(text.len(), "end\n");
+        text.delete(2, 5);
+        text.insert(0, "\n");
+        text.delete(text.len() - 1, 1);
+        let expected = GapBuffer::from_string(&text.get_range(0, text.len()));
+        assert_eq!(text.line_offsets(), expected.line_offsets());
+    }
+
+    #[test]
+    fn highlights_are_cached_by_line_text() {
+        let mut buffer = Buffer::with_text("let a = 1;\nlet a = 1;\n".to_string());
+        let mut highlighter = Highlighter::new();
+        highlighter.set_language("rust");
+        let spans = buffer.highlight_line(0, &highlighter);
+        assert_eq!(spans[0], (0..3, TokenKind::Keyword));
+        assert_eq!(buffer.highlight_line(1, &highlighter), spans);
+        assert_eq!(buffer.highlights.borrow().lines.len(), 1);
+
+        buffer.insert(0, "// ");
+        assert_eq!(
+            buffer.highlight_line(0, &highlighter),
+            [(0..13, TokenKind::Comment)]
+        );
+        highlighter.set_language("plaintext");
+        assert!(buffer.highlight_line(1, &highlighter).is_empty());
+    }
+
+    #[test]
+    fn replace_edits_in_place_from_the_end() {
+        let mut buf = Buffer::new();
+        let text: String = (0..2000).map(|i| format!("foo {} foo\n", i)).collect();
+        buf.insert(0, text.trim_end());
+        let from = buf.get_cursor_pos(1, 0);
+        let edits = buf.replace(&Pattern::literal("foo", true), "quux", from, None);
+        assert_eq!(edits.len(), 3998);
+        assert_eq!(buf.get_line(0), "foo 0 foo");
+        assert_eq!(buf.get_line(1), "quux 1 quux");
+        assert_eq!(buf.get_line(1999), "quux 1999 quux");
+        let expected = GapBuffer::from_string(&buf.text.to_string());
+        assert_eq!(buf.text.line_offsets(), expected.line_offsets());
+    }
+
+    #[test]
+    fn save_writes_file_and_clears_modified() {
+        let path = temp_path("save.txt");
+        let mut buf = Buffer::for_new_file(path.clone());
+        buf.insert(0, "hello");
+        assert_eq!(buf.save().unwrap(), SaveStatus::Saved);
+        assert!(!buf.is_modified);
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "hello");
+        std::fs::remove_file(path).ok();
+    }
+
+    #[test]
+    fn save_keeps_trailing_newlines_as_loaded() {
+        for content in ["one\n", "two\n\n\n", "three"] {
+            let path = temp_path("newlines.txt");
+            std::fs::write(&path, content).unwrap();
+            let mut buf = Buffer::from_file(path.clone()).unwrap();
+            buf.save().unwrap();
+            assert_eq!(std::fs::read_to_string(&path).unwrap(), content);
+            std::fs::remove_file(path).ok();
+        }
+    }
+
+    #[test]
+    fn crlf_files_are_edited_as_lf_and_saved_as_crlf() {
+        let path = temp_path("crlf.txt");
+        std::fs::write(&path, "a\r\nb\r\nc\n").unwrap();
+        let mut buf = Buffer::from_file(path.clone()).unwrap();
+        assert_eq!(buf.line_ending, LineEnding::CrLf);
+        assert!(buf.mixed_line_endings);
+        assert_eq!(buf.get_line(0), "a");
+        buf.save().unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "a\r\nb\r\nc\r\n");
+        std::fs::remove_file(path).ok();
+    }
+
+    #[test]
+    fn non_utf8_files_are_refused() {
+        let path = temp_path("latin1.txt");
+        std::fs::write(&path, b"caf\xe9\n").unwrap();
+        let err = Buffer::from_file(path.clone()).err().unwrap();
+        assert_eq!(err.to_string(), "not valid UTF-8 (byte 3)");
+        std::fs::remove_file(path).ok();
+    }
+
+    #[test]
+    fn indentation_is_detected_from_the_text() {
+        let detect = |text: &str| Buffer::with_text(text.to_string()).indent;
+        let two = "fn a() {\n  if b {\n    c();\n  }\n\n  /*\n   * d\n   */\n}\n";
+        assert_eq!(detect(two), Some(Indent::Spaces(2)));
+        assert_eq!(
+            detect("a:\n    b:\n        c\n    d\n"),
+            Some(Indent::Spaces(4))
+        );
+        assert_eq!(detect("{\n\ta\n\t\tb\n  c\n}\n"), Some(Indent::Tabs));
+        assert_eq!(detect("one\ntwo\n"), None);
+    }
+
+    #[test]
+    fn piped_text_opens_untitled() {
+        let mut buf = Buffer::with_text("one\r\ntwo".to_string());
+        assert_eq!(buf.path, None);
+        assert!(!buf.is_modified);
+        assert_eq!(buf.line_ending, LineEnding::CrLf);
+        assert_eq!(buf.get_line(1), "two");
+        assert_eq!(buf.save().unwrap(), SaveStatus::NoPath);
+    }
+
+    #[test]
+    fn save_without_path_reports_no_path() {
+        let mut buf = Buffer::new();
+        buf.insert(0, "hello");
+        assert_eq!(buf.save().unwrap(), SaveStatus::NoPath);
+        assert!(buf.is_modified);
+    }
+
+    #[test]
+    fn large_files_load_in_chunks() {
+        let path = temp_path("chunks.txt");
+        std::fs::write(&path, "aé\r\nb\r\nc").unwrap();
+        let mut buf = Buffer::from_file_in_chunks(path.clone(), 2).unwrap();
+        assert!(buf.is_partial());
+        assert_eq!(buf.text.to_string(), "a");
+        assert!(buf.save().is_err());
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "aé\r\nb\r\nc");
+
+        // The "é" and the first "\r\n" are both split between chunks.
+        while buf.is_loading() {
+            buf.load_chunk(2).unwrap();
+        }
+        assert!(!buf.is_partial());
+        assert_eq!(buf.text.to_string(), "aé\nb\nc\n");
+        assert_eq!(buf.num_lines(), 3);
+        assert_eq!(buf.line_ending, LineEnding::CrLf);
+        assert!(!buf.final_newline);
+        assert_eq!(buf.disk_content(), "aé\r\nb\r\nc");
+
+        std::fs::write(&path, b"ab\xffcd").unwrap();
+        let mut buf = Buffer::from_file_in_chunks(path.clone(), 2).unwrap();
+        assert!(buf.load_more().is_err());
+        assert!(buf.is_partial() && !buf.is_loading());
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn files_save_in_the_encoding_they_were_read_in() {
+        let path = temp_path("encoding.txt");
+        let utf16: Vec<u8> = [0xFF, 0xFE]
+            .into_iter()
+            .chain("hé\r\n".encode_utf16().flat_map(u16::to_le_bytes))
+            .collect();
+        for bytes in [utf16, b"\xEF\xBB\xBFhi\n".to_vec()] {
+            std::fs::write(&path, &bytes).unwrap();
+            let mut buf = Buffer::from_file(path.clone()).unwrap();
+            assert!(!buf.text.to_string().starts_with('\u{FEFF}'));
+            assert!(!buf.read_only);
+            buf.save().unwrap();
+            assert_eq!(std::fs::read(&path).unwrap(), bytes);
+        }
+        assert_eq!(
+            Buffer::from_file(path.clone()).unwrap().encoding,
+            Encoding::Utf8Bom
+        );
+
+        assert_eq!(
+            decode(b"\xFF\xFEh\0i\0".to_vec()).unwrap(),
+            (Encoding::Utf16Le, "hi".into())
+        );
+        assert_eq!(
+            decode(b"\xFE\xFF\0h".to_vec()).unwrap(),
+            (Encoding::Utf16Be, "h".into())
+        );
+        assert!(decode(b"\xFF\xFEh".to_vec()).is_err());
+        assert!(decode(b"\xFF\xFE\0\0".to_vec()).is_err());
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn save_error_keeps_modified() {
+        let path = temp_path("missing-dir").join("file.txt");
+        let mut buf = Buffer::for_new_file(path);
+        buf.insert(0, "hello");
+        assert!(buf.save().is_err());
+        assert!(buf.is_modified);
+    }
+
+    #[test]
+    fn find_ignoring_case_reports_original_offsets() {
+        let mut buf = Buffer::new();
+        buf.insert(0, "ÄÖx\nfoo äöX");
+        let ignore_case = Pattern::literal("äöx", false);
+        assert_eq!(buf.find_opts(&ignore_case, 0, 1), Some((1, 4)));
+        assert_eq!(buf.find_opts(&Pattern::literal("äöx", true), 0, 1), None);
+        assert_eq!(buf.rfind_opts(&ignore_case, 1, 4), Some((0, 0)));
+    }
+
+    #[test]
+    fn find_starts_at_byte_columns_on_multibyte_lines() {
+        let mut buf = Buffer::new();
+        buf.insert(0, "héé x héé x\nx");
+        // Column 6 is the "x" after two 2-byte characters.
+        assert_eq!(buf.find("x", 0, 6), Some((0, 6)));
+        assert_eq!(buf.find("x", 0, 7), Some((0, 14)));
+        // Inside "é", and past the end of the line.
+        assert_eq!(buf.find("héé", 0, 2), Some((0, 8)));
+        assert_eq!(buf.find("x", 0, 100), Some((1, 0)));
+        assert_eq!(buf.rfind("x", 1, 0), Some((0, 14)));
+    }
+
+    #[test]
+    fn match_bracket_honors_nesting() {
+        let mut buf = Buffer::new();
+        buf.insert(0, "f(a[0], {\n  b() })\n(");
+        assert_eq!(buf.match_bracket(1), Some(17));
+        assert_eq!(buf.match_bracket(17), Some(1));
+        assert_eq!(buf.match_bracket(8), Some(16));
+        assert_eq!(buf.match_bracket(3), Some(5));
+        assert_eq!(buf.match_bracket(19), None);
+        assert_eq!(buf.match_bracket(0), None);
+        assert_eq!(buf.match_bracket_in(1, 0..10), None);
+        assert_eq!(buf.match_bracket_in(16, 8..20), Some(8));
+        assert_eq!(buf.match_bracket_in(8, 9..20), None);
+    }
+}
@@ -0,0 +1,154 @@
+use std::path::Path;
+use std::process::Command;
+
+use similar::{DiffOp, TextDiff};
+
+/// How a line of the current text differs from an older version of it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LineChange {
+    /// The line is new or was changed.
+    Added,
+    /// Lines were removed just above this one.
+    Removed,
+}
+
+/// Marks the lines of `new` that differ from `old`, in line order. Removals
+/// at the very end are reported on the last line.
+pub fn line_changes(old: &str, new: &str) -> Vec<(usize, LineChange)> {
+    let last_line = new.lines().count().saturating_sub(1);
+    let mut changes = Vec::new();
+    for op in TextDiff::from_lines(old, new).ops() {
+        match *op {
+            DiffOp::Equal { .. } => {}
+            DiffOp::Delete { new_index, .. } => {
+                changes.push((new_index.min(last_line), LineChange::Removed));
+            }
+            DiffOp::Insert {
+                new_index, new_len, ..
+            } => {
+                changes.extend((new_index..new_index + new_len).map(|l| (l, LineChange::Added)));
+            }
+            DiffOp::Replace {
+                old_len,
+                new_index,
+                new_len,
+                ..
+            } => {
+                changes.extend((new_index..new_index + new_len).map(|l| (l, LineChange::Added)));
+                // Fewer lines came back than went: note the rest as removed
+                // below, unless that is the changed last line itself.
+                let below = (new_index + new_len).min(last_line);
+                if old_len > new_len && below >= new_index + new_len {
+                    changes.push((below, LineChange::Removed));
+                }
+            }
+        }
+    }
+    changes
+}
+
+/// Lines of the file at `path` that differ from git's HEAD, as saved on
+/// disk. `None` if the file is not in a git repository or git can't be run.
+pub fn git_changes(path: &Path) -> Option<Vec<(usize, LineChange)>> {
+    let dir = path.parent().filter(|d| !d.as_os_str().is_empty());
+    let output = Command::new("git")
+        .current_dir(dir.unwrap_or(Path::new(".")))
+        .args([
+            "diff",
+            "--unified=0",
+            "--no-color",
+            "--no-ext-diff",
+            "HEAD",
+            "--",
+        ])
+        .arg(path.file_name()?)
+        .output()
+        .ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    Some(parse_hunks(&String::from_utf8_lossy(&output.stdout)))
+}
+
+/// Reads the `@@ -a,b +c,d @@` headers of a diff made with `--unified=0`.
+fn parse_hunks(diff: &str) -> Vec<(usize, LineChange)> {
+    // A missing count means one line.
+    fn range(spec: &str) -> Option<(usize, usize)> {
+        let (start, len) = spec.split_once(',').unwrap_or((spec, "1"));
+        Some((start.parse().ok()?, len.parse().ok()?))
+    }
+
+    let mut changes = Vec::new();
+    for header in diff.lines().filter_map(|l| l.strip_prefix("@@ ")) {
+        let mut specs = header.split_whitespace();
+        let (Some(old), Some(new)) = (
+            specs
+                .next()
+                .and_then(|s| s.strip_prefix('-'))
+                .and_then(range),
+            specs
+                .next()
+                .and_then(|s| s.strip_prefix('+'))
+                .and_then(range),
+        ) else {
+            continue;
+        };
+        let ((_, old_len), (new_start, new_len)) = (old, new);
+        if new_len == 0 {
+            // Pure removals name the line before them.
+            changes.push((new_start, LineChange::Removed));
+            continue;
+        }
+        let first = new_start - 1;
+        changes.extend((first..first + new_len).map(|l| (l, LineChange::Added)));
+        if old_len > new_len {
+            changes.push((first + new_len, LineChange::Removed));
+        }
+    }
+    changes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn marks_added_changed_and_removed_lines() {
+        let old = "one\ntwo\nthree\nfour\n";
+        let new = "zero\none\nTWO\nfour\n";
+        assert_eq!(
+            line_changes(old, new),
+            vec![
+                (0, LineChange::Added),
+                (2, LineChange::Added),
+                (3, LineChange::Removed),
+            ]
+        );
+        assert_eq!(line_changes(old, "one\n"), vec![(0, LineChange::Removed)]);
+        assert!(line_changes(old, old).is_empty());
+    }
+
+    #[test]
+    fn parses_zero_context_hunks() {
+        let diff = "diff --git a/f b/f\n\
+                    --- a/f\n\
+                    +++ b/f\n\
+                    @@ -0,0 +1 @@\n\
+                    +new\n\
+                    @@ -3,2 +4 @@ fn main() {\n\
+                    -a\n\
+                    -b\n\
+                    +c\n\
+                    @@ -9 +9,0 @@\n\
+                    -gone\n";
+        assert_eq!(
+            parse_hunks(diff),
+            vec![
+                (0, LineChange::Added),
+                (3, LineChange::Added),
+                (4, LineChange::Removed),
+                (9, LineChange::Removed),
+            ]
+        );
+    }
+}
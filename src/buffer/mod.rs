@@ -1,3 +1,11 @@
+#[allow(clippy::module_inception)]
 pub mod buffer;
+pub mod diff;
+pub mod recovery;
+pub mod search;
+pub mod selection;
 
-pub use buffer::Buffer;
+pub use buffer::{Buffer, Indent, SaveStatus, decode};
+pub use diff::LineChange;
+pub use search::Pattern;
+pub use selection::{Selection, SelectionKind};
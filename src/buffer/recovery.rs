@@ -0,0 +1,83 @@
+use std::path::{Path, PathBuf};
+
+/// Directory holding recovery files, e.g. `~/.local/state/nova/recovery`.
+pub fn recovery_dir() -> Option<PathBuf> {
+    dirs::state_dir()
+        .or_else(dirs::data_local_dir)
+        .map(|p| p.join("nova").join("recovery"))
+}
+
+/// `path` made absolute with `/` replaced by `%`, naming a file per path.
+fn flat_name(path: &Path) -> Option<String> {
+    let absolute = std::path::absolute(path).ok()?;
+    Some(
+        absolute
+            .to_string_lossy()
+            .chars()
+            .map(|c| if std::path::is_separator(c) { '%' } else { c })
+            .collect(),
+    )
+}
+
+/// Recovery file for `path`: its absolute path with `/` replaced by `%`,
+/// plus a `.swp` extension.
+pub fn recovery_path(path: &Path) -> Option<PathBuf> {
+    let name = flat_name(path)?;
+    recovery_dir().map(|dir| dir.join(format!("{}.swp", name)))
+}
+
+/// Saved undo history for `path`, named like its recovery file but with an
+/// `.undo` extension.
+pub fn undo_path(path: &Path) -> Option<PathBuf> {
+    let name = flat_name(path)?;
+    recovery_dir().map(|dir| dir.join(format!("{}.undo", name)))
+}
+
+pub fn write_undo(path: &Path, content: &str) -> std::io::Result<()> {
+    let Some(file) = undo_path(path) else {
+        return Ok(());
+    };
+    if let Some(parent) = file.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    std::fs::write(file, content)
+}
+
+pub fn read_undo(path: &Path) -> Option<String> {
+    std::fs::read_to_string(undo_path(path)?).ok()
+}
+
+pub fn remove_undo(path: &Path) {
+    if let Some(file) = undo_path(path) {
+        let _ = std::fs::remove_file(file);
+    }
+}
+
+pub fn write(path: &Path, content: &str) -> std::io::Result<()> {
+    let Some(swap) = recovery_path(path) else {
+        return Ok(());
+    };
+    if let Some(parent) = swap.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    std::fs::write(swap, content)
+}
+
+/// Returns the contents of a recovery file for `path` that is newer than
+/// the file on disk, if any.
+pub fn read_newer(path: &Path) -> Option<String> {
+    let swap = recovery_path(path)?;
+    let swap_time = std::fs::metadata(&swap).ok()?.modified().ok()?;
+    if let Ok(file_time) = std::fs::metadata(path).and_then(|m| m.modified())
+        && file_time >= swap_time
+    {
+        return None;
+    }
+    std::fs::read_to_string(swap).ok()
+}
+
+pub fn remove(path: &Path) {
+    if let Some(swap) = recovery_path(path) {
+        let _ = std::fs::remove_file(swap);
+    }
+}
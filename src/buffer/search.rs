@@ -0,0 +1,142 @@
+use regex::{Regex, RegexBuilder};
+
+/// What to look for when searching: literal text or a regular expression.
+#[derive(Clone, Debug)]
+pub enum Pattern {
+    Literal { query: String, case_sensitive: bool },
+    Regex(Regex),
+}
+
+impl Pattern {
+    pub fn new(query: &str, case_sensitive: bool, regex: bool) -> Result<Self, regex::Error> {
+        if regex {
+            RegexBuilder::new(query)
+                .case_insensitive(!case_sensitive)
+                .multi_line(true)
+                .build()
+                .map(Pattern::Regex)
+        } else {
+            Ok(Pattern::literal(query, case_sensitive))
+        }
+    }
+
+    pub fn literal(query: &str, case_sensitive: bool) -> Self {
+        Pattern::Literal {
+            query: query.to_string(),
+            case_sensitive,
+        }
+    }
+
+    pub fn as_str(&self) -> &str {
+        match self {
+            Pattern::Literal { query, .. } => query,
+            Pattern::Regex(re) => re.as_str(),
+        }
+    }
+
+    /// Byte ranges of the non-overlapping matches in `text`. Empty matches,
+    /// such as those of an empty query or `^`, are skipped.
+    pub fn ranges(&self, text: &str) -> Vec<(usize, usize)> {
+        match self {
+            Pattern::Literal { query, .. } if query.is_empty() => Vec::new(),
+            Pattern::Literal {
+                query,
+                case_sensitive,
+            } => literal_ranges(text, query, *case_sensitive),
+            Pattern::Regex(re) => re
+                .find_iter(text)
+                .filter(|m| !m.is_empty())
+                .map(|m| (m.start(), m.end()))
+                .collect(),
+        }
+    }
+
+    /// Like `ranges`, paired with the text each match is replaced by. For
+    /// regular expressions, `$1` or `${name}` in `replacement` expand to the
+    /// captured groups.
+    pub fn replacements(&self, text: &str, replacement: &str) -> Vec<(usize, usize, String)> {
+        match self {
+            Pattern::Literal { .. } => self
+                .ranges(text)
+                .into_iter()
+                .map(|(start, end)| (start, end, replacement.to_string()))
+                .collect(),
+            Pattern::Regex(re) => re
+                .captures_iter(text)
+                .filter_map(|caps| {
+                    let m = caps.get(0).filter(|m| !m.is_empty())?;
+                    let mut new = String::new();
+                    caps.expand(replacement, &mut new);
+                    Some((m.start(), m.end(), new))
+                })
+                .collect(),
+        }
+    }
+}
+
+/// Byte ranges of non-overlapping matches of `query` in `text`. Without
+/// `case_sensitive` both sides are compared lowercased, but the ranges still
+/// index into the original `text`.
+fn literal_ranges(text: &str, query: &str, case_sensitive: bool) -> Vec<(usize, usize)> {
+    if case_sensitive {
+        return text
+            .match_indices(query)
+            .map(|(pos, m)| (pos, pos + m.len()))
+            .collect();
+    }
+
+    let query: Vec<char> = query.chars().flat_map(char::to_lowercase).collect();
+    let mut ranges = Vec::new();
+    let mut next = 0;
+    for (pos, _) in text.char_indices() {
+        if pos < next {
+            continue;
+        }
+        if let Some(len) = caseless_prefix_len(&text[pos..], &query) {
+            ranges.push((pos, pos + len));
+            next = pos + len;
+        }
+    }
+    ranges
+}
+
+/// Length in bytes of the prefix of `text` that lowercases to `query`.
+fn caseless_prefix_len(text: &str, query: &[char]) -> Option<usize> {
+    let mut rest = query;
+    for (i, c) in text.char_indices() {
+        if rest.is_empty() {
+            return Some(i);
+        }
+        for lower in c.to_lowercase() {
+            let (first, tail) = rest.split_first()?;
+            if *first != lower {
+                return None;
+            }
+            rest = tail;
+        }
+    }
+    rest.is_empty().then_some(text.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn regex_replacements_expand_captures() {
+        let pattern = Pattern::new(r"fn (\w+)\(", true, true).unwrap();
+        assert_eq!(
+            pattern.replacements("fn foo(a) fn bar(b)", "def $1("),
+            vec![
+                (0, 7, "def foo(".to_string()),
+                (10, 17, "def bar(".to_string())
+            ]
+        );
+    }
+
+    #[test]
+    fn empty_regex_matches_are_skipped() {
+        let pattern = Pattern::new("x*", true, true).unwrap();
+        assert_eq!(pattern.ranges("ab\nxx"), vec![(3, 5)]);
+    }
+}
@@ -0,0 +1,68 @@
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SelectionKind {
+    Linear,
+    Block,
+}
+
+/// A selection anchored at a fixed position; the other end is the cursor.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Selection {
+    pub anchor_line: usize,
+    pub anchor_col: usize,
+    pub kind: SelectionKind,
+}
+
+impl Selection {
+    pub fn new(anchor_line: usize, anchor_col: usize, kind: SelectionKind) -> Self {
+        Self {
+            anchor_line,
+            anchor_col,
+            kind,
+        }
+    }
+
+    /// Returns `(top, bottom, left, right)` of the rectangle spanned by the
+    /// anchor and the cursor. `right` is exclusive. The columns are byte
+    /// offsets on the anchor's and the cursor's own rows; other rows need
+    /// them turned into display columns first.
+    pub fn block_bounds(
+        &self,
+        cursor_line: usize,
+        cursor_col: usize,
+    ) -> (usize, usize, usize, usize) {
+        (
+            self.anchor_line.min(cursor_line),
+            self.anchor_line.max(cursor_line),
+            self.anchor_col.min(cursor_col),
+            self.anchor_col.max(cursor_col),
+        )
+    }
+
+    /// Returns the ordered `(start, end)` positions of a linear selection.
+    pub fn linear_bounds(
+        &self,
+        cursor_line: usize,
+        cursor_col: usize,
+    ) -> ((usize, usize), (usize, usize)) {
+        let anchor = (self.anchor_line, self.anchor_col);
+        let cursor = (cursor_line, cursor_col);
+        if anchor <= cursor {
+            (anchor, cursor)
+        } else {
+            (cursor, anchor)
+        }
+    }
+
+    pub fn contains(&self, cursor_line: usize, cursor_col: usize, line: usize, col: usize) -> bool {
+        match self.kind {
+            SelectionKind::Block => {
+                let (top, bottom, left, right) = self.block_bounds(cursor_line, cursor_col);
+                line >= top && line <= bottom && col >= left && col < right
+            }
+            SelectionKind::Linear => {
+                let (start, end) = self.linear_bounds(cursor_line, cursor_col);
+                (line, col) >= start && (line, col) < end
+            }
+        }
+    }
+}
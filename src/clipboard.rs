@@ -0,0 +1,44 @@
+/// Copies to the system clipboard, falling back to an in-editor register when
+/// no clipboard is available (e.g. over SSH or without a display server).
+pub struct Clipboard {
+    system: Option<arboard::Clipboard>,
+    local: String,
+}
+
+impl Clipboard {
+    pub fn new() -> Self {
+        Self {
+            system: arboard::Clipboard::new().ok(),
+            local: String::new(),
+        }
+    }
+
+    /// Only the in-editor register, leaving the system clipboard alone.
+    #[cfg(test)]
+    pub fn local() -> Self {
+        Self {
+            system: None,
+            local: String::new(),
+        }
+    }
+
+    pub fn get(&mut self) -> String {
+        self.system
+            .as_mut()
+            .and_then(|c| c.get_text().ok())
+            .unwrap_or_else(|| self.local.clone())
+    }
+
+    pub fn set(&mut self, text: String) {
+        if let Some(c) = self.system.as_mut() {
+            let _ = c.set_text(text.clone());
+        }
+        self.local = text;
+    }
+}
+
+impl Default for Clipboard {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,198 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+
+use crossterm::event::{KeyCode, KeyEvent, KeyModifiers};
+
+/// Actions that can be bound to keys, with their default keys. The names are
+/// the editor commands they run; an action may have several default keys.
+pub const DEFAULT_BINDINGS: &[(&str, &str)] = &[
+    ("help", "ctrl+h"),
+    ("command_palette", "ctrl+p"),
+    ("close", "ctrl+q"),
+    ("reload", "ctrl+r"),
+    ("new_tab", "ctrl+n"),
+    ("next_tab", "ctrl+pagedown"),
+    ("next_tab", "ctrl+tab"),
+    ("prev_tab", "ctrl+pageup"),
+    ("prev_tab", "ctrl+backtab"),
+    ("save", "ctrl+s"),
+    ("open", "ctrl+o"),
+    ("format_json", "alt+shift+f"),
+    ("reopen_closed", "alt+r"),
+    ("undo", "ctrl+z"),
+    ("redo", "ctrl+y"),
+    ("toggle_line_numbers", "ctrl+b"),
+    ("toggle_help_bar", "ctrl+t"),
+    ("toggle_word_wrap", "ctrl+w"),
+    ("toggle_overwrite", "insert"),
+    ("toggle_read_only", "alt+shift+r"),
+    ("stats", "ctrl+shift+i"),
+    ("theme", "ctrl+shift+t"),
+    ("search", "ctrl+f"),
+    ("replace", "ctrl+\\"),
+    ("goto_line", "ctrl+g"),
+    ("goto_bracket", "ctrl+]"),
+    // Most terminals send Ctrl+] as the same byte as Ctrl+5.
+    ("goto_bracket", "ctrl+5"),
+    ("toggle_mark", "ctrl+f2"),
+    ("next_mark", "f2"),
+    ("prev_mark", "shift+f2"),
+    ("jump_back", "alt+left"),
+    ("jump_forward", "alt+right"),
+    ("toggle_split", "alt+\\"),
+    ("switch_pane", "alt+w"),
+    ("toggle_diff", "alt+d"),
+    ("toggle_git_diff", "alt+g"),
+    ("insert_datetime", "alt+shift+d"),
+];
+
+/// A key with its modifiers, as written in the config (`"ctrl+s"`) or
+/// pressed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct KeyCombo {
+    pub code: KeyCode,
+    pub modifiers: KeyModifiers,
+}
+
+impl KeyCombo {
+    /// Normalizes letters to lowercase with an explicit Shift, since
+    /// terminals differ in how they report shifted keys.
+    pub fn new(code: KeyCode, modifiers: KeyModifiers) -> Self {
+        let (code, modifiers) = match code {
+            KeyCode::Char(c) if c.is_uppercase() => (
+                KeyCode::Char(c.to_lowercase().next().unwrap_or(c)),
+                modifiers | KeyModifiers::SHIFT,
+            ),
+            KeyCode::BackTab => (code, modifiers - KeyModifiers::SHIFT),
+            _ => (code, modifiers),
+        };
+        Self { code, modifiers }
+    }
+
+    pub fn from_event(key: &KeyEvent) -> Self {
+        Self::new(key.code, key.modifiers)
+    }
+
+    /// Parses descriptors like `"ctrl+s"`, `"alt+shift+f"` or `"f3"`.
+    pub fn parse(descriptor: &str) -> Result<Self, String> {
+        let descriptor = descriptor.trim().to_lowercase();
+        let mut parts: Vec<&str> = descriptor.split('+').collect();
+        // "ctrl++" binds the plus key.
+        if descriptor.ends_with("++") {
+            parts.truncate(parts.len() - 2);
+            parts.push("+");
+        }
+        let Some((key, modifiers)) = parts.split_last() else {
+            return Err(format!("invalid key '{}'", descriptor));
+        };
+
+        let mut mods = KeyModifiers::NONE;
+        for modifier in modifiers {
+            mods |= match *modifier {
+                "ctrl" | "control" => KeyModifiers::CONTROL,
+                "alt" => KeyModifiers::ALT,
+                "shift" => KeyModifiers::SHIFT,
+                _ => {
+                    return Err(format!(
+                        "unknown modifier '{}' in '{}'",
+                        modifier, descriptor
+                    ));
+                }
+            };
+        }
+
+        let code = match *key {
+            "enter" => KeyCode::Enter,
+            "esc" | "escape" => KeyCode::Esc,
+            "tab" => KeyCode::Tab,
+            "backtab" => KeyCode::BackTab,
+            "backspace" => KeyCode::Backspace,
+            "delete" | "del" => KeyCode::Delete,
+            "insert" => KeyCode::Insert,
+            "home" => KeyCode::Home,
+            "end" => KeyCode::End,
+            "pageup" => KeyCode::PageUp,
+            "pagedown" => KeyCode::PageDown,
+            "up" => KeyCode::Up,
+            "down" => KeyCode::Down,
+            "left" => KeyCode::Left,
+            "right" => KeyCode::Right,
+            "space" => KeyCode::Char(' '),
+            f if f.len() > 1 && f.starts_with('f') && f[1..].parse::<u8>().is_ok() => {
+                KeyCode::F(f[1..].parse().unwrap_or(1))
+            }
+            c if c.chars().count() == 1 => KeyCode::Char(c.chars().next().unwrap_or(' ')),
+            _ => return Err(format!("unknown key '{}'", descriptor)),
+        };
+        Ok(Self::new(code, mods))
+    }
+}
+
+impl fmt::Display for KeyCombo {
+    /// Formats as shown to the user, e.g. `Ctrl+Shift+T`.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (modifier, name) in [
+            (KeyModifiers::CONTROL, "Ctrl"),
+            (KeyModifiers::ALT, "Alt"),
+            (KeyModifiers::SHIFT, "Shift"),
+        ] {
+            if self.modifiers.contains(modifier) {
+                write!(f, "{}+", name)?;
+            }
+        }
+        match self.code {
+            KeyCode::Char(' ') => write!(f, "Space"),
+            KeyCode::Char(c) => write!(f, "{}", c.to_uppercase()),
+            KeyCode::F(n) => write!(f, "F{}", n),
+            KeyCode::PageUp => write!(f, "PageUp"),
+            KeyCode::PageDown => write!(f, "PageDown"),
+            KeyCode::BackTab => write!(f, "BackTab"),
+            code => write!(f, "{:?}", code),
+        }
+    }
+}
+
+/// Builds the key map from the defaults, with each action in `overrides`
+/// bound to its key instead. An empty key unbinds the action. Returns
+/// warnings for bindings that were ignored.
+pub fn keymap(
+    overrides: &BTreeMap<String, String>,
+) -> (HashMap<KeyCombo, &'static str>, Vec<String>) {
+    let mut warnings = Vec::new();
+    let mut map = HashMap::new();
+    for &(action, key) in DEFAULT_BINDINGS {
+        if !overrides.contains_key(action)
+            && let Ok(combo) = KeyCombo::parse(key)
+        {
+            map.insert(combo, action);
+        }
+    }
+
+    let mut bound_by_user = HashMap::new();
+    for (name, key) in overrides {
+        let Some(&(action, _)) = DEFAULT_BINDINGS.iter().find(|(a, _)| a == name) else {
+            warnings.push(format!("unknown action '{}'", name));
+            continue;
+        };
+        if key.trim().is_empty() {
+            continue;
+        }
+        let combo = match KeyCombo::parse(key) {
+            Ok(combo) => combo,
+            Err(e) => {
+                warnings.push(format!("{}: {}", action, e));
+                continue;
+            }
+        };
+        if let Some(other) = bound_by_user.insert(combo, action) {
+            warnings.push(format!(
+                "'{}' is bound to both {} and {}",
+                key, other, action
+            ));
+            bound_by_user.insert(combo, other);
+            continue;
+        }
+        map.insert(combo, action);
+    }
+    (map, warnings)
+}
@@ -1,3 +1,7 @@
+pub mod keybindings;
+pub mod positions;
 pub mod settings;
 
-pub use settings::Settings;
+pub use keybindings::KeyCombo;
+pub use positions::{Position, Positions};
+pub use settings::{CursorStyle, Settings};
@@ -0,0 +1,104 @@
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+/// Where the cursor was when a file was last closed.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Position {
+    pub line: usize,
+    pub col: usize,
+    pub scroll_offset: usize,
+}
+
+/// Cursor positions by absolute file path, kept in `positions.toml` next to
+/// the config file.
+#[derive(Clone, Debug, Default)]
+pub struct Positions {
+    files: BTreeMap<String, Position>,
+}
+
+impl Positions {
+    pub fn load() -> Self {
+        Self::positions_path()
+            .map(|path| Self::load_from(&path))
+            .unwrap_or_default()
+    }
+
+    /// Reads positions from `path`, dropping files that no longer exist.
+    pub fn load_from(path: &Path) -> Self {
+        let mut files: BTreeMap<String, Position> = std::fs::read_to_string(path)
+            .ok()
+            .and_then(|contents| toml::from_str(&contents).ok())
+            .unwrap_or_default();
+        files.retain(|file, _| Path::new(file).exists());
+        Self { files }
+    }
+
+    pub fn save(&self) -> std::io::Result<()> {
+        match Self::positions_path() {
+            Some(path) => self.save_to(&path),
+            None => Ok(()),
+        }
+    }
+
+    pub fn save_to(&self, path: &Path) -> std::io::Result<()> {
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let toml = toml::to_string_pretty(&self.files).map_err(std::io::Error::other)?;
+        std::fs::write(path, toml)
+    }
+
+    pub fn get(&self, file: &Path) -> Option<Position> {
+        self.files.get(&Self::key(file)?).copied()
+    }
+
+    pub fn set(&mut self, file: &Path, position: Position) {
+        if let Some(key) = Self::key(file) {
+            self.files.insert(key, position);
+        }
+    }
+
+    fn key(file: &Path) -> Option<String> {
+        std::path::absolute(file)
+            .ok()
+            .map(|p| p.to_string_lossy().into_owned())
+    }
+
+    fn positions_path() -> Option<PathBuf> {
+        dirs::config_dir().map(|p| p.join("nova").join("positions.toml"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn positions_round_trip_and_forget_missing_files() {
+        let dir = std::env::temp_dir().join(format!("nova-test-{}-positions", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let kept = dir.join("kept.txt");
+        let gone = dir.join("gone.txt");
+        std::fs::write(&kept, "").unwrap();
+        std::fs::write(&gone, "").unwrap();
+
+        let position = Position {
+            line: 120,
+            col: 4,
+            scroll_offset: 100,
+        };
+        let mut positions = Positions::default();
+        positions.set(&kept, position);
+        positions.set(&gone, Position::default());
+        let file = dir.join("positions.toml");
+        positions.save_to(&file).unwrap();
+        std::fs::remove_file(&gone).unwrap();
+
+        let loaded = Positions::load_from(&file);
+        assert_eq!(loaded.get(&kept), Some(position));
+        assert_eq!(loaded.get(&gone), None);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
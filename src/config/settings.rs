@@ -1,37 +1,118 @@
 use serde::{Deserialize, Serialize};
-use std::path::PathBuf;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
 pub struct Settings {
-    pub tab_size: usize,
+    /// Spaces a soft tab inserts and an indentation level spans. Configs
+    /// from before the split set it as `tab_size`.
+    #[serde(alias = "tab_size")]
+    pub indent_size: usize,
+    /// Columns between tab stops when displaying `\t`.
+    pub tab_width: usize,
     pub use_spaces: bool,
+    /// Indent opened files the way they already are, when that can be
+    /// told, instead of by `use_spaces` and `indent_size`.
+    pub detect_indent: bool,
     pub show_line_numbers: bool,
     pub highlight_current_line: bool,
     pub word_wrap: bool,
+    /// Lines of context kept visible above and below the cursor.
+    pub scroll_off: usize,
     pub auto_save: bool,
+    /// Seconds without edits before `auto_save` writes the file.
+    pub auto_save_interval_secs: u64,
     pub auto_indent: bool,
+    /// Edits kept for undo in each buffer; 0 keeps every edit.
+    pub undo_limit: usize,
+    /// Keep undo history across sessions for files reopened unchanged.
+    pub persist_undo: bool,
     pub theme: String,
     pub show_tabs: bool,
+    /// Draw tabs as `→` and leading or trailing spaces as `·`.
+    pub show_whitespace: bool,
+    /// Give spaces and tabs at the ends of lines a warning background.
+    pub highlight_trailing_whitespace: bool,
+    /// Also highlight them on the cursor's line, where they come and go
+    /// while typing.
+    pub trailing_whitespace_on_current_line: bool,
     pub show_status_bar: bool,
+    pub show_scrollbar: bool,
     pub show_help: bool,
     pub mouse_support: bool,
+    /// Wrap cursor movement around the start and end of the document.
+    pub cursor_wrap: bool,
+    /// `"blink"` or `"solid"`.
+    pub cursor_style: CursorStyle,
+    /// Milliseconds the cursor stays on or off while blinking; 0 keeps it
+    /// solid.
+    pub cursor_blink_ms: u64,
+    /// `strftime` format for `insert_datetime`, e.g. `"%d/%m/%Y %H:%M"`.
+    pub datetime_format: String,
+    /// Spaces per level when pretty-printing JSON.
+    pub json_indent: usize,
+    /// Backspace in leading spaces removes a whole indentation level.
+    pub smart_backspace: bool,
+    /// Typing an opening bracket or quote also inserts its closer.
+    pub auto_close_brackets: bool,
+    /// Periodically write unsaved changes to a recovery file.
+    pub recovery_files: bool,
+    /// Strip spaces and tabs from the ends of lines when saving.
+    pub trim_trailing_whitespace: bool,
+    /// Always end saved files with a newline, even if they had none.
+    pub insert_final_newline: bool,
+    /// Command run once after startup, e.g. `"goto_line 42"` or `"open"`.
+    pub startup_command: Option<String>,
+    /// Keys for editor actions, e.g. `save = "ctrl+s"`. Unlisted actions
+    /// keep their default keys.
+    pub keybindings: BTreeMap<String, String>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CursorStyle {
+    Blink,
+    Solid,
 }
 
 impl Default for Settings {
     fn default() -> Self {
         Self {
-            tab_size: 4,
+            indent_size: 4,
+            tab_width: 4,
             use_spaces: true,
+            detect_indent: true,
             show_line_numbers: true,
             highlight_current_line: true,
             word_wrap: false,
+            scroll_off: 3,
             auto_save: false,
+            auto_save_interval_secs: 30,
             auto_indent: true,
+            undo_limit: 1000,
+            persist_undo: false,
             theme: "monokai_pro".to_string(),
             show_tabs: true,
+            show_whitespace: false,
+            highlight_trailing_whitespace: true,
+            trailing_whitespace_on_current_line: false,
             show_status_bar: true,
+            show_scrollbar: true,
             show_help: true,
             mouse_support: true,
+            cursor_wrap: false,
+            cursor_style: CursorStyle::Blink,
+            cursor_blink_ms: 500,
+            datetime_format: "%Y-%m-%dT%H:%M:%S".to_string(),
+            json_indent: 2,
+            smart_backspace: true,
+            auto_close_brackets: true,
+            recovery_files: true,
+            trim_trailing_whitespace: false,
+            insert_final_newline: true,
+            startup_command: None,
+            keybindings: BTreeMap::new(),
         }
     }
 }
@@ -39,31 +120,88 @@ impl Default for Settings {
 impl Settings {
     pub fn load() -> Self {
         let config_path = Self::config_path();
-        if let Some(path) = config_path {
-            if path.exists() {
-                if let Ok(contents) = std::fs::read_to_string(&path) {
-                    if let Ok(settings) = toml::from_str(&contents) {
-                        return settings;
-                    }
-                }
-            }
+        if let Some(path) = config_path
+            && path.exists()
+            && let Ok(contents) = std::fs::read_to_string(&path)
+            && let Ok(settings) = toml::from_str(&contents)
+        {
+            return settings;
         }
         Self::default()
     }
 
-    #[allow(dead_code)]
-    pub fn save(&self) -> std::io::Result<()> {
-        if let Some(path) = Self::config_path() {
-            if let Some(parent) = path.parent() {
-                std::fs::create_dir_all(parent)?;
+    /// Sets `theme` in the config file, leaving the rest of it, comments
+    /// included, as it was.
+    pub fn save_theme(theme: &str) -> std::io::Result<()> {
+        match Self::config_path() {
+            Some(path) => Self::save_theme_to(&path, theme),
+            None => Ok(()),
+        }
+    }
+
+    /// Sets `theme` in the config file at `path`, creating the file if
+    /// needed. A file that doesn't parse is left alone.
+    pub fn save_theme_to(path: &Path, theme: &str) -> std::io::Result<()> {
+        let contents = match std::fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(e),
+        };
+        let mut doc: toml_edit::DocumentMut = contents.parse().map_err(|_| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("{} has errors", path.display()),
+            )
+        })?;
+        match doc.get_mut("theme").and_then(|item| item.as_value_mut()) {
+            // Keep the spacing and any comment after the old value.
+            Some(value) => {
+                let decor = value.decor().clone();
+                *value = theme.into();
+                *value.decor_mut() = decor;
             }
-            let toml = toml::to_string_pretty(self).unwrap();
-            std::fs::write(path, toml)?;
+            None => doc["theme"] = toml_edit::value(theme),
+        }
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
         }
-        Ok(())
+        std::fs::write(path, doc.to_string())
     }
 
     fn config_path() -> Option<PathBuf> {
         dirs::config_dir().map(|p| p.join("nova").join("config.toml"))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn saving_the_theme_keeps_the_rest_of_the_config() {
+        let path =
+            std::env::temp_dir().join(format!("nova-test-{}-config.toml", std::process::id()));
+        std::fs::write(
+            &path,
+            "# Mine\nword_wrap = true\ntheme = \"dracula\" # dark\n",
+        )
+        .unwrap();
+        Settings::save_theme_to(&path, "nord_frost").unwrap();
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            "# Mine\nword_wrap = true\ntheme = \"nord_frost\" # dark\n"
+        );
+
+        std::fs::write(&path, "word_wrap = \n").unwrap();
+        assert!(Settings::save_theme_to(&path, "dracula").is_err());
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "word_wrap = \n");
+
+        std::fs::remove_file(&path).unwrap();
+        Settings::save_theme_to(&path, "dracula").unwrap();
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            "theme = \"dracula\"\n"
+        );
+        std::fs::remove_file(path).unwrap();
+    }
+}
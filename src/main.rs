@@ -1,29 +1,50 @@
-use std::io::{self, stdout};
+use std::collections::HashMap;
+use std::io::{self, IsTerminal, Read, Write, stdout};
 
 use crossterm::{
-    event::{self, Event, KeyCode, KeyEventKind, KeyModifiers},
+    ExecutableCommand,
+    event::{
+        self, DisableMouseCapture, EnableMouseCapture, Event, KeyCode, KeyEventKind, KeyModifiers,
+        MouseButton, MouseEvent, MouseEventKind,
+    },
     terminal::{
-        disable_raw_mode, enable_raw_mode, size, EnterAlternateScreen, LeaveAlternateScreen,
+        EnterAlternateScreen, LeaveAlternateScreen, disable_raw_mode, enable_raw_mode, size,
     },
-    ExecutableCommand,
 };
 use ratatui::{
+    Terminal,
     backend::CrosstermBackend,
     layout::{Margin, Rect},
     style::Style,
+    text::{Line, Span},
     widgets::Paragraph,
-    Terminal,
+};
+use unicode_segmentation::UnicodeSegmentation;
+
+use crate::buffer::{
+    Buffer, Indent, LineChange, Pattern, SaveStatus, Selection, SelectionKind, decode, diff,
+    recovery,
+};
+use crate::clipboard::Clipboard;
+use crate::config::{CursorStyle, KeyCombo, Position, Positions, Settings, keybindings};
+use crate::syntax::{Highlighter, TokenKind};
+use crate::ui::{
+    EditorView, HelpBar, StatusBar, TabBar, Theme,
+    widgets::{
+        TitleBar, block_byte_range, block_columns, byte_col_at, display_width, text_columns,
+        wrap_rows,
+    },
 };
 
-use crate::buffer::Buffer;
-use crate::config::Settings;
-use crate::ui::{widgets::TitleBar, EditorView, HelpBar, StatusBar, Theme};
+use serde::{Deserialize, Serialize};
 
 mod buffer;
+mod clipboard;
 mod config;
+mod syntax;
 mod ui;
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 enum EditOp {
     Insert {
         pos: usize,
@@ -42,18 +63,58 @@ enum EditOp {
         old_text: String,
         new_text: String,
     },
+    /// Several edits applied in order and undone together.
+    Batch(Vec<EditOp>),
+}
+
+/// Applies the settings that rewrite a file as it is saved. Trimmed
+/// whitespace is recorded as a single undo step.
+fn prepare_save(settings: &Settings, buffer: &mut Buffer, undo: &mut UndoHistory) {
+    if settings.trim_trailing_whitespace {
+        let ops: Vec<EditOp> = buffer
+            .trim_trailing_whitespace()
+            .into_iter()
+            .map(|(pos, text)| EditOp::Delete { pos, text })
+            .collect();
+        if !ops.is_empty() {
+            undo.push(EditOp::Batch(ops));
+        }
+    }
+    // Takes precedence over keeping a missing final newline missing.
+    if settings.insert_final_newline {
+        buffer.final_newline = true;
+    }
 }
 
 struct UndoHistory {
     ops: Vec<EditOp>,
     pos: usize,
+    /// Most edits kept; the oldest are dropped beyond it. 0 keeps them all.
+    limit: usize,
+}
+
+/// Undo history as saved between sessions, with a hash of the text it was
+/// recorded against.
+#[derive(Serialize, Deserialize)]
+struct SavedUndo {
+    text_hash: u64,
+    pos: usize,
+    ops: Vec<EditOp>,
+}
+
+fn text_hash(text: &str) -> u64 {
+    use std::hash::{DefaultHasher, Hash, Hasher};
+    let mut hasher = DefaultHasher::new();
+    text.hash(&mut hasher);
+    hasher.finish()
 }
 
 impl UndoHistory {
-    fn new() -> Self {
+    fn new(limit: usize) -> Self {
         Self {
             ops: Vec::new(),
             pos: 0,
+            limit,
         }
     }
 
@@ -61,9 +122,10 @@ impl UndoHistory {
         self.ops.truncate(self.pos);
         self.ops.push(op);
         self.pos += 1;
-        if self.ops.len() > 1000 {
-            self.ops.remove(0);
-            self.pos -= 1;
+        if self.limit > 0 && self.ops.len() > self.limit {
+            let excess = self.ops.len() - self.limit;
+            self.ops.drain(..excess);
+            self.pos -= excess;
         }
     }
 
@@ -72,52 +134,57 @@ impl UndoHistory {
             return false;
         }
         self.pos -= 1;
-        match &self.ops[self.pos] {
-            EditOp::Insert { pos, text } => {
-                buffer.delete(*pos, text.len());
-                true
-            }
-            EditOp::Delete { pos, text } => {
-                buffer.insert(*pos, text);
-                true
-            }
+        Self::revert(&self.ops[self.pos], buffer);
+        true
+    }
+
+    fn redo(&mut self, buffer: &mut Buffer) -> bool {
+        if self.pos >= self.ops.len() {
+            return false;
+        }
+        Self::apply(&self.ops[self.pos], buffer);
+        self.pos += 1;
+        true
+    }
+
+    fn apply(op: &EditOp, buffer: &mut Buffer) {
+        match op {
+            EditOp::Insert { pos, text } => buffer.insert(*pos, text),
+            EditOp::Delete { pos, text } => buffer.delete(*pos, text.len()),
             EditOp::Replace {
                 pos,
                 old_len: _,
                 old_text,
                 new_text,
             } => {
-                buffer.delete(*pos, new_text.len());
-                buffer.insert(*pos, old_text);
-                true
+                buffer.delete(*pos, old_text.len());
+                buffer.insert(*pos, new_text);
+            }
+            EditOp::Batch(ops) => {
+                for op in ops {
+                    Self::apply(op, buffer);
+                }
             }
         }
     }
 
-    fn redo(&mut self, buffer: &mut Buffer) -> bool {
-        if self.pos >= self.ops.len() {
-            return false;
-        }
-        match &self.ops[self.pos] {
-            EditOp::Insert { pos, text } => {
-                buffer.insert(*pos, text);
-                self.pos += 1;
-                true
-            }
-            EditOp::Delete { pos, text } => {
-                buffer.delete(*pos, text.len());
-                self.pos += 1;
-                true
-            }
+    fn revert(op: &EditOp, buffer: &mut Buffer) {
+        match op {
+            EditOp::Insert { pos, text } => buffer.delete(*pos, text.len()),
+            EditOp::Delete { pos, text } => buffer.insert(*pos, text),
             EditOp::Replace {
                 pos,
                 old_len: _,
-                old_text: _,
+                old_text,
                 new_text,
             } => {
-                buffer.insert(*pos, new_text);
-                self.pos += 1;
-                true
+                buffer.delete(*pos, new_text.len());
+                buffer.insert(*pos, old_text);
+            }
+            EditOp::Batch(ops) => {
+                for op in ops.iter().rev() {
+                    Self::revert(op, buffer);
+                }
             }
         }
     }
@@ -126,6 +193,81 @@ impl UndoHistory {
         self.ops.clear();
         self.pos = 0;
     }
+
+    /// The history as JSON, for a buffer currently holding `text`.
+    fn to_json(&self, text: &str) -> String {
+        let saved = SavedUndo {
+            text_hash: text_hash(text),
+            pos: self.pos,
+            ops: self.ops.clone(),
+        };
+        serde_json::to_string(&saved).unwrap_or_default()
+    }
+
+    /// Reads a history saved by `to_json`, unless `text` has changed since.
+    fn from_json(json: &str, text: &str, limit: usize) -> Option<Self> {
+        let saved: SavedUndo = serde_json::from_str(json).ok()?;
+        if saved.text_hash != text_hash(text) || saved.pos > saved.ops.len() {
+            return None;
+        }
+        let mut undo = Self {
+            ops: saved.ops,
+            pos: saved.pos,
+            limit,
+        };
+        if limit > 0 && undo.ops.len() > limit {
+            let excess = (undo.ops.len() - limit).min(undo.pos);
+            undo.ops.drain(..excess);
+            undo.pos -= excess;
+        }
+        Some(undo)
+    }
+}
+
+/// Cursor positions from before jumps, as byte offsets, for going back and
+/// forward. `index` is the entry being visited, or the length of the list
+/// when not moving through it.
+#[derive(Default)]
+struct JumpList {
+    positions: Vec<usize>,
+    index: usize,
+}
+
+impl JumpList {
+    /// Records a position to come back to, dropping any ahead of the entry
+    /// being visited.
+    fn push(&mut self, pos: usize) {
+        self.positions.truncate(self.index);
+        if self.positions.last() != Some(&pos) {
+            self.positions.push(pos);
+        }
+        if self.positions.len() > MAX_JUMPS {
+            self.positions.remove(0);
+        }
+        self.index = self.positions.len();
+    }
+
+    /// The entry before the one being visited. Leaving the newest end
+    /// records `current` so `forward` can return to it.
+    fn back(&mut self, current: usize) -> Option<usize> {
+        if self.index == self.positions.len() {
+            self.push(current);
+            self.index = self.positions.len() - 1;
+        }
+        if self.index == 0 {
+            return None;
+        }
+        self.index -= 1;
+        Some(self.positions[self.index])
+    }
+
+    fn forward(&mut self) -> Option<usize> {
+        if self.index + 1 >= self.positions.len() {
+            return None;
+        }
+        self.index += 1;
+        Some(self.positions[self.index])
+    }
 }
 
 #[derive(Clone, PartialEq)]
@@ -134,16 +276,22 @@ enum EditorMode {
     Search {
         query: String,
         case_sensitive: bool,
+        regex: bool,
         backward: bool,
     },
     Replace {
         search: String,
         replace: String,
         case_sensitive: bool,
+        regex: bool,
         all: bool,
-        confirmed: bool,
+        /// Only replace inside `Editor::replace_selection`.
+        in_selection: bool,
+        stage: ReplaceStage,
+    },
+    GoToLine {
+        input: String,
     },
-    GoToLine,
     Confirm {
         title: String,
         message: String,
@@ -155,89 +303,445 @@ enum EditorMode {
         input: String,
         history: Vec<String>,
     },
+    FileBrowser {
+        dir: std::path::PathBuf,
+        entries: Vec<BrowserEntry>,
+        selected: usize,
+    },
+    /// `selected` indexes the commands matching `query`.
+    CommandPalette {
+        query: String,
+        selected: usize,
+    },
     Help,
+    /// Document statistics, one `(label, value)` row each.
+    Stats {
+        rows: Vec<(String, String)>,
+    },
+}
+
+/// A row of the file browser; `..` stands for the parent directory.
+#[derive(Clone, PartialEq)]
+struct BrowserEntry {
+    name: String,
+    is_dir: bool,
+}
+
+/// Which part of the find & replace prompt has focus.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum ReplaceStage {
+    Search,
+    Replace,
+    /// Stepping through matches, asking whether to replace each one.
+    Confirm,
+}
+
+/// Commands offered by the command palette, as `(label, command)`.
+const PALETTE_COMMANDS: &[(&str, &str)] = &[
+    ("Save", "save"),
+    ("Open File", "open"),
+    ("Close Tab", "close"),
+    ("Reload File", "reload"),
+    ("New Tab", "new_tab"),
+    ("Next Tab", "next_tab"),
+    ("Previous Tab", "prev_tab"),
+    ("Reopen Closed Tab", "reopen_closed"),
+    ("Undo", "undo"),
+    ("Redo", "redo"),
+    ("Repeat Last Action", "repeat_last"),
+    ("Search", "search"),
+    ("Replace", "replace"),
+    ("Go to Line", "goto_line"),
+    ("Go to Matching Bracket", "goto_bracket"),
+    ("Toggle Mark", "toggle_mark"),
+    ("Next Mark", "next_mark"),
+    ("Previous Mark", "prev_mark"),
+    ("Jump Back", "jump_back"),
+    ("Jump Forward", "jump_forward"),
+    ("Change Theme", "theme"),
+    ("Toggle Word Wrap", "toggle_word_wrap"),
+    ("Toggle Line Numbers", "toggle_line_numbers"),
+    ("Toggle Help Bar", "toggle_help_bar"),
+    ("Toggle Overwrite Mode", "toggle_overwrite"),
+    ("Toggle Read-Only", "toggle_read_only"),
+    ("Toggle Split View", "toggle_split"),
+    ("Toggle Diff Markers", "toggle_diff"),
+    ("Toggle Git Change Markers", "toggle_git_diff"),
+    ("Switch Split Pane", "switch_pane"),
+    ("Document Statistics", "stats"),
+    ("Insert Date/Time", "insert_datetime"),
+    ("Format JSON", "format_json"),
+    ("Minify JSON", "minify_json"),
+    ("Help", "help"),
+];
+
+/// Commands that change the buffer, refused in read-only mode.
+const EDITING_COMMANDS: &[&str] = &[
+    "undo",
+    "redo",
+    "replace",
+    "format_json",
+    "minify_json",
+    "insert_datetime",
+    "repeat_last",
+];
+
+const MAX_CLOSED_BUFFERS: usize = 10;
+const MAX_JUMPS: usize = 100;
+const CHORD_TIMEOUT_MS: u128 = 1000;
+const RECOVERY_INTERVAL_SECS: u64 = 5;
+const OPEN_FILE_TITLE: &str = "Open File";
+const OPEN_TITLE: &str = "Open";
+const RELOAD_TITLE: &str = "Reload";
+/// How often to check whether the open file changed on disk.
+const DISK_CHECK_INTERVAL_SECS: u64 = 2;
+const MESSAGE_TIMEOUT_SECS: u64 = 4;
+
+/// Writes the undo history for `path`, or removes it when there is nothing
+/// to undo or redo. A partly loaded file keeps the history it had.
+fn save_undo(path: &std::path::Path, buffer: &Buffer, undo: &UndoHistory) {
+    if buffer.is_partial() {
+        return;
+    }
+    if undo.ops.is_empty() {
+        recovery::remove_undo(path);
+    } else {
+        let _ = recovery::write_undo(path, &undo.to_json(&buffer.text.to_string()));
+    }
+}
+
+/// Status message for a failed save.
+fn save_error(e: &io::Error) -> String {
+    if e.kind() == io::ErrorKind::PermissionDenied {
+        "No write permission".to_string()
+    } else {
+        format!("Save failed: {}", e)
+    }
+}
+/// Columns kept visible left and right of the cursor on long lines.
+const SIDE_SCROLL_OFF: usize = 5;
+
+/// A file open in a tab. The active tab's state lives in the `Editor`'s own
+/// fields; the others are parked as `BufferState`s.
+struct BufferState {
+    buffer: Buffer,
+    cursor_line: usize,
+    cursor_col: usize,
+    scroll_offset: usize,
+    col_offset: usize,
+    selection: Option<Selection>,
+    undo: UndoHistory,
+    jumps: JumpList,
+    recovery_revision: u64,
+}
+
+/// Where the unfocused half of a split view is looking. It shows the same
+/// buffer as the focused half.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct Pane {
+    cursor_line: usize,
+    cursor_col: usize,
+    scroll_offset: usize,
+    col_offset: usize,
+}
+
+impl BufferState {
+    fn new(buffer: Buffer, undo_limit: usize) -> Self {
+        Self {
+            buffer,
+            cursor_line: 0,
+            cursor_col: 0,
+            scroll_offset: 0,
+            col_offset: 0,
+            selection: None,
+            undo: UndoHistory::new(undo_limit),
+            jumps: JumpList::default(),
+            recovery_revision: 0,
+        }
+    }
+}
+
+struct ClosedBuffer {
+    path: std::path::PathBuf,
+    cursor_line: usize,
+    cursor_col: usize,
 }
 
+const HELP_TEXT: &str = "Key          Action              Key          Action\n\
+     ------------------------------------------------\n\
+     Ctrl+O       Open file           Ctrl+Z       Undo\n\
+     Ctrl+S       Save file           Ctrl+Y       Redo\n\
+     Ctrl+F       Find text           Ctrl+Shift+T Change theme\n\
+     Ctrl+G       Go to line          Ctrl+B       Toggle lines\n\
+     Ctrl+\\       Replace             Ctrl+W       Toggle wrap\n\
+     Ctrl+Q       Quit                Ctrl+H       Help\n\
+     Ctrl+P       Command palette     Insert       Overwrite mode\n\
+     Ctrl+]       Matching bracket    Ctrl+F2      Toggle mark\n\
+     F2           Next mark           Shift+F2     Previous mark\n\
+     Alt+Left     Jump back           Alt+Right    Jump forward\n\
+     Alt+\\        Split view          Alt+W        Switch pane\n\
+     Alt+Shift+R  Toggle read-only    Ctrl+Shift+I Statistics\n\
+     Alt+D        Diff markers        Alt+G        Git markers\n\
+     Alt+Shift+D  Insert date/time";
+
 static TIPS: &[&str] = &[
     "Press Ctrl+F to search for text in the file",
     "Press Ctrl+\\ to find and replace text",
     "Press Ctrl+G to jump to a specific line number",
     "Use Ctrl+Z to undo and Ctrl+Y to redo changes",
-    "Press Ctrl+T to cycle through different themes",
+    "Press Ctrl+Shift+T to cycle through different themes",
     "Press Ctrl+B to toggle line numbers on/off",
     "Enable soft tabs in config for spaces instead of tabs",
     "Auto-indent is on by default - it preserves code structure",
     "Press Ctrl+W to toggle word wrap for long lines",
     "Use Ctrl+O to open a file, Ctrl+S to save",
+    "Press Ctrl+P to find and run any command by name",
 ];
 
+/// An edit that can be replayed at the cursor with "repeat last command".
+#[derive(Clone, Debug)]
+enum RepeatableAction {
+    /// A run of consecutively typed characters.
+    InsertText(String),
+    DeleteLine,
+    DeleteChar,
+    Comment(bool),
+}
+
+/// What gutter change markers compare the buffer with.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum DiffBase {
+    /// The file as saved on disk.
+    Disk,
+    /// The file as committed at git's HEAD.
+    GitHead,
+}
+
 #[derive(Clone)]
 enum PendingAction {
     SaveAndQuit,
     QuitWithoutSave,
     SaveAs(String),
-    ReplaceAll(String, String),
+    ReplaceAll(Pattern, String),
+    RestoreRecovery,
+    DiscardRecovery,
+    OpenFile(std::path::PathBuf),
+    SaveAndOpen,
+    DiscardAndOpen,
+    Reload,
+}
+
+/// Pattern compiled from the Search or Replace query, kept across renders
+/// until the query or its options change.
+struct SearchHighlight {
+    query: String,
+    case_sensitive: bool,
+    regex: bool,
+    pattern: Option<Pattern>,
 }
 
 struct Editor {
     buffer: Buffer,
+    /// Tabs other than the active one, in tab order.
+    buffers: Vec<BufferState>,
+    /// Position of the active tab among all tabs.
+    active: usize,
     cursor_line: usize,
     cursor_col: usize,
     scroll_offset: usize,
+    /// Display columns scrolled off the left edge when not wrapping.
+    col_offset: usize,
+    selection: Option<Selection>,
+    highlighter: Highlighter,
     settings: Settings,
+    /// Keys bound to editor commands, from the defaults and `[keybindings]`.
+    keymap: HashMap<KeyCombo, &'static str>,
     theme: Theme,
     show_help: bool,
     show_line_numbers: bool,
     word_wrap: bool,
     should_quit: bool,
     undo: UndoHistory,
+    /// Where search, go-to-line, bracket and mark jumps started.
+    jumps: JumpList,
+    /// The other half of the editor area when it is split.
+    split: Option<Pane>,
+    /// The bottom half of a split has focus.
+    split_bottom: bool,
+    /// What lines are marked in the gutter as changed against, if anything.
+    diff_base: Option<DiffBase>,
+    /// The file on disk as `diff_lines` last compared against.
+    disk_text: Option<String>,
+    diff_lines: Vec<(usize, LineChange)>,
+    /// `(revision, is_modified)` of the buffer `diff_lines` were computed
+    /// for; `None` after switching buffers.
+    diff_key: Option<(u64, bool)>,
     mode: EditorMode,
     pending_action: Option<PendingAction>,
     quit_after_save: bool,
+    /// Byte range replacing is limited to: the selection when the replace
+    /// prompt opened, unless "in selection" is turned off.
+    replace_selection: Option<(usize, usize)>,
+    /// `--stdout`: untitled buffers save to `stdout_text` instead of asking
+    /// for a file name, and it is written to stdout on exit.
+    to_stdout: bool,
+    stdout_text: Option<String>,
+    /// File to open once the current buffer has been saved.
+    open_after_save: Option<std::path::PathBuf>,
     cursor_blink_on: bool,
     last_cursor_time: std::time::Instant,
     screen_width: usize,
     screen_height: usize,
     current_tip: String,
+    help_scroll: u16,
+    /// Shown in the status bar until `MESSAGE_TIMEOUT_SECS` after
+    /// `message_time`; set through `set_message`.
+    status_message: Option<String>,
+    message_time: std::time::Instant,
+    closed_buffers: Vec<ClosedBuffer>,
+    /// Cursor positions remembered across sessions.
+    positions: Positions,
+    pending_chord: Option<(event::KeyEvent, std::time::Instant)>,
+    last_action: Option<RepeatableAction>,
+    insert_run: bool,
+    /// Typed characters replace the one under the cursor (Insert key).
+    overwrite: bool,
+    /// Editing keys and commands are refused (`--readonly`, Alt+Shift+R).
+    read_only: bool,
+    /// Closers inserted by `auto_close_brackets` during the current typing
+    /// run, innermost last.
+    auto_closed: Vec<char>,
+    /// Pattern of the most recent search.
+    last_search: Option<Pattern>,
+    search_highlight: std::cell::RefCell<Option<SearchHighlight>>,
+    clipboard: Clipboard,
+    recovery_revision: u64,
+    last_recovery_write: std::time::Instant,
+    /// When the buffer was last changed, for `auto_save`.
+    last_edit: std::time::Instant,
+    last_disk_check: std::time::Instant,
+    /// Disk modification time already reported as an outside change.
+    disk_change_reported: Option<std::time::SystemTime>,
+    /// Where the left mouse button went down, until it is released.
+    drag_anchor: Option<(usize, usize)>,
+    /// `(line, col)` of cursors besides the main one. Typing, Backspace and
+    /// Delete apply at all of them; any other key drops them.
+    extra_cursors: Vec<(usize, usize)>,
 }
 
 impl Editor {
     fn new(initial_file: Option<String>, width: usize, height: usize) -> Self {
-        let settings = Settings::load();
-        let theme = Theme::get_theme(&settings.theme);
+        Self::with_settings(
+            Settings::load(),
+            Positions::load(),
+            initial_file,
+            width,
+            height,
+        )
+    }
 
-        let buffer = if let Some(file_path) = initial_file {
-            let path = std::path::PathBuf::from(&file_path);
-            if path.exists() {
-                Buffer::from_file(path).unwrap_or_else(Buffer::new)
-            } else {
-                Buffer::for_new_file(path)
-            }
-        } else {
-            Buffer::new()
+    /// An editor using the given settings and remembered positions rather
+    /// than the ones in the user's config directory.
+    fn with_settings(
+        settings: Settings,
+        positions: Positions,
+        initial_file: Option<String>,
+        width: usize,
+        height: usize,
+    ) -> Self {
+        let (theme, theme_error) = match Theme::load(&settings.theme) {
+            Ok(theme) => (theme, None),
+            Err(e) => (Theme::monokai_pro(), Some(e)),
         };
+        let word_wrap = settings.word_wrap;
+        let undo = UndoHistory::new(settings.undo_limit);
+        let (keymap, keymap_warnings) = keybindings::keymap(&settings.keybindings);
 
-        Self {
+        let mut load_error = None;
+        let buffer = match initial_file.map(std::path::PathBuf::from) {
+            Some(path) => buffer_for_path(path.clone()).unwrap_or_else(|e| {
+                load_error = Some(format!("Cannot open {}: {}", path.display(), e));
+                Buffer::new()
+            }),
+            None => Buffer::new(),
+        };
+
+        let mut highlighter = Highlighter::new();
+        highlighter.set_language(&buffer.language);
+
+        let mut editor = Self {
             buffer,
+            buffers: Vec::new(),
+            active: 0,
             cursor_line: 0,
             cursor_col: 0,
             scroll_offset: 0,
+            col_offset: 0,
+            selection: None,
+            highlighter,
             settings,
+            keymap,
             theme,
             show_help: true,
             show_line_numbers: true,
-            word_wrap: false,
+            word_wrap,
             should_quit: false,
-            undo: UndoHistory::new(),
+            undo,
+            jumps: JumpList::default(),
+            split: None,
+            split_bottom: false,
+            diff_base: None,
+            disk_text: None,
+            diff_lines: Vec::new(),
+            diff_key: None,
             mode: EditorMode::Normal,
             pending_action: None,
             quit_after_save: false,
+            replace_selection: None,
+            to_stdout: false,
+            stdout_text: None,
+            open_after_save: None,
             cursor_blink_on: true,
             last_cursor_time: std::time::Instant::now(),
             screen_width: width,
             screen_height: height,
             current_tip: String::new(),
+            help_scroll: 0,
+            status_message: None,
+            message_time: std::time::Instant::now(),
+            closed_buffers: Vec::new(),
+            positions,
+            pending_chord: None,
+            last_action: None,
+            insert_run: false,
+            overwrite: false,
+            read_only: false,
+            auto_closed: Vec::new(),
+            last_search: None,
+            search_highlight: std::cell::RefCell::default(),
+            clipboard: Clipboard::new(),
+            recovery_revision: 0,
+            last_recovery_write: std::time::Instant::now(),
+            last_edit: std::time::Instant::now(),
+            last_disk_check: std::time::Instant::now(),
+            disk_change_reported: None,
+            drag_anchor: None,
+            extra_cursors: Vec::new(),
+        };
+        editor.check_recovery();
+        editor.note_mixed_line_endings();
+        editor.load_undo_history();
+        editor.restore_position();
+        if !keymap_warnings.is_empty() {
+            editor.set_message(format!("keybindings: {}", keymap_warnings.join("; ")));
+        }
+        if let Some(e) = theme_error {
+            editor.set_message(e);
         }
+        if let Some(e) = load_error {
+            editor.set_message(e);
+        }
+        editor
     }
 
     fn get_random_tip() -> String {
@@ -253,13 +757,194 @@ impl Editor {
         self.current_tip = Self::get_random_tip();
     }
 
+    fn open_help(&mut self) {
+        self.generate_tip();
+        self.help_scroll = 0;
+        self.mode = EditorMode::Help;
+    }
+
+    /// Shows line, word and character counts for the buffer, and for the
+    /// selection when there is one.
+    fn open_stats(&mut self) {
+        let mut rows = vec![
+            ("Lines".to_string(), self.buffer.num_lines().to_string()),
+            ("Words".to_string(), self.buffer.word_count().to_string()),
+            (
+                "Characters".to_string(),
+                self.buffer.char_count().to_string(),
+            ),
+            ("Bytes".to_string(), self.buffer.total_len().to_string()),
+        ];
+        let (start, end) = self.clipboard_range(self.selection);
+        if self.selection.is_some() && start < end {
+            let text = self.buffer.text.get_range(start, end);
+            let lines = text.trim_end_matches('\n').lines().count().max(1);
+            rows.extend([
+                ("Selected lines".to_string(), lines.to_string()),
+                (
+                    "Selected words".to_string(),
+                    text.split_whitespace().count().to_string(),
+                ),
+                (
+                    "Selected characters".to_string(),
+                    text.graphemes(true).count().to_string(),
+                ),
+                ("Selected bytes".to_string(), text.len().to_string()),
+            ]);
+        }
+        self.mode = EditorMode::Stats { rows };
+    }
+
+    /// Adapts to a new terminal size, keeping the cursor in view and the
+    /// help text scrolled no further than its end.
+    fn resize(&mut self, width: usize, height: usize) {
+        self.screen_width = width;
+        self.screen_height = height;
+        self.help_scroll = self.help_scroll.min(self.help_max_scroll());
+        self.update_scroll();
+    }
+
+    /// How far the help text scrolls before its last row reaches the bottom
+    /// of the help dialog at the current screen size.
+    fn help_max_scroll(&self) -> u16 {
+        let width = self.screen_width.min(60).saturating_sub(2);
+        let height = self.screen_height.min(20).saturating_sub(2);
+        let rows: usize = HELP_TEXT
+            .lines()
+            .map(|line| wrap_rows(line.trim(), width, self.settings.tab_width).len())
+            .sum();
+        rows.saturating_sub(height) as u16
+    }
+
+    /// Number of text lines the focused editor view shows: its pane of the
+    /// editor area minus the pane's top and bottom border.
+    fn view_height(&self) -> usize {
+        let (top, bottom) = self.pane_heights();
+        let height = if self.split_bottom { bottom } else { top };
+        height.saturating_sub(2).max(1)
+    }
+
+    /// Rows of the top and bottom pane: the screen minus the title, status
+    /// and help bars, halved when split.
+    fn pane_heights(&self) -> (usize, usize) {
+        let bars = if self.show_help { 3 } else { 2 } + self.tab_bar_height();
+        let height = self.screen_height.saturating_sub(bars);
+        if self.split.is_some() {
+            (height / 2, height - height / 2)
+        } else {
+            (height, 0)
+        }
+    }
+
+    fn pane(&self) -> Pane {
+        Pane {
+            cursor_line: self.cursor_line,
+            cursor_col: self.cursor_col,
+            scroll_offset: self.scroll_offset,
+            col_offset: self.col_offset,
+        }
+    }
+
+    /// Splits the editor area into two views of the buffer, or goes back to
+    /// one, keeping the focused view.
+    fn toggle_split(&mut self) {
+        if self.split.take().is_none() {
+            self.split = Some(self.pane());
+        }
+        self.split_bottom = false;
+        self.update_scroll();
+    }
+
+    /// Moves focus to the other half of a split view.
+    fn switch_pane(&mut self) {
+        let Some(other) = self.split.replace(self.pane()) else {
+            self.set_message("No split view".into());
+            return;
+        };
+        self.cursor_line = other.cursor_line;
+        self.cursor_col = other.cursor_col;
+        self.scroll_offset = other.scroll_offset;
+        self.col_offset = other.col_offset;
+        self.split_bottom = !self.split_bottom;
+        self.selection = None;
+        self.extra_cursors.clear();
+        self.drag_anchor = None;
+        // Edits in the other view may have moved the text out from under it.
+        self.clamp_cursor();
+        self.update_scroll();
+    }
+
+    /// The tab strip is shown once more than one file is open.
+    fn tab_bar_height(&self) -> usize {
+        usize::from(self.settings.show_tabs && self.tab_count() > 1)
+    }
+
+    /// File name and modified flag of every tab, in order.
+    fn tab_labels(&self) -> Vec<(String, bool)> {
+        let mut tabs: Vec<(String, bool)> = self
+            .buffers
+            .iter()
+            .map(|b| (b.buffer.file_name(), b.buffer.is_modified))
+            .collect();
+        tabs.insert(
+            self.active,
+            (self.buffer.file_name(), self.buffer.is_modified),
+        );
+        tabs
+    }
+
+    /// Scrolls so the cursor is on screen with `scroll_off` lines of context
+    /// above and below it, except at the ends of the document.
     fn update_scroll(&mut self) {
-        let view_height = self.screen_height.saturating_sub(3);
-        if self.cursor_line < self.scroll_offset {
-            self.scroll_offset = self.cursor_line;
+        self.scroll_to_cursor(self.settings.scroll_off, SIDE_SCROLL_OFF);
+    }
+
+    fn scroll_to_cursor(&mut self, margin: usize, side_margin: usize) {
+        // Long lines scroll sideways together, only as far as the cursor
+        // needs.
+        if self.word_wrap {
+            self.col_offset = 0;
+        } else {
+            let width = self.text_width().max(1);
+            let side_margin = side_margin.min((width - 1) / 2);
+            let x = self.cursor_display_col();
+            if x < self.col_offset + side_margin {
+                self.col_offset = x.saturating_sub(side_margin);
+            }
+            if x + side_margin >= self.col_offset + width {
+                self.col_offset = x + side_margin + 1 - width;
+            }
+        }
+
+        let view_height = self.view_height();
+        let margin = margin.min(view_height.saturating_sub(1) / 2);
+        if self.cursor_line < self.scroll_offset + margin {
+            self.scroll_offset = self.cursor_line.saturating_sub(margin);
+        }
+        if self.cursor_line + margin >= self.scroll_offset + view_height {
+            self.scroll_offset = (self.cursor_line + margin + 1).saturating_sub(view_height);
         }
-        if self.cursor_line >= self.scroll_offset + view_height {
-            self.scroll_offset = self.cursor_line.saturating_sub(view_height - 1);
+        if self.word_wrap {
+            // Lines above the cursor can take several rows each, so scroll
+            // until every row down to the cursor's, and the margin below it,
+            // fits.
+            let (row, _) = self.cursor_row();
+            let below = (self.row_count(self.cursor_line) - 1 - row)
+                + (self.cursor_line + 1..self.buffer.num_lines())
+                    .take(margin)
+                    .map(|line| self.row_count(line))
+                    .sum::<usize>();
+            let mut rows = row
+                + 1
+                + below.min(margin)
+                + (self.scroll_offset..self.cursor_line)
+                    .map(|line| self.row_count(line))
+                    .sum::<usize>();
+            while rows > view_height && self.scroll_offset < self.cursor_line {
+                rows -= self.row_count(self.scroll_offset);
+                self.scroll_offset += 1;
+            }
+            return;
         }
         let max_scroll = self.buffer.num_lines().saturating_sub(view_height);
         if self.scroll_offset > max_scroll {
@@ -268,17 +953,83 @@ impl Editor {
     }
 
     fn update_cursor_blink(&mut self) {
-        let elapsed = self.last_cursor_time.elapsed().as_millis();
-        if elapsed > 500 {
+        let Some(interval) = self.blink_interval() else {
+            self.cursor_blink_on = true;
+            return;
+        };
+        if self.last_cursor_time.elapsed() >= interval {
             self.cursor_blink_on = !self.cursor_blink_on;
             self.last_cursor_time = std::time::Instant::now();
         }
     }
 
+    /// How long the cursor stays on or off, or `None` for a solid cursor.
+    fn blink_interval(&self) -> Option<std::time::Duration> {
+        match (self.settings.cursor_style, self.settings.cursor_blink_ms) {
+            (CursorStyle::Solid, _) | (_, 0) => None,
+            (CursorStyle::Blink, ms) => Some(std::time::Duration::from_millis(ms)),
+        }
+    }
+
+    /// How long to wait for input before something on a timer is due: the
+    /// cursor blinking, a message or chord expiring, a recovery write, an
+    /// auto-save or a check for changes on disk. `None` when nothing is, so
+    /// the main loop can sleep until the next event.
+    fn poll_timeout(&self) -> Option<std::time::Duration> {
+        use std::time::{Duration, Instant};
+        let after = |since: Instant, wait: Duration| wait.saturating_sub(since.elapsed());
+        let secs = Duration::from_secs;
+        let unsaved = |b: &Buffer| b.is_modified && b.path.is_some();
+
+        let blink = self
+            .blink_interval()
+            .map(|interval| after(self.last_cursor_time, interval));
+        let message = self
+            .status_message
+            .as_ref()
+            .map(|_| after(self.message_time, secs(MESSAGE_TIMEOUT_SECS)));
+        let chord = self
+            .pending_chord
+            .map(|(_, started)| after(started, Duration::from_millis(CHORD_TIMEOUT_MS as u64 + 1)));
+        let recovery = (self.settings.recovery_files
+            && unsaved(&self.buffer)
+            && self.buffer.revision != self.recovery_revision)
+            .then(|| after(self.last_recovery_write, secs(RECOVERY_INTERVAL_SECS)));
+        let auto_save = (self.settings.auto_save
+            && (unsaved(&self.buffer) || self.buffers.iter().any(|s| unsaved(&s.buffer))))
+        .then(|| after(self.last_edit, secs(self.settings.auto_save_interval_secs)));
+        let disk_check = self
+            .buffer
+            .disk_mtime
+            .map(|_| after(self.last_disk_check, secs(DISK_CHECK_INTERVAL_SECS)));
+        let loading = (self.buffer.is_loading()
+            || self.buffers.iter().any(|s| s.buffer.is_loading()))
+        .then_some(Duration::ZERO);
+
+        [
+            blink, message, chord, recovery, auto_save, disk_check, loading,
+        ]
+        .into_iter()
+        .flatten()
+        .min()
+    }
+
     fn clamp_cursor(&mut self) {
         let num_lines = self.buffer.num_lines().saturating_sub(1);
         self.cursor_line = self.cursor_line.min(num_lines);
-        self.cursor_col = self.cursor_col.min(self.buffer.line_len(self.cursor_line));
+        self.cursor_col = self
+            .buffer
+            .grapheme_floor(self.cursor_line, self.cursor_col);
+    }
+
+    /// Terminal column of the cursor, counting wide characters as two and
+    /// tabs up to the next tab stop.
+    fn cursor_display_col(&self) -> usize {
+        let line = self.buffer.get_line(self.cursor_line);
+        display_width(
+            line.get(..self.cursor_col).unwrap_or(&line),
+            self.settings.tab_width,
+        )
     }
 
     fn get_indent(&self, line: usize) -> String {
@@ -301,25 +1052,42 @@ impl Editor {
             return;
         }
 
+        let revision = self.buffer.revision;
         let mode = std::mem::replace(&mut self.mode, EditorMode::Normal);
 
         match mode {
             EditorMode::Normal => {
-                self.handle_normal(key);
+                if let Some((prefix, _)) = self.pending_chord.take() {
+                    if !self.handle_chord(&prefix, key) {
+                        self.handle_normal(&prefix);
+                        self.handle_normal(key);
+                    }
+                } else if Self::is_chord_prefix(key) && self.can_edit() {
+                    self.pending_chord = Some((*key, std::time::Instant::now()));
+                } else {
+                    self.handle_normal(key);
+                }
             }
             EditorMode::Search {
                 query,
                 case_sensitive,
+                regex,
                 backward,
             } => {
-                let (new_query, new_case, new_backward, should_exit) =
-                    self.handle_search_owned(key, query, case_sensitive, backward);
+                let (new_query, new_case, new_regex, new_backward, should_exit) =
+                    self.handle_search_owned(key, query, case_sensitive, regex, backward);
+                if !new_query.is_empty()
+                    && let Ok(pattern) = Pattern::new(&new_query, new_case, new_regex)
+                {
+                    self.last_search = Some(pattern);
+                }
                 if should_exit {
                     self.mode = EditorMode::Normal;
                 } else {
                     self.mode = EditorMode::Search {
                         query: new_query,
                         case_sensitive: new_case,
+                        regex: new_regex,
                         backward: new_backward,
                     };
                 }
@@ -328,18 +1096,31 @@ impl Editor {
                 search,
                 replace,
                 case_sensitive,
+                regex,
                 all,
-                confirmed,
+                in_selection,
+                stage,
             } => {
                 let (
                     new_search,
                     new_replace,
                     new_case,
+                    new_regex,
                     new_all,
-                    new_confirmed,
+                    new_in_selection,
+                    new_stage,
                     action,
                     should_exit,
-                ) = self.handle_replace_owned(key, search, replace, case_sensitive, all, confirmed);
+                ) = self.handle_replace_owned(
+                    key,
+                    search,
+                    replace,
+                    case_sensitive,
+                    regex,
+                    all,
+                    in_selection,
+                    stage,
+                );
                 if let Some(act) = action {
                     self.pending_action = Some(act);
                 }
@@ -350,18 +1131,22 @@ impl Editor {
                         search: new_search,
                         replace: new_replace,
                         case_sensitive: new_case,
+                        regex: new_regex,
                         all: new_all,
-                        confirmed: new_confirmed,
+                        in_selection: new_in_selection,
+                        stage: new_stage,
                     };
                 }
             }
-            EditorMode::GoToLine => {
-                let (line_num, should_exit) = self.handle_goto_owned(key);
-                if should_exit {
-                    self.mode = EditorMode::Normal;
-                } else if let Some(num) = line_num {
+            EditorMode::GoToLine { input } => {
+                let (new_input, line_num, should_exit) = self.handle_goto_owned(key, input);
+                if let Some(num) = line_num {
                     self.goto_line(num);
+                }
+                if should_exit {
                     self.mode = EditorMode::Normal;
+                } else {
+                    self.mode = EditorMode::GoToLine { input: new_input };
                 }
             }
             EditorMode::Confirm {
@@ -384,9 +1169,7 @@ impl Editor {
                         input: "untitled.txt".into(),
                         history: Vec::new(),
                     };
-                } else if key.code == KeyCode::Enter {
-                    self.mode = EditorMode::Normal;
-                } else if key.code == KeyCode::Esc {
+                } else if key.code == KeyCode::Enter || key.code == KeyCode::Esc {
                     self.mode = EditorMode::Normal;
                 } else {
                     self.mode = EditorMode::Confirm {
@@ -407,6 +1190,9 @@ impl Editor {
                 if let Some(act) = action {
                     self.pending_action = Some(act);
                 }
+                if key.code == KeyCode::Esc {
+                    self.open_after_save = None;
+                }
                 if key.code != KeyCode::Enter && key.code != KeyCode::Esc {
                     self.mode = EditorMode::Input {
                         title: new_title,
@@ -417,245 +1203,1206 @@ impl Editor {
                     self.mode = EditorMode::Normal;
                 }
             }
-            EditorMode::Help => {
-                if key.code == KeyCode::Esc
-                    || (key.code == KeyCode::Char('h') && key.modifiers == KeyModifiers::CONTROL)
+            EditorMode::FileBrowser {
+                dir,
+                entries,
+                selected,
+            } => {
+                let (new_dir, new_entries, new_selected, action, should_exit) =
+                    self.handle_browser_owned(key, dir, entries, selected);
+                self.pending_action = action;
+                if !should_exit {
+                    self.mode = EditorMode::FileBrowser {
+                        dir: new_dir,
+                        entries: new_entries,
+                        selected: new_selected,
+                    };
+                }
+            }
+            EditorMode::CommandPalette { query, selected } => {
+                let (new_query, new_selected, command, should_exit) =
+                    self.handle_palette_owned(key, query, selected);
+                if !should_exit {
+                    self.mode = EditorMode::CommandPalette {
+                        query: new_query,
+                        selected: new_selected,
+                    };
+                } else if let Some(command) = command
+                    && let Err(e) = self.run_command(command, "")
                 {
-                    self.mode = EditorMode::Normal;
+                    self.set_message(e);
                 }
             }
+            EditorMode::Help => {
+                let max_scroll = self.help_max_scroll();
+                match key.code {
+                    KeyCode::Esc => {}
+                    KeyCode::Char('h') if key.modifiers == KeyModifiers::CONTROL => {}
+                    KeyCode::Up => {
+                        self.help_scroll = self.help_scroll.saturating_sub(1);
+                        self.mode = EditorMode::Help;
+                    }
+                    KeyCode::Down => {
+                        self.help_scroll = (self.help_scroll + 1).min(max_scroll);
+                        self.mode = EditorMode::Help;
+                    }
+                    _ => self.mode = EditorMode::Help,
+                }
+            }
+            // Any key closes the statistics.
+            EditorMode::Stats { .. } => {}
         }
 
         if let Some(action) = self.pending_action.take() {
             match action {
                 PendingAction::SaveAndQuit => {
-                    let _ = self.buffer.save();
-                    self.should_quit = true;
+                    if self.save() {
+                        self.close_buffer();
+                    } else {
+                        self.quit_after_save = matches!(self.mode, EditorMode::Input { .. });
+                    }
                 }
                 PendingAction::QuitWithoutSave => {
                     self.buffer.is_modified = false;
-                    self.should_quit = true;
+                    self.remove_recovery_file();
+                    self.close_buffer();
                 }
                 PendingAction::SaveAs(filename) => {
                     let path = std::path::PathBuf::from(filename);
-                    let _ = self.buffer.save_as(path);
-                    if self.quit_after_save {
-                        self.should_quit = true;
+                    let old_path = self.buffer.path.clone();
+                    self.prepare_save();
+                    let result = self.buffer.save_as(path);
+                    // A new extension may change the language.
+                    self.highlighter.set_language(&self.buffer.language);
+                    if let Err(e) = result {
+                        self.set_message(save_error(&e));
                         self.quit_after_save = false;
+                        self.open_after_save = None;
+                    } else {
+                        if let Some(old) = &old_path {
+                            recovery::remove(old);
+                        }
+                        self.remove_recovery_file();
+                        self.set_message(format!("Saved {}", self.buffer.file_name()));
+                        if self.quit_after_save {
+                            self.close_buffer();
+                            self.quit_after_save = false;
+                        } else if let Some(path) = self.open_after_save.take() {
+                            self.load_path(path);
+                        }
+                    }
+                }
+                PendingAction::ReplaceAll(pattern, replace) => {
+                    let count = self.replace_all(&pattern, &replace, 0);
+                    self.set_message(format!("Replaced {} occurrences", count));
+                }
+                PendingAction::RestoreRecovery => self.restore_recovery(),
+                PendingAction::OpenFile(path) => self.open_path(path),
+                PendingAction::SaveAndOpen => {
+                    if self.save() {
+                        if let Some(path) = self.open_after_save.take() {
+                            self.load_path(path);
+                        }
+                    } else if !matches!(self.mode, EditorMode::Input { .. }) {
+                        // Saving failed rather than asking for a file name.
+                        self.open_after_save = None;
                     }
                 }
-                PendingAction::ReplaceAll(search, replace) => {
-                    let _count = self.buffer.replace(&search, &replace);
-                    self.undo.clear();
+                PendingAction::Reload => self.reload(),
+                PendingAction::DiscardAndOpen => {
+                    self.remove_recovery_file();
+                    if let Some(path) = self.open_after_save.take() {
+                        self.load_path(path);
+                    }
                 }
+                PendingAction::DiscardRecovery => self.remove_recovery_file(),
             }
-        }
-    }
-
-    fn goto_line(&mut self, line_num: usize) {
-        let num_lines = self.buffer.num_lines();
-        if line_num > 0 && line_num <= num_lines {
-            self.cursor_line = line_num - 1;
-            self.cursor_col = 0;
+            // Pending actions may shrink the buffer underneath the cursor.
             self.clamp_cursor();
             self.update_scroll();
         }
+
+        if self.buffer.revision != revision {
+            self.last_edit = std::time::Instant::now();
+        }
     }
 
-    fn handle_normal(&mut self, k: &event::KeyEvent) {
-        self.cursor_blink_on = true;
-        self.last_cursor_time = std::time::Instant::now();
-
-        match (k.code, k.modifiers) {
-            (KeyCode::Char('h'), KeyModifiers::CONTROL) => {
-                self.generate_tip();
-                self.mode = EditorMode::Help;
-            }
-            (KeyCode::Char('q'), KeyModifiers::CONTROL) => {
-                if self.buffer.path.is_none() {
-                    self.quit_after_save = true;
-                    self.mode = EditorMode::Input {
-                        title: "Save As".into(),
-                        input: "untitled.txt".into(),
-                        history: Vec::new(),
-                    };
-                } else if self.buffer.is_modified {
-                    self.mode = EditorMode::Confirm {
-                        title: "Quit".into(),
-                        message: "Save changes?".into(),
-                        options: vec!["Yes".into(), "No".into(), "Cancel".into()],
-                        selected: 0,
-                    };
-                } else {
-                    self.should_quit = true;
-                }
+    /// Clicking places the cursor; dragging with the left button selects
+    /// from the click point, scrolling when the pointer leaves the text.
+    fn handle_mouse(&mut self, m: &MouseEvent) {
+        if self.mode != EditorMode::Normal {
+            return;
+        }
+        // Clicking in the other half of a split focuses it first.
+        if let MouseEventKind::Down(_) = m.kind
+            && self.split.is_some()
+        {
+            let bottom_top = 1 + self.tab_bar_height() + self.pane_heights().0;
+            if (m.row as usize >= bottom_top) != self.split_bottom {
+                self.switch_pane();
             }
-            (KeyCode::Char('s'), KeyModifiers::CONTROL) => {
-                if self.buffer.path.is_none() {
-                    self.mode = EditorMode::Input {
-                        title: "Save As".into(),
-                        input: "untitled.txt".into(),
-                        history: Vec::new(),
-                    };
-                } else {
-                    let _ = self.buffer.save();
-                }
+        }
+        match m.kind {
+            MouseEventKind::Down(MouseButton::Left) if m.modifiers.contains(KeyModifiers::ALT) => {
+                let (line, col) = self.text_position(m.column, m.row);
+                self.selection = None;
+                self.add_cursor(line, col);
             }
-            (KeyCode::Char('o'), KeyModifiers::CONTROL) => {
-                self.open_file();
+            MouseEventKind::Down(MouseButton::Left) => {
+                let (line, col) = self.text_position(m.column, m.row);
+                self.selection = None;
+                self.extra_cursors.clear();
+                self.cursor_line = line;
+                self.cursor_col = col;
+                self.clamp_cursor();
+                self.drag_anchor = Some((self.cursor_line, self.cursor_col));
             }
-            (KeyCode::Char('z'), KeyModifiers::CONTROL) => {
-                if self.undo.undo(&mut self.buffer) {
-                    let (line, col) = self.buffer.get_line_col(0);
-                    self.cursor_line = line;
-                    self.cursor_col = col;
+            MouseEventKind::Drag(MouseButton::Left) => {
+                let Some((anchor_line, anchor_col)) = self.drag_anchor else {
+                    return;
+                };
+                let (line, col) = self.text_position(m.column, m.row);
+                if self.selection.is_none() {
+                    self.selection = Some(Selection::new(
+                        anchor_line,
+                        anchor_col,
+                        SelectionKind::Linear,
+                    ));
                 }
+                self.cursor_line = line;
+                self.cursor_col = col;
                 self.clamp_cursor();
-                self.update_scroll();
+                // Dragging past the edge scrolls a line at a time.
+                self.scroll_to_cursor(0, 0);
             }
-            (KeyCode::Char('y'), KeyModifiers::CONTROL) => {
-                if self.undo.redo(&mut self.buffer) {
-                    let (line, col) = self.buffer.get_line_col(0);
-                    self.cursor_line = line;
-                    self.cursor_col = col;
+            MouseEventKind::Up(MouseButton::Left) => self.drag_anchor = None,
+            _ => {}
+        }
+        self.cursor_blink_on = true;
+        self.last_cursor_time = std::time::Instant::now();
+    }
+
+    /// Screen columns showing text, as the editor view draws them.
+    fn view_columns(&self) -> std::ops::Range<u16> {
+        text_columns(
+            self.screen_width.min(u16::MAX as usize) as u16,
+            self.buffer.num_lines(),
+            self.show_line_numbers,
+        )
+    }
+
+    /// Cells available for text beside the line numbers.
+    fn text_width(&self) -> usize {
+        self.view_columns().len()
+    }
+
+    /// Byte offsets where the screen rows of `text` start: several with word
+    /// wrap on, otherwise just the one.
+    fn wrap_starts(&self, text: &str) -> Vec<usize> {
+        if self.word_wrap {
+            wrap_rows(text, self.text_width(), self.settings.tab_width)
+        } else {
+            vec![0]
+        }
+    }
+
+    fn row_count(&self, line: usize) -> usize {
+        self.wrap_starts(&self.buffer.get_line(line)).len()
+    }
+
+    /// Screen row of the cursor within its line, and its screen column
+    /// within that row.
+    fn cursor_row(&self) -> (usize, usize) {
+        let line = self.buffer.get_line(self.cursor_line);
+        let starts = self.wrap_starts(&line);
+        let col = self.cursor_col.min(line.len());
+        let row = starts.partition_point(|&start| start <= col) - 1;
+        let x = display_width(&line[starts[row]..col], self.settings.tab_width);
+        (row, x)
+    }
+
+    /// Byte column at screen column `x` of row `row` of `text`. Past the end
+    /// of a wrapped row, that row's last character rather than the next row.
+    fn col_in_row(&self, text: &str, starts: &[usize], row: usize, x: usize) -> usize {
+        let start = starts[row];
+        let end = starts.get(row + 1).copied().unwrap_or(text.len());
+        let col = start + byte_col_at(&text[start..end], x, self.settings.tab_width);
+        if col == end && row + 1 < starts.len() {
+            text[start..end]
+                .grapheme_indices(true)
+                .next_back()
+                .map_or(start, |(i, _)| start + i)
+        } else {
+            col
+        }
+    }
+
+    /// Buffer position under a screen cell. Rows above or below the text
+    /// map to the line just outside the view, so dragging there scrolls.
+    fn text_position(&self, column: u16, row: u16) -> (usize, usize) {
+        // The title bar, tab strip, the top pane of a split and the editor's
+        // top border sit above the text.
+        let mut top = 2 + self.tab_bar_height();
+        if self.split_bottom {
+            top += self.pane_heights().0;
+        }
+        let height = self.view_height();
+        let row = row as usize;
+        let last_line = self.buffer.num_lines().saturating_sub(1);
+        let (mut line, mut y) = if row < top {
+            (self.scroll_offset.saturating_sub(1), 0)
+        } else {
+            (self.scroll_offset, (row - top).min(height))
+        };
+        if !self.word_wrap {
+            line += y;
+            y = 0;
+        }
+        line = line.min(last_line);
+
+        // With word wrap, walk down the rows of each line from the top.
+        let mut text = self.buffer.get_line(line);
+        let mut starts = self.wrap_starts(&text);
+        while y >= starts.len() && line < last_line {
+            y -= starts.len();
+            line += 1;
+            text = self.buffer.get_line(line);
+            starts = self.wrap_starts(&text);
+        }
+
+        let mut x = column.saturating_sub(self.view_columns().start) as usize;
+        if !self.word_wrap {
+            x += self.col_offset;
+        }
+        (
+            line,
+            self.col_in_row(&text, &starts, y.min(starts.len() - 1), x),
+        )
+    }
+
+    fn is_chord_prefix(k: &event::KeyEvent) -> bool {
+        k.code == KeyCode::Char('k') && k.modifiers == KeyModifiers::CONTROL
+    }
+
+    /// Keys that change the buffer when pressed in normal mode.
+    fn is_edit_key(k: &event::KeyEvent) -> bool {
+        match (k.code, k.modifiers) {
+            (
+                KeyCode::Enter
+                | KeyCode::Backspace
+                | KeyCode::Delete
+                | KeyCode::Tab
+                | KeyCode::BackTab,
+                _,
+            ) => true,
+            (KeyCode::Up | KeyCode::Down | KeyCode::Char('.'), KeyModifiers::ALT) => true,
+            (KeyCode::Char(c), m) if m.is_empty() || m == KeyModifiers::SHIFT => !c.is_control(),
+            (KeyCode::Char(c), m) if m.contains(KeyModifiers::CONTROL) => {
+                matches!(
+                    c.to_ascii_lowercase(),
+                    'k' | 'u' | 'd' | 'x' | 'v' | '/' | '7'
+                )
+            }
+            _ => false,
+        }
+    }
+
+    /// Runs the second half of a chord. Returns false if `k` doesn't complete
+    /// a known chord, in which case both keys are handled individually.
+    fn handle_chord(&mut self, prefix: &event::KeyEvent, k: &event::KeyEvent) -> bool {
+        if prefix.code != KeyCode::Char('k') || k.modifiers != KeyModifiers::CONTROL {
+            return false;
+        }
+        let comment = match k.code {
+            KeyCode::Char('c') => true,
+            KeyCode::Char('u') => false,
+            _ => return false,
+        };
+        self.comment_lines(comment);
+        self.last_action = Some(RepeatableAction::Comment(comment));
+        self.clamp_cursor();
+        self.update_scroll();
+        true
+    }
+
+    /// Shows `text` in the status bar for a few seconds.
+    fn set_message(&mut self, text: String) {
+        self.status_message = Some(text);
+        self.message_time = std::time::Instant::now();
+    }
+
+    fn expire_message(&mut self) {
+        if self.status_message.is_some()
+            && self.message_time.elapsed().as_secs() >= MESSAGE_TIMEOUT_SECS
+        {
+            self.status_message = None;
+        }
+    }
+
+    /// Falls back to the prefix key's own action once the chord times out.
+    fn expire_chord(&mut self) {
+        if let Some((prefix, started)) = self.pending_chord
+            && started.elapsed().as_millis() > CHORD_TIMEOUT_MS
+        {
+            self.pending_chord = None;
+            self.handle_normal(&prefix);
+        }
+    }
+
+    /// First and last line touched by the selection, or the cursor line.
+    fn selected_lines(&self) -> (usize, usize) {
+        match self.selection {
+            Some(sel) => (
+                sel.anchor_line.min(self.cursor_line),
+                sel.anchor_line.max(self.cursor_line),
+            ),
+            None => (self.cursor_line, self.cursor_line),
+        }
+    }
+
+    /// Whether every non-blank selected line already starts with the
+    /// language's comment prefix, so that toggling should uncomment them.
+    fn lines_commented(&self) -> bool {
+        let Some(prefix) = self.highlighter.get_comment_prefix() else {
+            return false;
+        };
+        let (top, bottom) = self.selected_lines();
+        let mut lines = (top..=bottom)
+            .map(|line| self.buffer.get_line(line))
+            .filter(|text| !text.trim().is_empty())
+            .peekable();
+        lines.peek().is_some() && lines.all(|text| text.trim_start().starts_with(prefix))
+    }
+
+    /// Adds or removes the language's line comment after the indentation of
+    /// every selected line, as a single undo step. Blank lines are skipped.
+    fn comment_lines(&mut self, comment: bool) {
+        let Some(prefix) = self.highlighter.get_comment_prefix() else {
+            return;
+        };
+        let open = format!("{} ", prefix);
+        let close = self
+            .highlighter
+            .get_comment_suffix()
+            .map(|s| format!(" {}", s))
+            .unwrap_or_default();
+
+        let (top, bottom) = self.selected_lines();
+        let mut ops = Vec::new();
+        for line in (top..=bottom).rev() {
+            let text = self.buffer.get_line(line);
+            let indent = self.get_indent(line).len();
+            let body = &text[indent..];
+            let line_start = self.buffer.get_cursor_pos(line, 0);
+            let pos = line_start + indent;
+
+            if comment {
+                if body.trim().is_empty() {
+                    continue;
+                }
+                if !close.is_empty() {
+                    let end = line_start + text.len();
+                    self.buffer.insert(end, &close);
+                    ops.push(EditOp::Insert {
+                        pos: end,
+                        text: close.clone(),
+                    });
+                }
+                self.buffer.insert(pos, &open);
+                ops.push(EditOp::Insert {
+                    pos,
+                    text: open.clone(),
+                });
+                if line == self.cursor_line && self.cursor_col >= indent {
+                    self.cursor_col += open.len();
+                }
+            } else if let Some(rest) = body.strip_prefix(prefix) {
+                if !close.is_empty()
+                    && let Some(stripped) = body.trim_end().strip_suffix(close.trim_start())
+                {
+                    let suffix_start = if stripped.ends_with(' ') {
+                        stripped.len() - 1
+                    } else {
+                        stripped.len()
+                    };
+                    let end = pos + suffix_start;
+                    let removed = body[suffix_start..].to_string();
+                    self.buffer.delete(end, removed.len());
+                    ops.push(EditOp::Delete {
+                        pos: end,
+                        text: removed,
+                    });
+                }
+                let len = prefix.len() + usize::from(rest.starts_with(' '));
+                let removed = body[..len].to_string();
+                self.buffer.delete(pos, len);
+                ops.push(EditOp::Delete { pos, text: removed });
+                if line == self.cursor_line && self.cursor_col > indent {
+                    self.cursor_col = self.cursor_col.saturating_sub(len).max(indent);
                 }
-                self.clamp_cursor();
-                self.update_scroll();
             }
-            (KeyCode::Char('b'), KeyModifiers::CONTROL) => {
-                self.show_line_numbers = !self.show_line_numbers;
+        }
+        if !ops.is_empty() {
+            self.undo.push(EditOp::Batch(ops));
+        }
+    }
+
+    /// Pretty-prints (or minifies) a JSON buffer as one undo step. Parse
+    /// errors are reported and leave the buffer untouched.
+    fn reformat_json(&mut self, minify: bool) {
+        if self.buffer.language != "json" {
+            self.set_message("Not a JSON file".into());
+            return;
+        }
+        let old_text = self.buffer.text.to_string();
+        let result = if minify {
+            syntax::json::minify(&old_text)
+        } else {
+            syntax::json::pretty(&old_text, self.settings.json_indent)
+        };
+        let new_text = match result {
+            Ok(text) => format!("{}\n", text),
+            Err(e) => {
+                // serde_json's message already carries the line and column.
+                self.set_message(format!("Invalid JSON: {}", e));
+                return;
             }
-            (KeyCode::Char('t'), KeyModifiers::CONTROL) => {
-                self.show_help = !self.show_help;
+        };
+        if new_text == old_text {
+            return;
+        }
+
+        self.buffer.delete(0, old_text.len());
+        self.buffer.insert(0, &new_text);
+        self.undo.push(EditOp::Replace {
+            pos: 0,
+            old_len: old_text.len(),
+            old_text,
+            new_text,
+        });
+        self.selection = None;
+        self.cursor_line = 0;
+        self.cursor_col = 0;
+        self.update_scroll();
+    }
+
+    /// Inserts the local date and time, formatted by `datetime_format`.
+    fn insert_datetime(&mut self) -> Result<(), String> {
+        use chrono::format::{Item, StrftimeItems};
+        let format = &self.settings.datetime_format;
+        if StrftimeItems::new(format).any(|item| item == Item::Error) {
+            return Err(format!("invalid datetime_format '{}'", format));
+        }
+        let text = chrono::Local::now().format(format).to_string();
+        let pos = self
+            .buffer
+            .get_cursor_pos(self.cursor_line, self.cursor_col);
+        self.buffer.insert(pos, &text);
+        (self.cursor_line, self.cursor_col) = self.buffer.get_line_col(pos + text.len());
+        self.undo.push(EditOp::Insert { pos, text });
+        self.selection = None;
+        self.update_scroll();
+        Ok(())
+    }
+
+    fn goto_line(&mut self, line_num: usize) {
+        self.push_jump();
+        // Lines past the end go to the last line.
+        let line_num = line_num.clamp(1, self.buffer.num_lines().max(1));
+        self.cursor_line = line_num - 1;
+        self.cursor_col = 0;
+        self.clamp_cursor();
+        self.update_scroll();
+    }
+
+    /// Moves to a 1-based line and optional 1-based character column, as
+    /// given by `file:line:col` on the command line.
+    fn goto_position(&mut self, line: usize, col: Option<usize>) {
+        self.goto_line(line);
+        if let Some(col) = col {
+            let text = self.buffer.get_line(self.cursor_line);
+            self.cursor_col = text
+                .char_indices()
+                .nth(col.saturating_sub(1))
+                .map_or(text.len(), |(i, _)| i);
+            self.clamp_cursor();
+            self.update_scroll();
+        }
+    }
+
+    fn run_startup_command(&mut self) {
+        let Some(command) = self.settings.startup_command.clone() else {
+            return;
+        };
+        let command = command.trim();
+        if command.is_empty() {
+            return;
+        }
+        let (name, args) = command.split_once(' ').unwrap_or((command, ""));
+        if let Err(e) = self.run_command(name, args.trim()) {
+            self.set_message(format!("startup_command: {}", e));
+        }
+    }
+
+    fn run_command(&mut self, name: &str, args: &str) -> Result<(), String> {
+        if !self.can_edit() && EDITING_COMMANDS.contains(&name) {
+            return Err(self.read_only_message());
+        }
+        match name {
+            "open" if args.is_empty() => self.open_file(),
+            "open" => self.open_path(std::path::PathBuf::from(args)),
+            "reopen_closed" => self.reopen_closed_buffer(),
+            "repeat_last" => self.repeat_last_action(),
+            "close" => self.close(),
+            "reload" => self.request_reload(),
+            "new_tab" => self.open_tab(Buffer::new()),
+            "next_tab" => self.cycle_buffer(true),
+            "prev_tab" => self.cycle_buffer(false),
+            "undo" => self.undo_edit(),
+            "redo" => self.redo_edit(),
+            "save" => {
+                self.save();
             }
-            (KeyCode::Char('w'), KeyModifiers::CONTROL) => {
-                self.word_wrap = !self.word_wrap;
+            "help" => {
+                self.open_help();
             }
-            (KeyCode::Char('T'), KeyModifiers::CONTROL | KeyModifiers::SHIFT) => {
-                let ts = Theme::all_themes();
-                let c = ts.iter().position(|x| *x == self.theme.name).unwrap_or(0);
-                self.theme = Theme::get_theme(&ts[(c + 1) % ts.len()]);
+            "stats" => self.open_stats(),
+            "command_palette" => {
+                self.mode = EditorMode::CommandPalette {
+                    query: String::new(),
+                    selected: 0,
+                };
             }
-            (KeyCode::Char('f'), KeyModifiers::CONTROL) => {
+            "search" => {
+                self.push_jump();
                 self.mode = EditorMode::Search {
-                    query: String::new(),
+                    query: args.to_string(),
                     case_sensitive: false,
+                    regex: false,
                     backward: false,
                 };
             }
-            (KeyCode::Char('\\'), KeyModifiers::CONTROL) => {
+            "replace" => {
+                self.replace_selection = self
+                    .selection
+                    .take()
+                    .filter(|sel| sel.kind == SelectionKind::Linear)
+                    .map(|sel| self.clipboard_range(Some(sel)))
+                    .filter(|(start, end)| start < end);
                 self.mode = EditorMode::Replace {
-                    search: String::new(),
+                    search: args.to_string(),
                     replace: String::new(),
                     case_sensitive: false,
+                    regex: false,
                     all: false,
-                    confirmed: false,
+                    in_selection: self.replace_selection.is_some(),
+                    stage: ReplaceStage::Search,
                 };
             }
-            (KeyCode::Char('g'), KeyModifiers::CONTROL) => {
-                self.mode = EditorMode::GoToLine;
-            }
-            (KeyCode::Up, _) => {
-                if self.cursor_line > 0 {
-                    self.cursor_line -= 1;
-                    let indent = self.get_indent(self.cursor_line);
-                    if self.cursor_col < indent.len() && !indent.is_empty() {
-                        self.cursor_col = indent.len();
-                    }
+            "goto_bracket" => self.goto_bracket(),
+            "toggle_split" => self.toggle_split(),
+            "toggle_diff" => self.toggle_diff(DiffBase::Disk)?,
+            "toggle_git_diff" => self.toggle_diff(DiffBase::GitHead)?,
+            "switch_pane" => self.switch_pane(),
+            "jump_back" => self.jump(true),
+            "jump_forward" => self.jump(false),
+            "toggle_mark" => self.buffer.toggle_mark(self.cursor_line),
+            "next_mark" => self.goto_mark(true),
+            "prev_mark" => self.goto_mark(false),
+            "goto_line" => {
+                if args.is_empty() {
+                    self.mode = EditorMode::GoToLine {
+                        input: String::new(),
+                    };
+                } else {
+                    let line = args
+                        .parse::<usize>()
+                        .map_err(|_| format!("invalid line number '{}'", args))?;
+                    self.goto_line(line);
                 }
             }
-            (KeyCode::Down, _) => {
-                if self.cursor_line < self.buffer.num_lines() - 1 {
-                    self.cursor_line += 1;
-                    let indent = self.get_indent(self.cursor_line);
-                    if self.cursor_col < indent.len() && !indent.is_empty() {
-                        self.cursor_col = indent.len();
-                    }
+            "theme" => {
+                if args.is_empty() {
+                    self.cycle_theme();
+                } else {
+                    self.set_theme(Theme::load(args)?);
                 }
             }
-            (KeyCode::Left, _) => {
-                if self.cursor_col > 0 {
-                    self.cursor_col -= 1;
-                } else if self.cursor_line > 0 {
-                    self.cursor_line -= 1;
-                    self.cursor_col = self.buffer.line_len(self.cursor_line);
-                }
+            "format_json" => self.reformat_json(false),
+            "minify_json" => self.reformat_json(true),
+            "insert_datetime" => self.insert_datetime()?,
+            "toggle_line_numbers" => self.show_line_numbers = !self.show_line_numbers,
+            "toggle_word_wrap" => self.word_wrap = !self.word_wrap,
+            "toggle_help_bar" => self.show_help = !self.show_help,
+            "toggle_overwrite" => self.overwrite = !self.overwrite,
+            "toggle_read_only" => self.read_only = !self.read_only,
+            _ => return Err(format!("unknown command '{}'", name)),
+        }
+        Ok(())
+    }
+
+    /// Closes the current tab, asking to save unsaved changes first.
+    fn close(&mut self) {
+        if self.buffer.path.is_none() && !self.to_stdout {
+            self.quit_after_save = true;
+            self.mode = EditorMode::Input {
+                title: "Save As".into(),
+                input: "untitled.txt".into(),
+                history: Vec::new(),
+            };
+        } else if self.buffer.is_modified {
+            self.mode = EditorMode::Confirm {
+                title: "Quit".into(),
+                message: "Save changes?".into(),
+                options: vec!["Yes".into(), "No".into(), "Cancel".into()],
+                selected: 0,
+            };
+        } else {
+            self.close_buffer();
+        }
+    }
+
+    /// Reloads the file from disk, asking first if that discards changes.
+    fn request_reload(&mut self) {
+        if self.buffer.path.is_none() {
+            self.set_message("No file to reload".into());
+        } else if self.buffer.is_modified {
+            self.mode = EditorMode::Confirm {
+                title: RELOAD_TITLE.into(),
+                message: "Discard unsaved changes and reload from disk?".into(),
+                options: vec!["Reload".into(), "Cancel".into()],
+                selected: 0,
+            };
+        } else {
+            self.reload();
+        }
+    }
+
+    fn undo_edit(&mut self) {
+        if self.undo.undo(&mut self.buffer) {
+            let (line, col) = self.buffer.get_line_col(0);
+            self.cursor_line = line;
+            self.cursor_col = col;
+        }
+        self.clamp_cursor();
+        self.update_scroll();
+    }
+
+    fn redo_edit(&mut self) {
+        if self.undo.redo(&mut self.buffer) {
+            let (line, col) = self.buffer.get_line_col(0);
+            self.cursor_line = line;
+            self.cursor_col = col;
+        }
+        self.clamp_cursor();
+        self.update_scroll();
+    }
+
+    /// Saves the buffer, prompting for a name if it has none. Returns true
+    /// only if the file was actually written.
+    fn save(&mut self) -> bool {
+        if self.buffer.path.is_some() || self.to_stdout {
+            self.prepare_save();
+        }
+        if self.buffer.path.is_none() && self.to_stdout {
+            self.stdout_text = Some(self.buffer.disk_content());
+            self.buffer.is_modified = false;
+            self.set_message("Saved for stdout".into());
+            return true;
+        }
+        match self.buffer.save() {
+            Ok(SaveStatus::Saved) => {
+                self.remove_recovery_file();
+                self.set_message(format!("Saved {}", self.buffer.file_name()));
+                true
             }
-            (KeyCode::Right, _) => {
-                let line_len = self.buffer.line_len(self.cursor_line);
-                if self.cursor_col < line_len {
-                    self.cursor_col += 1;
-                } else if self.cursor_line < self.buffer.num_lines() - 1 {
-                    self.cursor_line += 1;
-                    self.cursor_col = 0;
-                }
+            Ok(SaveStatus::NoPath) => {
+                self.mode = EditorMode::Input {
+                    title: "Save As".into(),
+                    input: "untitled.txt".into(),
+                    history: Vec::new(),
+                };
+                false
             }
-            (KeyCode::Home, _) => {
-                let indent = self.get_indent(self.cursor_line);
-                if self.cursor_col > indent.len() {
-                    self.cursor_col = indent.len();
-                } else {
-                    self.cursor_col = 0;
-                }
+            Err(e) => {
+                self.set_message(save_error(&e));
+                false
             }
-            (KeyCode::End, _) => {
-                self.cursor_col = self.buffer.line_len(self.cursor_line);
+        }
+    }
+
+    /// Only the cursor's own line can get shorter under it when trailing
+    /// whitespace is trimmed, so the cursor just needs clamping.
+    fn prepare_save(&mut self) {
+        if !self.can_edit() {
+            return;
+        }
+        prepare_save(&self.settings, &mut self.buffer, &mut self.undo);
+        self.clamp_cursor();
+    }
+
+    /// Whether the buffer may be changed; false in read-only mode and while
+    /// only part of the file is loaded.
+    fn can_edit(&self) -> bool {
+        !self.read_only && !self.buffer.is_partial()
+    }
+
+    /// Why `can_edit` refuses.
+    fn read_only_message(&self) -> String {
+        if self.buffer.is_partial() {
+            "File is not fully loaded".into()
+        } else {
+            "Buffer is read-only".into()
+        }
+    }
+
+    fn cycle_theme(&mut self) {
+        let ts = Theme::all_themes();
+        let c = ts.iter().position(|x| *x == self.theme.name).unwrap_or(0);
+        self.set_theme(Theme::get_theme(&ts[(c + 1) % ts.len()]));
+    }
+
+    /// Switches to `theme` and saves it as the theme to start with.
+    fn set_theme(&mut self, theme: Theme) {
+        self.settings.theme = theme.name.clone();
+        self.set_message(match Settings::save_theme(&theme.name) {
+            Ok(()) => format!("Theme: {}", theme.name),
+            Err(e) => format!("Theme: {} (not saved: {})", theme.name, e),
+        });
+        self.theme = theme;
+    }
+
+    fn handle_multi_cursor(&mut self, k: &event::KeyEvent) -> bool {
+        if k.modifiers == KeyModifiers::CONTROL | KeyModifiers::ALT
+            && matches!(k.code, KeyCode::Up | KeyCode::Down)
+        {
+            let line = if k.code == KeyCode::Up {
+                self.cursor_line.checked_sub(1)
+            } else {
+                Some(self.cursor_line + 1).filter(|&l| l < self.buffer.num_lines())
+            };
+            if let Some(line) = line {
+                let col = byte_col_at(
+                    &self.buffer.get_line(line),
+                    self.cursor_display_col(),
+                    self.settings.tab_width,
+                );
+                self.selection = None;
+                self.add_cursor(line, col);
             }
-            (KeyCode::PageUp, _) => {
-                self.cursor_line = self.cursor_line.saturating_sub(self.screen_height - 2);
+            return true;
+        }
+        if self.extra_cursors.is_empty() {
+            return false;
+        }
+        match (k.code, k.modifiers) {
+            (KeyCode::Char(c), m)
+                if (m.is_empty() || m == KeyModifiers::SHIFT) && !c.is_control() =>
+            {
+                self.edit_cursors(Some(c), false);
+                true
             }
-            (KeyCode::PageDown, _) => {
-                let max_line = self.buffer.num_lines() - 1;
-                self.cursor_line = (self.cursor_line + self.screen_height - 2).min(max_line);
+            (KeyCode::Backspace, KeyModifiers::NONE) => {
+                self.edit_cursors(None, true);
+                true
             }
-            (KeyCode::Enter, _) => {
-                let indent = self.get_indent(self.cursor_line);
-                self.buffer
-                    .insert_newline(self.cursor_line, self.cursor_col);
-                self.undo.push(EditOp::Insert {
-                    pos: self.buffer.get_cursor_pos(self.cursor_line, 0),
-                    text: "\n".to_string(),
-                });
-                self.cursor_line += 1;
-                self.cursor_col = 0;
-                if self.settings.auto_indent && !indent.is_empty() {
-                    self.buffer
-                        .insert(self.buffer.get_cursor_pos(self.cursor_line, 0), &indent);
-                    self.cursor_col = indent.len();
-                }
+            (KeyCode::Delete, KeyModifiers::NONE) => {
+                self.edit_cursors(None, false);
+                true
             }
-            (KeyCode::Backspace, _) => {
-                if self.cursor_col > 0 {
-                    let pos = self
-                        .buffer
-                        .get_cursor_pos(self.cursor_line, self.cursor_col - 1);
-                    let ch = self
-                        .buffer
-                        .get_line(self.cursor_line)
-                        .chars()
-                        .nth(self.cursor_col - 1)
-                        .unwrap_or(' ');
-                    self.buffer.delete(pos, 1);
-                    self.undo.push(EditOp::Delete {
-                        pos,
-                        text: ch.to_string(),
-                    });
-                    self.cursor_col -= 1;
-                } else if self.cursor_line > 0 {
-                    let prev_line_len = self.buffer.line_len(self.cursor_line - 1);
-                    self.buffer.delete(
-                        self.buffer
-                            .get_cursor_pos(self.cursor_line, 0)
-                            .saturating_sub(1),
-                        1,
-                    );
-                    self.cursor_line -= 1;
-                    self.cursor_col = prev_line_len;
-                }
+            (KeyCode::Esc, _) => {
+                self.extra_cursors.clear();
+                true
             }
-            (KeyCode::Tab, _) => {
-                if self.settings.use_spaces {
-                    let spaces = " ".repeat(self.settings.tab_size);
+            _ => {
+                self.extra_cursors.clear();
+                false
+            }
+        }
+    }
+
+    /// Leaves a cursor where the main one is and moves the main one to
+    /// `(line, col)`.
+    fn add_cursor(&mut self, line: usize, col: usize) {
+        let current = (self.cursor_line, self.cursor_col);
+        self.cursor_line = line;
+        self.cursor_col = col;
+        self.clamp_cursor();
+        let new = (self.cursor_line, self.cursor_col);
+        self.extra_cursors.retain(|&c| c != new);
+        if current != new && !self.extra_cursors.contains(&current) {
+            self.extra_cursors.push(current);
+        }
+    }
+
+    /// Inserts `insert` at every cursor, or deletes the character before
+    /// (`backward`) or after each one within its line, as one undo step.
+    fn edit_cursors(&mut self, insert: Option<char>, backward: bool) {
+        let main = self
+            .buffer
+            .get_cursor_pos(self.cursor_line, self.cursor_col);
+        let mut cursors: Vec<usize> = self
+            .extra_cursors
+            .iter()
+            .map(|&(line, col)| self.buffer.get_cursor_pos(line, col))
+            .chain([main])
+            .collect();
+        cursors.sort_unstable();
+        cursors.dedup();
+
+        // The byte range each cursor removes, in original offsets.
+        let ranges: Vec<(usize, usize)> = cursors
+            .iter()
+            .map(|&pos| {
+                let (line, col) = self.buffer.get_line_col(pos);
+                match insert {
+                    Some(_) => (pos, pos),
+                    None if backward => (pos - (col - self.buffer.prev_grapheme(line, col)), pos),
+                    None => (pos, pos + self.buffer.next_grapheme(line, col) - col),
+                }
+            })
+            .collect();
+        let text = insert.map(String::from).unwrap_or_default();
+
+        // Last cursor first, so the offsets of the ones before it stay valid.
+        let mut ops = Vec::new();
+        for &(start, end) in ranges.iter().rev() {
+            if end > start {
+                let removed = self.buffer.text.get_range(start, end);
+                self.buffer.delete(start, end - start);
+                ops.push(EditOp::Delete {
+                    pos: start,
+                    text: removed,
+                });
+            }
+            if !text.is_empty() {
+                self.buffer.insert(start, &text);
+                ops.push(EditOp::Insert {
+                    pos: start,
+                    text: text.clone(),
+                });
+            }
+        }
+        if ops.is_empty() {
+            return;
+        }
+        self.undo.push(EditOp::Batch(ops));
+
+        // Each cursor shifts by the edits before it, plus its own insertion.
+        let mut removed = 0;
+        let mut moved = Vec::new();
+        for (i, &(start, end)) in ranges.iter().enumerate() {
+            let pos = start - removed + i * text.len() + text.len();
+            removed += end - start;
+            moved.push((cursors[i] == main, self.buffer.get_line_col(pos)));
+        }
+        self.extra_cursors.clear();
+        for (is_main, (line, col)) in moved {
+            if is_main {
+                (self.cursor_line, self.cursor_col) = (line, col);
+            } else {
+                self.extra_cursors.push((line, col));
+            }
+        }
+    }
+
+    fn handle_block_selection(&mut self, k: &event::KeyEvent) -> bool {
+        if k.modifiers == KeyModifiers::ALT | KeyModifiers::SHIFT
+            && matches!(
+                k.code,
+                KeyCode::Up | KeyCode::Down | KeyCode::Left | KeyCode::Right
+            )
+        {
+            let sel = self
+                .selection
+                .filter(|s| s.kind == SelectionKind::Block)
+                .unwrap_or(Selection::new(
+                    self.cursor_line,
+                    self.cursor_col,
+                    SelectionKind::Block,
+                ));
+            self.selection = Some(sel);
+            match k.code {
+                KeyCode::Up => {
+                    if self.cursor_line > 0 {
+                        self.cursor_line -= 1;
+                    } else if self.settings.cursor_wrap {
+                        self.cursor_line = self.buffer.num_lines().saturating_sub(1);
+                    }
+                }
+                KeyCode::Down => {
+                    if self.cursor_line + 1 < self.buffer.num_lines() {
+                        self.cursor_line += 1;
+                    } else if self.settings.cursor_wrap {
+                        self.cursor_line = 0;
+                    }
+                }
+                KeyCode::Left => {
+                    self.cursor_col = self.buffer.prev_grapheme(self.cursor_line, self.cursor_col);
+                }
+                KeyCode::Right => {
+                    self.cursor_col = self.buffer.next_grapheme(self.cursor_line, self.cursor_col);
+                }
+                _ => {}
+            }
+            return true;
+        }
+
+        let Some(sel) = self.selection.filter(|s| s.kind == SelectionKind::Block) else {
+            return false;
+        };
+        match (k.code, k.modifiers) {
+            (KeyCode::Char(c), m)
+                if (m.is_empty() || m == KeyModifiers::SHIFT) && !c.is_control() =>
+            {
+                self.edit_block(sel, Some(c), false);
+                true
+            }
+            (KeyCode::Backspace, _) => {
+                self.edit_block(sel, None, true);
+                true
+            }
+            (KeyCode::Delete, _) => {
+                self.edit_block(sel, None, false);
+                true
+            }
+            _ => {
+                self.selection = None;
+                false
+            }
+        }
+    }
+
+    /// Replaces the block's column range on every row with `insert`, or deletes
+    /// it. A zero-width block deletes one grapheme towards `backward` instead.
+    /// The range is taken in display columns, so rows with tabs or wide
+    /// characters before the block are cut at the same place on screen.
+    fn edit_block(&mut self, sel: Selection, insert: Option<char>, backward: bool) {
+        let (top, bottom, ..) = sel.block_bounds(self.cursor_line, self.cursor_col);
+        let (left, right) = block_columns(
+            &self.buffer,
+            &sel,
+            self.cursor_line,
+            self.cursor_col,
+            self.settings.tab_width,
+        );
+        let inserted = insert.map_or(0, |c| c.len_utf8());
+
+        // Bottom-up so the offsets of rows not yet edited stay valid.
+        let mut ops = Vec::new();
+        let (mut anchor_col, mut cursor_col) = (sel.anchor_col, self.cursor_col);
+        for line in (top..=bottom).rev() {
+            let text = self.buffer.get_line(line);
+            let Some(cols) = block_byte_range(&text, left, right, self.settings.tab_width) else {
+                continue;
+            };
+            let (mut start, mut end) = (cols.start, cols.end);
+            if start == end && insert.is_none() {
+                if backward {
+                    start = self.buffer.prev_grapheme(line, start);
+                } else {
+                    end = self.buffer.next_grapheme(line, end);
+                }
+            }
+            let line_start = self.buffer.get_cursor_pos(line, 0);
+            if end > start {
+                let pos = line_start + start;
+                let removed = self.buffer.text.get_range(pos, line_start + end);
+                self.buffer.delete(pos, removed.len());
+                ops.push(EditOp::Delete { pos, text: removed });
+            }
+            if let Some(c) = insert {
+                let text = c.to_string();
+                self.buffer.insert(line_start + start, &text);
+                ops.push(EditOp::Insert {
+                    pos: line_start + start,
+                    text,
+                });
+            }
+            if line == sel.anchor_line {
+                anchor_col = start + inserted;
+            }
+            if line == self.cursor_line {
+                cursor_col = start + inserted;
+            }
+        }
+        if ops.is_empty() {
+            return;
+        }
+        self.undo.push(EditOp::Batch(ops));
+
+        self.selection = Some(Selection::new(
+            sel.anchor_line,
+            anchor_col,
+            SelectionKind::Block,
+        ));
+        self.cursor_col = cursor_col;
+    }
+
+    fn handle_normal(&mut self, k: &event::KeyEvent) {
+        self.cursor_blink_on = true;
+        self.last_cursor_time = std::time::Instant::now();
+
+        if !self.can_edit()
+            && Self::is_edit_key(k)
+            && !self.keymap.contains_key(&KeyCombo::from_event(k))
+        {
+            self.set_message(self.read_only_message());
+            return;
+        }
+
+        let continuing_insert = std::mem::take(&mut self.insert_run);
+        if !continuing_insert {
+            self.auto_closed.clear();
+        }
+
+        if self.handle_multi_cursor(k) || self.handle_block_selection(k) {
+            self.clamp_cursor();
+            self.update_scroll();
+            return;
+        }
+
+        if k.modifiers == KeyModifiers::SHIFT
+            && matches!(
+                k.code,
+                KeyCode::Up
+                    | KeyCode::Down
+                    | KeyCode::Left
+                    | KeyCode::Right
+                    | KeyCode::Home
+                    | KeyCode::End
+                    | KeyCode::PageUp
+                    | KeyCode::PageDown
+            )
+        {
+            if self.selection.is_none() {
+                self.selection = Some(Selection::new(
+                    self.cursor_line,
+                    self.cursor_col,
+                    SelectionKind::Linear,
+                ));
+            }
+            self.move_cursor(k.code);
+            self.clamp_cursor();
+            self.update_scroll();
+            return;
+        }
+        let selection = self.selection.take();
+
+        if let Some(&action) = self.keymap.get(&KeyCombo::from_event(k)) {
+            // Replacing can be limited to the selection, and statistics
+            // cover it.
+            if action == "replace" || action == "stats" {
+                self.selection = selection;
+            }
+            if let Err(e) = self.run_command(action, "") {
+                self.set_message(e);
+            }
+            self.clamp_cursor();
+            self.update_scroll();
+            return;
+        }
+
+        match (k.code, k.modifiers) {
+            (KeyCode::F(3), KeyModifiers::NONE) => {
+                self.repeat_search(false);
+            }
+            (KeyCode::F(3), KeyModifiers::SHIFT) => {
+                self.repeat_search(true);
+            }
+            (KeyCode::Up, KeyModifiers::ALT) => {
+                self.move_line(true);
+            }
+            (KeyCode::Down, KeyModifiers::ALT) => {
+                self.move_line(false);
+            }
+            (KeyCode::Left, KeyModifiers::CONTROL) => {
+                self.move_word(true);
+            }
+            (KeyCode::Right, KeyModifiers::CONTROL) => {
+                self.move_word(false);
+            }
+            (KeyCode::Backspace, KeyModifiers::CONTROL) => {
+                self.delete_word(true);
+            }
+            (KeyCode::Delete, KeyModifiers::CONTROL) => {
+                self.delete_word(false);
+            }
+            (KeyCode::Home, KeyModifiers::CONTROL) => {
+                self.cursor_line = 0;
+                self.cursor_col = 0;
+            }
+            (KeyCode::End, KeyModifiers::CONTROL) => {
+                self.cursor_line = self.buffer.num_lines().saturating_sub(1);
+                self.cursor_col = self.buffer.line_len(self.cursor_line);
+            }
+            (
+                KeyCode::Up
+                | KeyCode::Down
+                | KeyCode::Left
+                | KeyCode::Right
+                | KeyCode::Home
+                | KeyCode::End
+                | KeyCode::PageUp
+                | KeyCode::PageDown,
+                _,
+            ) => {
+                self.move_cursor(k.code);
+            }
+            (KeyCode::Enter, _) => {
+                self.insert_newline();
+            }
+            (KeyCode::Backspace, _) => {
+                let line = self.buffer.get_line(self.cursor_line);
+                let in_indent = self.cursor_col > 0
+                    && line.len() >= self.cursor_col
+                    && line.as_bytes()[..self.cursor_col]
+                        .iter()
+                        .all(|&b| b == b' ');
+                if in_indent
+                    && self.settings.smart_backspace
+                    && let Indent::Spaces(size) = self.indent()
+                    && size > 0
+                {
+                    // Delete back to the previous indentation stop.
+                    let count = (self.cursor_col - 1) % size + 1;
+                    let pos = self
+                        .buffer
+                        .get_cursor_pos(self.cursor_line, self.cursor_col - count);
+                    self.buffer.delete(pos, count);
+                    self.undo.push(EditOp::Delete {
+                        pos,
+                        text: " ".repeat(count),
+                    });
+                    self.cursor_col -= count;
+                } else if self.cursor_col > 0 {
+                    let col = self.buffer.prev_grapheme(self.cursor_line, self.cursor_col);
+                    let pos = self.buffer.get_cursor_pos(self.cursor_line, col);
+                    let text = self.buffer.text.get_range(pos, pos + self.cursor_col - col);
+                    self.buffer.delete(pos, text.len());
+                    self.undo.push(EditOp::Delete { pos, text });
+                    self.cursor_col = col;
+                } else if self.cursor_line > 0 {
+                    let prev_line_len = self.buffer.line_len(self.cursor_line - 1);
+                    let pos = self.buffer.get_cursor_pos(self.cursor_line, 0) - 1;
+                    self.buffer.delete(pos, 1);
+                    self.undo.push(EditOp::Delete {
+                        pos,
+                        text: "\n".into(),
+                    });
+                    self.cursor_line -= 1;
+                    self.cursor_col = prev_line_len;
+                }
+            }
+            (KeyCode::Tab, _) if selection.is_some_and(|s| s.anchor_line != self.cursor_line) => {
+                self.shift_lines(selection, false);
+            }
+            (KeyCode::BackTab, _) => {
+                self.shift_lines(selection, true);
+            }
+            (KeyCode::Tab, _) => {
+                if let Indent::Spaces(size) = self.indent() {
+                    let spaces = " ".repeat(size);
                     let pos = self
                         .buffer
                         .get_cursor_pos(self.cursor_line, self.cursor_col);
@@ -678,30 +2425,14 @@ impl Editor {
                 }
             }
             (KeyCode::Char('k'), KeyModifiers::CONTROL) => {
-                if self.buffer.num_lines() > 1 {
-                    let start_pos = self.buffer.get_cursor_pos(self.cursor_line, 0);
-                    let line_len = self.buffer.line_len(self.cursor_line);
-                    let deleted = self.buffer.get_line(self.cursor_line);
-                    self.buffer.delete(start_pos, line_len + 1);
-                    if self.cursor_line >= self.buffer.num_lines() - 1 {
-                        self.cursor_line = self.buffer.num_lines() - 1;
-                    }
-                    self.cursor_col = self.cursor_col.min(self.buffer.line_len(self.cursor_line));
-                    self.undo.push(EditOp::Delete {
-                        pos: start_pos,
-                        text: deleted,
-                    });
-                }
+                self.delete_line();
+                self.last_action = Some(RepeatableAction::DeleteLine);
             }
             (KeyCode::Char('u'), KeyModifiers::CONTROL) => {
                 let start_pos = self.buffer.get_cursor_pos(self.cursor_line, 0);
                 if self.cursor_col > 0 {
-                    let deleted: String = self
-                        .buffer
-                        .get_line(self.cursor_line)
-                        .chars()
-                        .take(self.cursor_col)
-                        .collect();
+                    let line = self.buffer.get_line(self.cursor_line);
+                    let deleted = line.get(..self.cursor_col).unwrap_or(&line).to_string();
                     self.buffer.delete(start_pos, deleted.len());
                     self.undo.push(EditOp::Delete {
                         pos: start_pos,
@@ -710,28 +2441,90 @@ impl Editor {
                     self.cursor_col = 0;
                 }
             }
+            (KeyCode::Char('D' | 'd'), m) if m == KeyModifiers::CONTROL | KeyModifiers::SHIFT => {
+                self.duplicate_line();
+            }
+            // Terminals without the keyboard enhancement protocol report
+            // Ctrl+/ as Ctrl+7.
+            (KeyCode::Char('/') | KeyCode::Char('7'), KeyModifiers::CONTROL) => {
+                self.selection = selection;
+                let comment = !self.lines_commented();
+                self.comment_lines(comment);
+                self.last_action = Some(RepeatableAction::Comment(comment));
+            }
             (KeyCode::Char('d'), KeyModifiers::CONTROL) => {
-                let pos = self
-                    .buffer
-                    .get_cursor_pos(self.cursor_line, self.cursor_col);
-                if pos < self.buffer.total_len() - 1 {
-                    let ch = self.buffer.text.get_range(pos, pos + 1);
-                    self.buffer.delete(pos, 1);
-                    self.undo.push(EditOp::Delete { pos, text: ch });
+                self.delete_char_forward();
+                self.last_action = Some(RepeatableAction::DeleteChar);
+            }
+            (KeyCode::Char('.'), KeyModifiers::ALT) => {
+                self.repeat_last_action();
+            }
+            (KeyCode::Char('c'), KeyModifiers::CONTROL) => {
+                let (start, end) = self.clipboard_range(selection);
+                let text = self.buffer.text.get_range(start, end);
+                self.clipboard.set(text);
+                self.selection = selection;
+            }
+            (KeyCode::Char('x'), KeyModifiers::CONTROL) => {
+                let (start, end) = self.clipboard_range(selection);
+                let text = self.buffer.text.get_range(start, end);
+                if !text.is_empty() {
+                    self.buffer.delete(start, text.len());
+                    self.undo.push(EditOp::Delete {
+                        pos: start,
+                        text: text.clone(),
+                    });
+                    self.clipboard.set(text);
+                    let (line, col) = self.buffer.get_line_col(start);
+                    self.cursor_line = line;
+                    self.cursor_col = col;
                 }
             }
-            (KeyCode::Char(c), m) if m.is_empty() || m == KeyModifiers::SHIFT => {
-                if !c.is_control() {
-                    let pos = self
-                        .buffer
-                        .get_cursor_pos(self.cursor_line, self.cursor_col);
-                    self.buffer.insert(pos, &c.to_string());
-                    self.undo.push(EditOp::Insert {
+            (KeyCode::Char('v'), KeyModifiers::CONTROL) => {
+                let text = self.clipboard.get();
+                if !text.is_empty() {
+                    // The pasted text replaces the selection, as one undo step.
+                    let (pos, end) = match selection {
+                        Some(_) => self.clipboard_range(selection),
+                        None => {
+                            let pos = self
+                                .buffer
+                                .get_cursor_pos(self.cursor_line, self.cursor_col);
+                            (pos, pos)
+                        }
+                    };
+                    let removed = self.buffer.text.get_range(pos, end);
+                    if !removed.is_empty() {
+                        self.buffer.delete(pos, removed.len());
+                    }
+                    self.buffer.insert(pos, &text);
+                    let insert = EditOp::Insert {
                         pos,
-                        text: c.to_string(),
+                        text: text.clone(),
+                    };
+                    self.undo.push(if removed.is_empty() {
+                        insert
+                    } else {
+                        EditOp::Batch(vec![EditOp::Delete { pos, text: removed }, insert])
                     });
-                    self.cursor_col += 1;
+                    let (line, col) = self.buffer.get_line_col(pos + text.len());
+                    self.cursor_line = line;
+                    self.cursor_col = col;
+                }
+            }
+            (KeyCode::Char(c), m)
+                if (m.is_empty() || m == KeyModifiers::SHIFT) && !c.is_control() =>
+            {
+                if self.overwrite {
+                    self.overwrite_char(c);
+                } else {
+                    self.type_char(c);
                 }
+                match &mut self.last_action {
+                    Some(RepeatableAction::InsertText(run)) if continuing_insert => run.push(c),
+                    _ => self.last_action = Some(RepeatableAction::InsertText(c.to_string())),
+                }
+                self.insert_run = true;
             }
             _ => {}
         }
@@ -739,498 +2532,3766 @@ impl Editor {
         self.update_scroll();
     }
 
-    fn handle_search_owned(
-        &mut self,
-        k: &event::KeyEvent,
-        mut query: String,
-        mut case_sensitive: bool,
-        mut backward: bool,
-    ) -> (String, bool, bool, bool) {
-        self.cursor_blink_on = true;
-        self.last_cursor_time = std::time::Instant::now();
+    /// Byte range copied or cut by the clipboard commands: the selection, or
+    /// the whole current line including its newline.
+    fn clipboard_range(&self, selection: Option<Selection>) -> (usize, usize) {
+        match selection {
+            Some(sel) => {
+                let ((sl, sc), (el, ec)) = sel.linear_bounds(self.cursor_line, self.cursor_col);
+                (
+                    self.buffer.get_cursor_pos(sl, sc),
+                    self.buffer.get_cursor_pos(el, ec),
+                )
+            }
+            None => (
+                self.buffer.get_cursor_pos(self.cursor_line, 0),
+                self.buffer.get_cursor_pos(self.cursor_line + 1, 0),
+            ),
+        }
+    }
 
-        let mut should_exit = false;
-        match k.code {
-            KeyCode::Esc => {
-                should_exit = true;
+    /// Moves the cursor one screen row up or down, which is within the line
+    /// when it wraps, keeping it in the same screen column. Returns false
+    /// on the first or last row of the buffer.
+    fn move_vertically(&mut self, up: bool) -> bool {
+        let (row, x) = self.cursor_row();
+        let row_count = self.row_count(self.cursor_line);
+        let (line, row) = match (up, row) {
+            (true, 0) if self.cursor_line > 0 => (self.cursor_line - 1, usize::MAX),
+            (true, 0) => return false,
+            (true, _) => (self.cursor_line, row - 1),
+            (false, _) if row + 1 < row_count => (self.cursor_line, row + 1),
+            (false, _) if self.cursor_line + 1 < self.buffer.num_lines() => {
+                (self.cursor_line + 1, 0)
             }
-            KeyCode::Enter => {
-                if !query.is_empty() {
-                    if let Some((line, col)) =
-                        self.buffer.find(&query, self.cursor_line, self.cursor_col)
-                    {
-                        self.cursor_line = line;
-                        self.cursor_col = col;
-                        self.clamp_cursor();
-                        self.update_scroll();
+            (false, _) => return false,
+        };
+        let text = self.buffer.get_line(line);
+        let starts = self.wrap_starts(&text);
+        self.cursor_line = line;
+        self.cursor_col = self.col_in_row(&text, &starts, row.min(starts.len() - 1), x);
+        true
+    }
+
+    fn move_cursor(&mut self, code: KeyCode) {
+        match code {
+            KeyCode::Up => {
+                let line = self.cursor_line;
+                if self.move_vertically(true) {
+                    if self.cursor_line == line {
+                        return;
+                    }
+                    let indent = self.get_indent(self.cursor_line);
+                    if self.cursor_col < indent.len() && !indent.is_empty() {
+                        self.cursor_col = indent.len();
                     }
+                } else if self.settings.cursor_wrap {
+                    self.cursor_line = self.buffer.num_lines().saturating_sub(1);
                 }
-                should_exit = true;
-            }
-            KeyCode::Backspace => {
-                query.pop();
             }
-            KeyCode::Char('c') if k.modifiers == KeyModifiers::CONTROL => {
-                case_sensitive = !case_sensitive;
+            KeyCode::Down => {
+                let line = self.cursor_line;
+                if self.move_vertically(false) {
+                    if self.cursor_line == line {
+                        return;
+                    }
+                    let indent = self.get_indent(self.cursor_line);
+                    if self.cursor_col < indent.len() && !indent.is_empty() {
+                        self.cursor_col = indent.len();
+                    }
+                } else if self.settings.cursor_wrap {
+                    self.cursor_line = 0;
+                }
             }
-            KeyCode::Char('r') if k.modifiers == KeyModifiers::CONTROL => {
-                backward = !backward;
+            KeyCode::Left => {
+                if self.cursor_col > 0 {
+                    self.cursor_col = self.buffer.prev_grapheme(self.cursor_line, self.cursor_col);
+                } else if self.cursor_line > 0 {
+                    self.cursor_line -= 1;
+                    self.cursor_col = self.buffer.line_len(self.cursor_line);
+                } else if self.settings.cursor_wrap {
+                    self.cursor_line = self.buffer.num_lines().saturating_sub(1);
+                    self.cursor_col = self.buffer.line_len(self.cursor_line);
+                }
             }
-            KeyCode::Char(c) if k.modifiers.is_empty() || k.modifiers == KeyModifiers::SHIFT => {
-                if !c.is_control() {
-                    query.push(c);
-                    if !query.is_empty() {
-                        if let Some((line, col)) =
-                            self.buffer.find(&query, self.cursor_line, self.cursor_col)
-                        {
-                            self.cursor_line = line;
-                            self.cursor_col = col;
-                            self.clamp_cursor();
-                            self.update_scroll();
-                        }
-                    }
+            KeyCode::Right => {
+                let line_len = self.buffer.line_len(self.cursor_line);
+                if self.cursor_col < line_len {
+                    self.cursor_col = self.buffer.next_grapheme(self.cursor_line, self.cursor_col);
+                } else if self.cursor_line + 1 < self.buffer.num_lines() {
+                    self.cursor_line += 1;
+                    self.cursor_col = 0;
+                } else if self.settings.cursor_wrap {
+                    self.cursor_line = 0;
+                    self.cursor_col = 0;
                 }
             }
+            KeyCode::Home => {
+                // Smart home: the first non-blank character, then column 0.
+                let indent = self.buffer.first_non_blank(self.cursor_line);
+                self.cursor_col = if self.cursor_col == indent { 0 } else { indent };
+            }
+            KeyCode::End => {
+                self.cursor_col = self.buffer.line_len(self.cursor_line);
+            }
+            KeyCode::PageUp => {
+                let page = self.screen_height.saturating_sub(2);
+                self.cursor_line = self.cursor_line.saturating_sub(page);
+            }
+            KeyCode::PageDown => {
+                let page = self.screen_height.saturating_sub(2);
+                let max_line = self.buffer.num_lines().saturating_sub(1);
+                self.cursor_line = (self.cursor_line + page).min(max_line);
+            }
             _ => {}
         }
-        (query, case_sensitive, backward, should_exit)
     }
 
-    fn handle_replace_owned(
-        &mut self,
-        k: &event::KeyEvent,
-        mut search: String,
-        mut replace: String,
-        case_sensitive: bool,
-        all: bool,
-        confirmed: bool,
-    ) -> (
-        String,
-        String,
-        bool,
-        bool,
-        bool,
-        Option<PendingAction>,
-        bool,
-    ) {
-        self.cursor_blink_on = true;
-        self.last_cursor_time = std::time::Instant::now();
+    /// Inserts a typed character. With `auto_close_brackets`, an opening
+    /// bracket or quote also inserts its closer, and typing that closer next
+    /// steps over it instead of doubling it.
+    fn type_char(&mut self, c: char) {
+        let line = self.buffer.get_line(self.cursor_line);
+        let next = line.get(self.cursor_col..).and_then(|s| s.chars().next());
+        if self.auto_closed.last() == Some(&c) && next == Some(c) {
+            self.auto_closed.pop();
+            self.cursor_col += c.len_utf8();
+            return;
+        }
 
-        let mut action = None;
-        let mut should_exit = false;
-        let mut new_confirmed = confirmed;
+        let prev = line
+            .get(..self.cursor_col)
+            .and_then(|s| s.chars().next_back());
+        let closer = match c {
+            '(' => Some(')'),
+            '[' => Some(']'),
+            '{' => Some('}'),
+            '"' | '\'' => Some(c),
+            _ => None,
+        }
+        .filter(|_| self.settings.auto_close_brackets)
+        .filter(|_| next.is_none_or(|n| n.is_whitespace() || ")]}".contains(n)))
+        // Don't pair the apostrophe in "don't".
+        .filter(|&closer| closer != c || !prev.is_some_and(char::is_alphanumeric));
 
-        match k.code {
-            KeyCode::Esc => {
-                should_exit = true;
+        match closer {
+            Some(closer) => {
+                self.insert_text(&format!("{}{}", c, closer));
+                self.cursor_col -= closer.len_utf8();
+                self.auto_closed.push(closer);
             }
-            KeyCode::Enter => {
-                if confirmed {
-                    if all {
-                        action = Some(PendingAction::ReplaceAll(search.clone(), replace.clone()));
-                    } else {
-                        let _count = self.buffer.replace(&search, &replace);
-                        self.undo.clear();
-                    }
-                    should_exit = true;
-                } else {
-                    new_confirmed = true;
+            None if self.settings.auto_indent
+                && ")]}".contains(c)
+                && self.cursor_col > 0
+                && line[..self.cursor_col]
+                    .chars()
+                    .all(|ch| ch == ' ' || ch == '\t') =>
+            {
+                self.insert_closer(c);
+            }
+            None => self.insert_text(&c.to_string()),
+        }
+    }
+
+    /// Replaces the character under the cursor with `c` as one edit. At the
+    /// end of a line there is nothing to replace, so `c` is inserted.
+    fn overwrite_char(&mut self, c: char) {
+        let end = self.buffer.next_grapheme(self.cursor_line, self.cursor_col);
+        if end == self.cursor_col {
+            self.insert_text(&c.to_string());
+            return;
+        }
+        let pos = self
+            .buffer
+            .get_cursor_pos(self.cursor_line, self.cursor_col);
+        let old_text = self.buffer.get_line(self.cursor_line)[self.cursor_col..end].to_string();
+        let new_text = c.to_string();
+        self.buffer.delete(pos, old_text.len());
+        self.buffer.insert(pos, &new_text);
+        self.undo.push(EditOp::Replace {
+            pos,
+            old_len: old_text.len(),
+            old_text,
+            new_text,
+        });
+        self.cursor_col += c.len_utf8();
+    }
+
+    /// Breaks the line at the cursor as one undo step. With `auto_indent`
+    /// the new line keeps the current indentation, one level deeper after an
+    /// opening bracket, and a closing bracket right after the cursor moves
+    /// to a line of its own.
+    fn insert_newline(&mut self) {
+        let mut text = "\n".to_string();
+        let mut closing_line = String::new();
+        if self.settings.auto_indent {
+            let line = self.buffer.get_line(self.cursor_line);
+            let indent = self.get_indent(self.cursor_line);
+            text.push_str(&indent);
+            let close = match line[..self.cursor_col].trim_end().chars().next_back() {
+                Some('(') => Some(')'),
+                Some('[') => Some(']'),
+                Some('{') => Some('}'),
+                _ => None,
+            };
+            if let Some(close) = close {
+                text.push_str(&self.indent_unit());
+                if line[self.cursor_col..].trim_start().starts_with(close) {
+                    closing_line = format!("\n{}", indent);
                 }
             }
-            KeyCode::Tab => {
-                if search.is_empty() {
-                    search = self.buffer.get_line(self.cursor_line);
+        }
+        let pos = self
+            .buffer
+            .get_cursor_pos(self.cursor_line, self.cursor_col);
+        let inserted = format!("{}{}", text, closing_line);
+        self.buffer.insert(pos, &inserted);
+        self.undo.push(EditOp::Insert {
+            pos,
+            text: inserted,
+        });
+        self.cursor_line += 1;
+        self.cursor_col = text.len() - 1;
+    }
+
+    /// Types a closing bracket into a line's indentation, lining the line up
+    /// with the one holding the matching opener. Typing and reindenting undo
+    /// together.
+    fn insert_closer(&mut self, c: char) {
+        let start = self.buffer.get_cursor_pos(self.cursor_line, 0);
+        let old_text = self.buffer.get_line(self.cursor_line)[..self.cursor_col].to_string();
+        let pos = start + old_text.len();
+        // The matching opener can only be looked up with the closer in place.
+        self.buffer.insert(pos, &c.to_string());
+        let indent = match self.buffer.match_bracket(pos) {
+            Some(open) => self.get_indent(self.buffer.get_line_col(open).0),
+            None => old_text.clone(),
+        };
+        self.buffer.delete(pos, c.len_utf8());
+
+        let new_text = format!("{}{}", indent, c);
+        self.buffer.delete(start, old_text.len());
+        self.buffer.insert(start, &new_text);
+        self.cursor_col = new_text.len();
+        self.undo.push(EditOp::Replace {
+            pos: start,
+            old_len: old_text.len(),
+            old_text,
+            new_text,
+        });
+    }
+
+    /// Indentation Tab inserts: the file's own when `detect_indent` found
+    /// it, otherwise `use_spaces` and `indent_size`.
+    fn indent(&self) -> Indent {
+        match self.buffer.indent {
+            Some(indent) if self.settings.detect_indent => indent,
+            _ if self.settings.use_spaces => Indent::Spaces(self.settings.indent_size),
+            _ => Indent::Tabs,
+        }
+    }
+
+    /// One level of indentation as inserted by Tab.
+    fn indent_unit(&self) -> String {
+        match self.indent() {
+            Indent::Spaces(size) => " ".repeat(size),
+            Indent::Tabs => "\t".to_string(),
+        }
+    }
+
+    fn insert_text(&mut self, text: &str) {
+        let pos = self
+            .buffer
+            .get_cursor_pos(self.cursor_line, self.cursor_col);
+        self.buffer.insert(pos, text);
+        self.undo.push(EditOp::Insert {
+            pos,
+            text: text.to_string(),
+        });
+        self.cursor_col += text.len();
+    }
+
+    fn delete_line(&mut self) {
+        if self.buffer.num_lines() <= 1 {
+            return;
+        }
+        let start_pos = self.buffer.get_cursor_pos(self.cursor_line, 0);
+        let end_pos = self.buffer.get_cursor_pos(self.cursor_line + 1, 0);
+        let deleted = self.buffer.text.get_range(start_pos, end_pos);
+        self.buffer.delete(start_pos, deleted.len());
+        let last_line = self.buffer.num_lines().saturating_sub(1);
+        self.cursor_line = self.cursor_line.min(last_line);
+        self.cursor_col = self.cursor_col.min(self.buffer.line_len(self.cursor_line));
+        self.undo.push(EditOp::Delete {
+            pos: start_pos,
+            text: deleted,
+        });
+    }
+
+    /// Inserts a copy of the current line below it and moves onto the copy.
+    fn duplicate_line(&mut self) {
+        let start = self.buffer.get_cursor_pos(self.cursor_line, 0);
+        let end = self.buffer.get_cursor_pos(self.cursor_line + 1, 0);
+        let line = self.buffer.text.get_range(start, end);
+        // The last line may lack a newline to put the copy after.
+        let text = if line.ends_with('\n') {
+            line
+        } else {
+            format!("\n{}", line)
+        };
+        self.buffer.insert(end, &text);
+        self.undo.push(EditOp::Insert { pos: end, text });
+        self.cursor_line += 1;
+    }
+
+    /// Swaps the current line with the one above or below, keeping the cursor
+    /// on the moved line.
+    fn move_line(&mut self, up: bool) {
+        let first = if up {
+            if self.cursor_line == 0 {
+                return;
+            }
+            self.cursor_line - 1
+        } else {
+            if self.cursor_line + 1 >= self.buffer.num_lines() {
+                return;
+            }
+            self.cursor_line
+        };
+        let start = self.buffer.get_cursor_pos(first, 0);
+        let end = self.buffer.get_cursor_pos(first + 2, 0);
+        let old_text = self.buffer.text.get_range(start, end);
+        let upper = self.buffer.get_line(first);
+        let lower = self.buffer.get_line(first + 1);
+        let mut new_text = format!("{}\n{}", lower, upper);
+        if old_text.ends_with('\n') {
+            new_text.push('\n');
+        }
+
+        self.buffer.delete(start, old_text.len());
+        self.buffer.insert(start, &new_text);
+        self.undo.push(EditOp::Batch(vec![
+            EditOp::Delete {
+                pos: start,
+                text: old_text,
+            },
+            EditOp::Insert {
+                pos: start,
+                text: new_text,
+            },
+        ]));
+        if up {
+            self.cursor_line -= 1;
+        } else {
+            self.cursor_line += 1;
+        }
+    }
+
+    /// Indents (or dedents) the lines touched by `selection`, or the cursor
+    /// line without one, by one level as a single undo step. A selection
+    /// ending at column 0 leaves that last line alone, and stays on the
+    /// same lines afterwards.
+    fn shift_lines(&mut self, selection: Option<Selection>, dedent: bool) {
+        let (first, last) = match selection {
+            Some(sel) => {
+                let ((sl, _), (el, ec)) = sel.linear_bounds(self.cursor_line, self.cursor_col);
+                (sl, if ec == 0 && el > sl { el - 1 } else { el })
+            }
+            None => (self.cursor_line, self.cursor_line),
+        };
+        let unit = self.indent_unit();
+
+        let mut ops = Vec::new();
+        let mut deltas = Vec::new();
+        for line in first..=last {
+            let text = self.buffer.get_line(line);
+            let pos = self.buffer.get_cursor_pos(line, 0);
+            if dedent {
+                let len = if text.starts_with('\t') {
+                    1
                 } else {
-                    replace = "".to_string();
+                    let size = match self.indent() {
+                        Indent::Spaces(size) => size,
+                        Indent::Tabs => self.settings.indent_size,
+                    };
+                    text.bytes().take(size).take_while(|&b| b == b' ').count()
+                };
+                if len > 0 {
+                    self.buffer.delete(pos, len);
+                    ops.push(EditOp::Delete {
+                        pos,
+                        text: text[..len].to_string(),
+                    });
+                }
+                deltas.push(-(len as isize));
+            } else if !text.is_empty() {
+                self.buffer.insert(pos, &unit);
+                ops.push(EditOp::Insert {
+                    pos,
+                    text: unit.clone(),
+                });
+                deltas.push(unit.len() as isize);
+            } else {
+                deltas.push(0);
+            }
+        }
+        if ops.is_empty() {
+            self.selection = selection;
+            return;
+        }
+        self.undo.push(EditOp::Batch(ops));
+
+        // Columns move with their text; column 0 stays put so a selection
+        // from the start of a line still covers its indentation.
+        let adjust =
+            |line: usize, col: usize| match line.checked_sub(first).and_then(|i| deltas.get(i)) {
+                Some(&delta) if delta < 0 => col.saturating_sub(delta.unsigned_abs()),
+                Some(&delta) if col > 0 => col + delta as usize,
+                _ => col,
+            };
+        self.cursor_col = adjust(self.cursor_line, self.cursor_col);
+        self.selection = selection.map(|sel| Selection {
+            anchor_col: adjust(sel.anchor_line, sel.anchor_col),
+            ..sel
+        });
+    }
+
+    fn delete_char_forward(&mut self) {
+        let pos = self
+            .buffer
+            .get_cursor_pos(self.cursor_line, self.cursor_col);
+        // At the end of a line this joins the next one.
+        let next = self.buffer.next_grapheme(self.cursor_line, self.cursor_col);
+        let len = (next - self.cursor_col).max(1);
+        if pos + len < self.buffer.total_len() {
+            let text = self.buffer.text.get_range(pos, pos + len);
+            self.buffer.delete(pos, len);
+            self.undo.push(EditOp::Delete { pos, text });
+        }
+    }
+
+    /// Deletes up to the previous (`backward`) or next word boundary as one
+    /// undo step.
+    fn delete_word(&mut self, backward: bool) {
+        let pos = self
+            .buffer
+            .get_cursor_pos(self.cursor_line, self.cursor_col);
+        let (start, end) = if backward {
+            (self.buffer.prev_word_boundary(pos), pos)
+        } else {
+            // Keep the buffer's final newline.
+            let end = self.buffer.next_word_boundary(pos);
+            (pos, end.min(self.buffer.total_len().saturating_sub(1)))
+        };
+        if start >= end {
+            return;
+        }
+        let text = self.buffer.text.get_range(start, end);
+        self.buffer.delete(start, text.len());
+        self.undo.push(EditOp::Delete { pos: start, text });
+        let (line, col) = self.buffer.get_line_col(start);
+        self.cursor_line = line;
+        self.cursor_col = col;
+    }
+
+    fn move_word(&mut self, backward: bool) {
+        let pos = self
+            .buffer
+            .get_cursor_pos(self.cursor_line, self.cursor_col);
+        let target = if backward {
+            self.buffer.prev_word_boundary(pos)
+        } else {
+            self.buffer.next_word_boundary(pos)
+        };
+        let (line, col) = self.buffer.get_line_col(target);
+        self.cursor_line = line;
+        self.cursor_col = col;
+    }
+
+    /// Re-applies the last repeatable edit at the current cursor.
+    fn repeat_last_action(&mut self) {
+        let Some(action) = self.last_action.clone() else {
+            self.set_message("Nothing to repeat".into());
+            return;
+        };
+        match action {
+            RepeatableAction::InsertText(text) => self.insert_text(&text),
+            RepeatableAction::DeleteLine => self.delete_line(),
+            RepeatableAction::DeleteChar => self.delete_char_forward(),
+            RepeatableAction::Comment(comment) => self.comment_lines(comment),
+        }
+    }
+
+    fn handle_search_owned(
+        &mut self,
+        k: &event::KeyEvent,
+        mut query: String,
+        mut case_sensitive: bool,
+        mut regex: bool,
+        mut backward: bool,
+    ) -> (String, bool, bool, bool, bool) {
+        self.cursor_blink_on = true;
+        self.last_cursor_time = std::time::Instant::now();
+
+        let mut should_exit = false;
+        match k.code {
+            KeyCode::Esc => {
+                should_exit = true;
+            }
+            KeyCode::Enter => {
+                if query.is_empty() {
+                    should_exit = true;
+                } else if let Some(pattern) = self.compile_pattern(&query, case_sensitive, regex) {
+                    self.find_next(&pattern, backward, true);
                 }
             }
             KeyCode::Backspace => {
-                if replace.is_empty() && !search.is_empty() {
-                    search.pop();
-                } else {
-                    replace.pop();
+                query.pop();
+            }
+            KeyCode::Char('c') if k.modifiers == KeyModifiers::CONTROL => {
+                case_sensitive = !case_sensitive;
+            }
+            KeyCode::Char('e') if k.modifiers == KeyModifiers::CONTROL => {
+                regex = !regex;
+            }
+            KeyCode::Char('r') if k.modifiers == KeyModifiers::CONTROL => {
+                backward = !backward;
+            }
+            KeyCode::Char('n') if k.modifiers == KeyModifiers::CONTROL && !query.is_empty() => {
+                if let Some(pattern) = self.compile_pattern(&query, case_sensitive, regex) {
+                    let count = self.buffer.find_all(&pattern).len();
+                    let noun = if count == 1 { "match" } else { "matches" };
+                    self.set_message(format!("{} {}", count, noun));
                 }
             }
-            KeyCode::Char('a') if k.modifiers == KeyModifiers::CONTROL => {
-                return (
-                    search,
-                    replace,
-                    case_sensitive,
-                    true,
-                    confirmed,
-                    action,
-                    should_exit,
-                );
+            KeyCode::Char(c)
+                if (k.modifiers.is_empty() || k.modifiers == KeyModifiers::SHIFT)
+                    && !c.is_control() =>
+            {
+                query.push(c);
+                // Half-typed regexes are often invalid, so don't complain yet.
+                if let Ok(pattern) = Pattern::new(&query, case_sensitive, regex) {
+                    self.find_next(&pattern, backward, false);
+                }
+            }
+            _ => {}
+        }
+        (query, case_sensitive, regex, backward, should_exit)
+    }
+
+    /// Builds a search pattern, reporting an invalid regex in the status bar.
+    fn compile_pattern(
+        &mut self,
+        query: &str,
+        case_sensitive: bool,
+        regex: bool,
+    ) -> Option<Pattern> {
+        match Pattern::new(query, case_sensitive, regex) {
+            Ok(pattern) => Some(pattern),
+            Err(e) => {
+                // Syntax errors span several lines; the last one names the problem.
+                let e = e.to_string();
+                self.set_message(format!(
+                    "Invalid regex: {}",
+                    e.lines()
+                        .last()
+                        .unwrap_or_default()
+                        .trim_start_matches("error: ")
+                ));
+                None
+            }
+        }
+    }
+
+    /// Moves the cursor to the next match of `pattern` in the given direction.
+    /// With `skip_current`, a match under the cursor is passed over.
+    fn find_next(&mut self, pattern: &Pattern, backward: bool, skip_current: bool) -> bool {
+        let found = if backward {
+            let col = self.cursor_col + usize::from(!skip_current);
+            self.buffer.rfind_opts(pattern, self.cursor_line, col)
+        } else {
+            let col = self.cursor_col + usize::from(skip_current);
+            self.buffer.find_opts(pattern, self.cursor_line, col)
+        };
+        let Some((line, col)) = found else {
+            return false;
+        };
+        self.cursor_line = line;
+        self.cursor_col = col;
+        self.clamp_cursor();
+        self.update_scroll();
+        true
+    }
+
+    /// Jumps to the next or previous match of the last search and reports
+    /// its position among all matches.
+    fn repeat_search(&mut self, backward: bool) {
+        let Some(pattern) = self.last_search.clone() else {
+            self.set_message("No previous search".into());
+            return;
+        };
+        let start = self
+            .buffer
+            .get_cursor_pos(self.cursor_line, self.cursor_col);
+        if !self.find_next(&pattern, backward, true) {
+            self.set_message(format!("'{}' not found", pattern.as_str()));
+            return;
+        }
+        self.jumps.push(start);
+        let matches = self.buffer.find_all(&pattern);
+        let pos = self
+            .buffer
+            .get_cursor_pos(self.cursor_line, self.cursor_col);
+        if let Some(index) = matches.iter().position(|&m| m == pos) {
+            self.set_message(format!("{} of {}", index + 1, matches.len()));
+        }
+    }
+
+    /// Replaces every match of `pattern` from byte `from` onward, as a single
+    /// undo step. Returns the number of replacements.
+    fn replace_all(&mut self, pattern: &Pattern, replace: &str, from: usize) -> usize {
+        let cursor = self
+            .buffer
+            .get_cursor_pos(self.cursor_line, self.cursor_col);
+        let edits = self
+            .buffer
+            .replace(pattern, replace, from, self.replace_selection);
+        let count = edits.len();
+        if !edits.is_empty() {
+            // Keep the cursor on the same text: shift it by the edits before
+            // it, or move it to the start of a match it was inside.
+            let mut new_cursor = cursor;
+            for (pos, old_text, new_text) in &edits {
+                if pos + old_text.len() <= cursor {
+                    new_cursor = new_cursor + new_text.len() - old_text.len();
+                } else if *pos < cursor {
+                    new_cursor = new_cursor + pos - cursor;
+                }
+            }
+            let (line, col) = self.buffer.get_line_col(new_cursor);
+            self.cursor_line = line;
+            self.cursor_col = col;
+
+            self.undo.push(EditOp::Batch(
+                edits
+                    .into_iter()
+                    .map(|(pos, old_text, new_text)| EditOp::Replace {
+                        pos,
+                        old_len: old_text.len(),
+                        old_text,
+                        new_text,
+                    })
+                    .collect(),
+            ));
+        }
+        self.selection = None;
+        count
+    }
+
+    /// Moves the cursor to the first match at or after byte `from`, without
+    /// wrapping. Returns false if there is none.
+    fn goto_match_from(&mut self, pattern: &Pattern, from: usize) -> bool {
+        let Some((start, _)) = self
+            .buffer
+            .matches_from(pattern, from)
+            .find(|&(_, end)| self.replace_selection.is_none_or(|(_, e)| end <= e))
+        else {
+            return false;
+        };
+        let (line, col) = self.buffer.get_line_col(start);
+        self.cursor_line = line;
+        self.cursor_col = col;
+        self.update_scroll();
+        true
+    }
+
+    /// Replaces the match under the cursor and moves on to the next one.
+    /// Returns false once there are no more matches.
+    fn replace_current_match(&mut self, pattern: &Pattern, replace: &str) -> bool {
+        let pos = self
+            .buffer
+            .get_cursor_pos(self.cursor_line, self.cursor_col);
+        let Some((start, end, new_text)) = pattern
+            .replacements(&self.buffer.text.to_string(), replace)
+            .into_iter()
+            .find(|&(start, _, _)| start >= pos)
+            .filter(|&(start, _, _)| start == pos)
+        else {
+            return self.goto_match_from(pattern, pos);
+        };
+        let old_text = self.buffer.text.get_range(start, end);
+        self.buffer.delete(start, old_text.len());
+        self.buffer.insert(start, &new_text);
+        let next = start + new_text.len();
+        if let Some((_, scope_end)) = &mut self.replace_selection {
+            *scope_end = *scope_end + new_text.len() - old_text.len();
+        }
+        self.undo.push(EditOp::Replace {
+            pos: start,
+            old_len: old_text.len(),
+            old_text,
+            new_text,
+        });
+        self.goto_match_from(pattern, next)
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn handle_replace_owned(
+        &mut self,
+        k: &event::KeyEvent,
+        mut search: String,
+        mut replace: String,
+        mut case_sensitive: bool,
+        mut regex: bool,
+        mut all: bool,
+        mut in_selection: bool,
+        mut stage: ReplaceStage,
+    ) -> (
+        String,
+        String,
+        bool,
+        bool,
+        bool,
+        bool,
+        ReplaceStage,
+        Option<PendingAction>,
+        bool,
+    ) {
+        self.cursor_blink_on = true;
+        self.last_cursor_time = std::time::Instant::now();
+
+        let mut action = None;
+        let mut should_exit = false;
+        let typed = match k.code {
+            KeyCode::Char(c)
+                if (k.modifiers.is_empty() || k.modifiers == KeyModifiers::SHIFT)
+                    && !c.is_control() =>
+            {
+                Some(c)
             }
-            KeyCode::Char(c) if k.modifiers.is_empty() || k.modifiers == KeyModifiers::SHIFT => {
-                if !c.is_control() {
-                    if replace.is_empty() && !search.is_empty() && !confirmed {
-                        replace.push(c);
-                    } else if confirmed {
-                        replace.push(c);
+            _ => None,
+        };
+
+        match stage {
+            ReplaceStage::Search | ReplaceStage::Replace => match k.code {
+                KeyCode::Esc => {
+                    should_exit = true;
+                }
+                KeyCode::Char('c') if k.modifiers == KeyModifiers::CONTROL => {
+                    case_sensitive = !case_sensitive;
+                }
+                KeyCode::Char('e') if k.modifiers == KeyModifiers::CONTROL => {
+                    regex = !regex;
+                }
+                KeyCode::Char('a') if k.modifiers == KeyModifiers::CONTROL => {
+                    all = !all;
+                }
+                KeyCode::Char('l') if k.modifiers == KeyModifiers::CONTROL => {
+                    if self.replace_selection.is_some() {
+                        in_selection = !in_selection;
+                    } else {
+                        self.set_message("No selection to replace in".into());
+                    }
+                }
+                KeyCode::Tab if stage == ReplaceStage::Replace => {
+                    stage = ReplaceStage::Search;
+                }
+                KeyCode::Tab | KeyCode::Enter if stage == ReplaceStage::Search => {
+                    if !search.is_empty() {
+                        stage = ReplaceStage::Replace;
+                    }
+                }
+                KeyCode::Enter => {
+                    if !in_selection {
+                        self.replace_selection = None;
+                    }
+                    let pos = match self.replace_selection {
+                        Some((start, _)) => start,
+                        None => self
+                            .buffer
+                            .get_cursor_pos(self.cursor_line, self.cursor_col),
+                    };
+                    match self.compile_pattern(&search, case_sensitive, regex) {
+                        // Stay in the prompt so the pattern can be fixed.
+                        None => {}
+                        Some(pattern) if all => {
+                            action = Some(PendingAction::ReplaceAll(pattern, replace.clone()));
+                            should_exit = true;
+                        }
+                        Some(pattern) => {
+                            if self.goto_match_from(&pattern, pos) {
+                                stage = ReplaceStage::Confirm;
+                            } else {
+                                self.set_message(format!("'{}' not found", search));
+                                should_exit = true;
+                            }
+                        }
+                    }
+                }
+                KeyCode::Backspace => {
+                    if stage == ReplaceStage::Search {
+                        search.pop();
                     } else {
-                        search.push(c);
+                        replace.pop();
+                    }
+                }
+                _ => {
+                    if let Some(c) = typed {
+                        if stage == ReplaceStage::Search {
+                            search.push(c);
+                        } else {
+                            replace.push(c);
+                        }
+                    }
+                }
+            },
+            ReplaceStage::Confirm => {
+                // The pattern compiled when the prompt was confirmed.
+                let pattern = Pattern::new(&search, case_sensitive, regex)
+                    .unwrap_or_else(|_| Pattern::literal(&search, case_sensitive));
+                let pos = self
+                    .buffer
+                    .get_cursor_pos(self.cursor_line, self.cursor_col);
+                match typed {
+                    Some('y') => {
+                        should_exit = !self.replace_current_match(&pattern, &replace);
+                    }
+                    Some('n') => {
+                        should_exit = !self.goto_match_from(&pattern, pos + 1);
+                    }
+                    Some('a') => {
+                        let count = self.replace_all(&pattern, &replace, pos);
+                        self.set_message(format!("Replaced {} occurrences", count));
+                        should_exit = true;
                     }
+                    Some('q') => should_exit = true,
+                    _ => should_exit = k.code == KeyCode::Esc,
                 }
             }
-            _ => {}
         }
         (
             search,
             replace,
             case_sensitive,
+            regex,
             all,
-            new_confirmed,
+            in_selection,
+            stage,
             action,
             should_exit,
         )
     }
 
-    fn handle_goto_owned(&mut self, k: &event::KeyEvent) -> (Option<usize>, bool) {
-        self.cursor_blink_on = true;
-        self.last_cursor_time = std::time::Instant::now();
+    fn handle_goto_owned(
+        &mut self,
+        k: &event::KeyEvent,
+        mut input: String,
+    ) -> (String, Option<usize>, bool) {
+        self.cursor_blink_on = true;
+        self.last_cursor_time = std::time::Instant::now();
+
+        match k.code {
+            KeyCode::Esc => (input, None, true),
+            KeyCode::Enter => {
+                // Only digits are accepted, so parsing fails just on overflow.
+                let line = (!input.is_empty()).then(|| input.parse().unwrap_or(usize::MAX));
+                (input, line, true)
+            }
+            KeyCode::Backspace => {
+                input.pop();
+                (input, None, false)
+            }
+            KeyCode::Char(c) if c.is_ascii_digit() => {
+                input.push(c);
+                (input, None, false)
+            }
+            _ => (input, None, false),
+        }
+    }
+
+    fn handle_palette_owned(
+        &mut self,
+        k: &event::KeyEvent,
+        mut query: String,
+        mut selected: usize,
+    ) -> (String, usize, Option<&'static str>, bool) {
+        let matches = palette_matches(&query);
+        match k.code {
+            KeyCode::Esc => return (query, selected, None, true),
+            KeyCode::Enter => {
+                let command = matches.get(selected).map(|&(_, command)| command);
+                return (query, selected, command, true);
+            }
+            KeyCode::Up => selected = selected.saturating_sub(1),
+            KeyCode::Down => selected = (selected + 1).min(matches.len().saturating_sub(1)),
+            KeyCode::Backspace => {
+                query.pop();
+                selected = 0;
+            }
+            KeyCode::Char(c) if !k.modifiers.contains(KeyModifiers::CONTROL) => {
+                query.push(c);
+                selected = 0;
+            }
+            _ => {}
+        }
+        (query, selected, None, false)
+    }
+
+    fn handle_confirm_owned(
+        &mut self,
+        k: &event::KeyEvent,
+        title: String,
+        message: String,
+        options: Vec<String>,
+        mut selected: usize,
+    ) -> (String, String, Vec<String>, usize, Option<PendingAction>) {
+        self.cursor_blink_on = true;
+        self.last_cursor_time = std::time::Instant::now();
+
+        let mut action = None;
+        match k.code {
+            KeyCode::Up | KeyCode::Left => {
+                selected = selected.saturating_sub(1);
+            }
+            KeyCode::Down | KeyCode::Right if selected + 1 < options.len() => {
+                selected += 1;
+            }
+            KeyCode::Enter if title == OPEN_TITLE => match options[selected].as_str() {
+                "Save" => action = Some(PendingAction::SaveAndOpen),
+                "Discard" => action = Some(PendingAction::DiscardAndOpen),
+                _ => self.open_after_save = None,
+            },
+            KeyCode::Esc if title == OPEN_TITLE => self.open_after_save = None,
+            KeyCode::Enter if title == RELOAD_TITLE && options[selected] == "Reload" => {
+                action = Some(PendingAction::Reload);
+            }
+            KeyCode::Enter => match options[selected].as_str() {
+                "Yes" => {
+                    if self.buffer.path.is_some() || self.to_stdout {
+                        action = Some(PendingAction::SaveAndQuit);
+                    } else {
+                        self.quit_after_save = true;
+                        return (title, message, options, selected, action);
+                    }
+                }
+                "No" => {
+                    action = Some(PendingAction::QuitWithoutSave);
+                }
+                "Restore" => {
+                    action = Some(PendingAction::RestoreRecovery);
+                }
+                "Discard" => {
+                    action = Some(PendingAction::DiscardRecovery);
+                }
+                _ => {}
+            },
+            KeyCode::Esc => {}
+            _ => {}
+        }
+        (title, message, options, selected, action)
+    }
+
+    fn handle_input_owned(
+        &mut self,
+        k: &event::KeyEvent,
+        title: String,
+        mut input: String,
+        mut history: Vec<String>,
+    ) -> (String, String, Vec<String>, Option<PendingAction>) {
+        self.cursor_blink_on = true;
+        self.last_cursor_time = std::time::Instant::now();
+
+        let mut action = None;
+        match k.code {
+            KeyCode::Enter => {
+                action = Some(if title == OPEN_FILE_TITLE {
+                    PendingAction::OpenFile(std::path::PathBuf::from(input.trim()))
+                } else {
+                    PendingAction::SaveAs(input.clone())
+                });
+                if !input.is_empty() {
+                    history.push(input.clone());
+                }
+            }
+            KeyCode::Esc => {}
+            KeyCode::Backspace => {
+                input.pop();
+            }
+            KeyCode::Char(c) if !c.is_control() => {
+                input.push(c);
+            }
+            KeyCode::Tab => {
+                input.push('\t');
+            }
+            _ => {}
+        }
+        (title, input, history, action)
+    }
+
+    /// Asks for the path of a file to open.
+    fn open_file(&mut self) {
+        self.mode = EditorMode::Input {
+            title: OPEN_FILE_TITLE.into(),
+            input: String::new(),
+            history: Vec::new(),
+        };
+    }
+
+    /// Opens `path` in place of the current buffer, starting a new file if it
+    /// doesn't exist. An empty path or a directory opens the file browser.
+    /// Unsaved changes are offered to be saved first.
+    fn open_path(&mut self, path: std::path::PathBuf) {
+        if path.as_os_str().is_empty() {
+            self.browse(self.browse_dir());
+        } else if path.is_dir() {
+            self.browse(path);
+        } else if self.buffer.is_modified {
+            self.open_after_save = Some(path);
+            self.mode = EditorMode::Confirm {
+                title: OPEN_TITLE.into(),
+                message: "Save changes before opening?".into(),
+                options: vec!["Save".into(), "Discard".into(), "Cancel".into()],
+                selected: 0,
+            };
+        } else {
+            self.load_path(path);
+        }
+    }
+
+    /// Replaces the buffer with `path`, restoring the cursor if the file was
+    /// open recently.
+    fn load_path(&mut self, path: std::path::PathBuf) {
+        let buffer = match buffer_for_path(path.clone()) {
+            Ok(buffer) => buffer,
+            Err(e) => {
+                self.set_message(format!("Cannot open {}: {}", path.display(), e));
+                return;
+            }
+        };
+        let closed = self
+            .closed_buffers
+            .iter()
+            .position(|c| c.path == path)
+            .map(|i| self.closed_buffers.remove(i));
+        self.replace_buffer(buffer);
+        if let Some(closed) = closed {
+            self.cursor_line = closed.cursor_line;
+            self.cursor_col = closed.cursor_col;
+            self.clamp_cursor();
+            self.update_scroll();
+        }
+    }
+
+    /// Directory of the current file, or the working directory for an
+    /// unnamed buffer.
+    fn browse_dir(&self) -> std::path::PathBuf {
+        self.buffer
+            .path
+            .as_deref()
+            .and_then(|p| std::path::absolute(p).ok())
+            .and_then(|p| p.parent().map(|d| d.to_path_buf()))
+            .or_else(|| std::env::current_dir().ok())
+            .unwrap_or_else(|| std::path::PathBuf::from("."))
+    }
+
+    fn browse(&mut self, dir: std::path::PathBuf) {
+        let dir = std::path::absolute(&dir).unwrap_or(dir);
+        match read_browser_entries(&dir) {
+            Ok(entries) => {
+                self.mode = EditorMode::FileBrowser {
+                    dir,
+                    entries,
+                    selected: 0,
+                }
+            }
+            Err(e) => self.set_message(format!("Cannot read {}: {}", dir.display(), e)),
+        }
+    }
+
+    fn handle_browser_owned(
+        &mut self,
+        k: &event::KeyEvent,
+        mut dir: std::path::PathBuf,
+        mut entries: Vec<BrowserEntry>,
+        mut selected: usize,
+    ) -> (
+        std::path::PathBuf,
+        Vec<BrowserEntry>,
+        usize,
+        Option<PendingAction>,
+        bool,
+    ) {
+        let page = self.view_height().saturating_sub(2).max(1);
+        let last = entries.len().saturating_sub(1);
+        let target = match k.code {
+            KeyCode::Esc => return (dir, entries, selected, None, true),
+            KeyCode::Up => {
+                selected = selected.saturating_sub(1);
+                None
+            }
+            KeyCode::Down => {
+                selected = (selected + 1).min(last);
+                None
+            }
+            KeyCode::PageUp => {
+                selected = selected.saturating_sub(page);
+                None
+            }
+            KeyCode::PageDown => {
+                selected = (selected + page).min(last);
+                None
+            }
+            KeyCode::Home => {
+                selected = 0;
+                None
+            }
+            KeyCode::End => {
+                selected = last;
+                None
+            }
+            KeyCode::Backspace => dir.parent().map(|p| (p.to_path_buf(), true)),
+            KeyCode::Enter => entries.get(selected).map(|entry| {
+                if entry.name == ".." {
+                    let parent = dir.parent().unwrap_or(&dir).to_path_buf();
+                    (parent, true)
+                } else {
+                    (dir.join(&entry.name), entry.is_dir)
+                }
+            }),
+            _ => None,
+        };
+
+        match target {
+            Some((path, true)) => match read_browser_entries(&path) {
+                Ok(new_entries) => {
+                    // Going up keeps the directory we came from selected.
+                    let came_from = dir.strip_prefix(&path).ok().and_then(|r| r.iter().next());
+                    selected = came_from
+                        .and_then(|name| new_entries.iter().position(|e| *name == *e.name))
+                        .unwrap_or(0);
+                    dir = path;
+                    entries = new_entries;
+                }
+                Err(e) => self.set_message(format!("Cannot read {}: {}", path.display(), e)),
+            },
+            Some((path, false)) => {
+                return (
+                    dir,
+                    entries,
+                    selected,
+                    Some(PendingAction::OpenFile(path)),
+                    true,
+                );
+            }
+            None => {}
+        }
+        (dir, entries, selected, None, false)
+    }
+
+    /// Swaps in `buffer`, remembering the outgoing file so it can be reopened.
+    fn replace_buffer(&mut self, buffer: Buffer) {
+        if let Some(path) = self.buffer.path.clone() {
+            self.remember_closed(path);
+        }
+        self.remember_position();
+        self.save_undo_history();
+        self.buffer = buffer;
+        self.highlighter.set_language(&self.buffer.language);
+        self.cursor_line = 0;
+        self.cursor_col = 0;
+        self.scroll_offset = 0;
+        self.col_offset = 0;
+        self.selection = None;
+        self.extra_cursors.clear();
+        self.undo.clear();
+        self.jumps = JumpList::default();
+        self.check_recovery();
+        self.note_mixed_line_endings();
+        self.load_undo_history();
+        self.restore_position();
+        if self.split.is_some() {
+            self.split = Some(self.pane());
+        }
+        self.diff_key = None;
+    }
+
+    fn tab_count(&self) -> usize {
+        self.buffers.len() + 1
+    }
+
+    /// Moves the active buffer's state out of the editor.
+    fn take_state(&mut self) -> BufferState {
+        BufferState {
+            buffer: std::mem::take(&mut self.buffer),
+            cursor_line: self.cursor_line,
+            cursor_col: self.cursor_col,
+            scroll_offset: self.scroll_offset,
+            col_offset: self.col_offset,
+            selection: self.selection.take(),
+            undo: std::mem::replace(&mut self.undo, UndoHistory::new(self.settings.undo_limit)),
+            jumps: std::mem::take(&mut self.jumps),
+            recovery_revision: self.recovery_revision,
+        }
+    }
+
+    fn restore_state(&mut self, state: BufferState) {
+        self.buffer = state.buffer;
+        self.cursor_line = state.cursor_line;
+        self.cursor_col = state.cursor_col;
+        self.scroll_offset = state.scroll_offset;
+        self.col_offset = state.col_offset;
+        self.selection = state.selection;
+        self.undo = state.undo;
+        self.jumps = state.jumps;
+        self.recovery_revision = state.recovery_revision;
+        self.highlighter.set_language(&self.buffer.language);
+        self.auto_closed.clear();
+        self.drag_anchor = None;
+        self.extra_cursors.clear();
+        if self.split.is_some() {
+            self.split = Some(self.pane());
+        }
+        self.diff_key = None;
+    }
+
+    fn switch_buffer(&mut self, index: usize) {
+        if index == self.active || index >= self.tab_count() {
+            return;
+        }
+        let current = self.take_state();
+        self.buffers.insert(self.active, current);
+        let state = self.buffers.remove(index);
+        self.active = index;
+        self.restore_state(state);
+    }
+
+    fn cycle_buffer(&mut self, forward: bool) {
+        let count = self.tab_count();
+        if count == 1 {
+            self.set_message("No other open files".into());
+            return;
+        }
+        let step = if forward { 1 } else { count - 1 };
+        self.switch_buffer((self.active + step) % count);
+    }
+
+    /// Opens `buffer` in a new tab right of the active one.
+    fn open_tab(&mut self, buffer: Buffer) {
+        let current = self.take_state();
+        self.buffers.insert(self.active, current);
+        self.active += 1;
+        self.restore_state(BufferState::new(buffer, self.settings.undo_limit));
+        self.check_recovery();
+        self.note_mixed_line_endings();
+        self.load_undo_history();
+        self.restore_position();
+    }
+
+    /// Closes the active tab, activating its right neighbour (or the left one
+    /// for the last tab). Closing the only tab quits.
+    fn close_buffer(&mut self) {
+        if self.buffers.is_empty() {
+            self.should_quit = true;
+            return;
+        }
+        let index = self.active.min(self.buffers.len() - 1);
+        let state = self.buffers.remove(index);
+        if let Some(path) = self.buffer.path.clone() {
+            self.remember_closed(path);
+        }
+        self.remember_position();
+        self.save_undo_history();
+        self.active = index;
+        self.restore_state(state);
+        self.update_scroll();
+    }
+
+    fn remember_closed(&mut self, path: std::path::PathBuf) {
+        self.closed_buffers.retain(|c| c.path != path);
+        self.closed_buffers.push(ClosedBuffer {
+            path,
+            cursor_line: self.cursor_line,
+            cursor_col: self.cursor_col,
+        });
+        if self.closed_buffers.len() > MAX_CLOSED_BUFFERS {
+            self.closed_buffers.remove(0);
+        }
+    }
+
+    /// Moves to the next (or previous) marked line, wrapping around the file.
+    fn goto_mark(&mut self, forward: bool) {
+        let marks = self.buffer.marked_lines();
+        let target = if forward {
+            marks
+                .iter()
+                .find(|&&l| l > self.cursor_line)
+                .or(marks.first())
+        } else {
+            marks
+                .iter()
+                .rev()
+                .find(|&&l| l < self.cursor_line)
+                .or(marks.last())
+        };
+        match target {
+            Some(&line) => {
+                self.push_jump();
+                self.selection = None;
+                self.cursor_line = line;
+                self.cursor_col = 0;
+                self.update_scroll();
+            }
+            None => self.set_message("No marks".into()),
+        }
+    }
+
+    /// Records the cursor position in the active file for the next session.
+    fn remember_position(&mut self) {
+        if let Some(path) = &self.buffer.path {
+            self.positions.set(
+                path,
+                Position {
+                    line: self.cursor_line,
+                    col: self.cursor_col,
+                    scroll_offset: self.scroll_offset,
+                },
+            );
+        }
+    }
+
+    /// Puts the cursor back where it was when the active file was last
+    /// closed, in this session or an earlier one.
+    fn restore_position(&mut self) {
+        let Some(position) = self
+            .buffer
+            .path
+            .as_deref()
+            .and_then(|p| self.positions.get(p))
+        else {
+            return;
+        };
+        self.cursor_line = position.line.min(self.buffer.num_lines().saturating_sub(1));
+        self.cursor_col = position.col;
+        self.scroll_offset = position.scroll_offset.min(self.cursor_line);
+        self.clamp_cursor();
+        self.update_scroll();
+    }
+
+    /// With `persist_undo`, keeps the active file's undo history for when it
+    /// is next opened.
+    fn save_undo_history(&self) {
+        if !self.settings.persist_undo {
+            return;
+        }
+        if let Some(path) = &self.buffer.path {
+            save_undo(path, &self.buffer, &self.undo);
+        }
+    }
+
+    /// Picks up the undo history saved for the active file, if the file
+    /// hasn't changed since. A stale history is deleted.
+    fn load_undo_history(&mut self) {
+        // A large file picks it up once it has finished loading.
+        if !self.settings.persist_undo || self.buffer.is_partial() {
+            return;
+        }
+        let Some(path) = self.buffer.path.as_deref() else {
+            return;
+        };
+        let Some(json) = recovery::read_undo(path) else {
+            return;
+        };
+        let text = self.buffer.text.to_string();
+        match UndoHistory::from_json(&json, &text, self.settings.undo_limit) {
+            Some(undo) => self.undo = undo,
+            None => recovery::remove_undo(path),
+        }
+    }
+
+    /// Saves the undo history of every open file on exit.
+    fn save_undo_histories(&self) {
+        if !self.settings.persist_undo {
+            return;
+        }
+        self.save_undo_history();
+        for state in &self.buffers {
+            if let Some(path) = &state.buffer.path {
+                save_undo(path, &state.buffer, &state.undo);
+            }
+        }
+    }
+
+    /// Writes the positions in all open files to `positions.toml`.
+    fn save_positions(&mut self) {
+        self.remember_position();
+        for state in &self.buffers {
+            if let Some(path) = &state.buffer.path {
+                self.positions.set(
+                    path,
+                    Position {
+                        line: state.cursor_line,
+                        col: state.cursor_col,
+                        scroll_offset: state.scroll_offset,
+                    },
+                );
+            }
+        }
+        // Nothing can be shown once the editor is closing.
+        let _ = self.positions.save();
+    }
+
+    /// Replaces the buffer's text with the file on disk, as one undo step.
+    fn reload(&mut self) {
+        let Some(path) = self.buffer.path.clone() else {
+            return;
+        };
+        let fresh = match Buffer::from_file(path.clone()) {
+            Ok(fresh) => fresh,
+            Err(e) => {
+                self.set_message(format!("Cannot read {}: {}", path.display(), e));
+                return;
+            }
+        };
+        let old_text = self.buffer.text.to_string();
+        let new_text = fresh.text.to_string();
+        if old_text != new_text {
+            self.buffer.delete(0, old_text.len());
+            self.buffer.insert(0, &new_text);
+            self.undo.push(EditOp::Replace {
+                pos: 0,
+                old_len: old_text.len(),
+                old_text,
+                new_text,
+            });
+        }
+        self.buffer.is_modified = false;
+        self.buffer.disk_mtime = fresh.disk_mtime;
+        self.buffer.final_newline = fresh.final_newline;
+        self.buffer.line_ending = fresh.line_ending;
+        self.buffer.read_only = fresh.read_only;
+        self.buffer.indent = fresh.indent;
+        self.remove_recovery_file();
+        self.selection = None;
+        self.clamp_cursor();
+        self.update_scroll();
+        self.set_message(format!("Reloaded {}", self.buffer.file_name()));
+    }
+
+    /// Mentions once in the status bar when the file changes on disk.
+    /// Reads the next chunk of every large file still loading, reporting
+    /// progress for the active one.
+    fn load_files(&mut self) {
+        if self.buffer.is_loading() {
+            let name = self.buffer.file_name();
+            let message = match self.buffer.load_more() {
+                Ok(()) => match self.buffer.load_progress() {
+                    Some(percent) => format!("Loading {}: {}%", name, percent),
+                    None => format!("Loaded {}", name),
+                },
+                Err(e) => format!("Stopped loading {}: {}", name, e),
+            };
+            self.set_message(message);
+            if !self.buffer.is_partial() {
+                self.check_recovery();
+                self.load_undo_history();
+            }
+        }
+        let mut failed = None;
+        for state in &mut self.buffers {
+            if let Err(e) = state.buffer.load_more() {
+                failed = Some(format!(
+                    "Stopped loading {}: {}",
+                    state.buffer.file_name(),
+                    e
+                ));
+            }
+        }
+        if let Some(message) = failed {
+            self.set_message(message);
+        }
+    }
+
+    fn check_disk_change(&mut self) {
+        if self.last_disk_check.elapsed().as_secs() < DISK_CHECK_INTERVAL_SECS {
+            return;
+        }
+        self.last_disk_check = std::time::Instant::now();
+        if !self.buffer.changed_on_disk() {
+            return;
+        }
+        let mtime = self
+            .buffer
+            .path
+            .as_deref()
+            .and_then(|p| std::fs::metadata(p).and_then(|m| m.modified()).ok());
+        if mtime != self.disk_change_reported {
+            self.disk_change_reported = mtime;
+            self.set_message(format!(
+                "{} changed on disk; press Ctrl+R to reload",
+                self.buffer.file_name()
+            ));
+        }
+    }
+
+    /// Turns gutter change markers against `base` on, or off if they are
+    /// already shown.
+    fn toggle_diff(&mut self, base: DiffBase) -> Result<(), String> {
+        if self.diff_base == Some(base) {
+            self.diff_base = None;
+            return Ok(());
+        }
+        let Some(path) = &self.buffer.path else {
+            return Err("No file on disk to compare with".into());
+        };
+        if base == DiffBase::GitHead && diff::git_changes(path).is_none() {
+            return Err("Not in a git repository".into());
+        }
+        self.diff_base = Some(base);
+        self.diff_key = None;
+        self.refresh_diff();
+        Ok(())
+    }
+
+    /// Updates the gutter change markers. Against the file on disk they
+    /// follow edits, reading the file again when the buffer becomes
+    /// unmodified, which is what saving and reloading do. Git only knows the
+    /// saved file, so markers against HEAD change then too.
+    fn refresh_diff(&mut self) {
+        let key = (self.buffer.revision, self.buffer.is_modified);
+        let Some(base) = self.diff_base else {
+            return;
+        };
+        if self.diff_key == Some(key) {
+            return;
+        }
+        let reread = self.diff_key.is_none() || !self.buffer.is_modified;
+        self.diff_key = Some(key);
+        let path = self.buffer.path.as_deref();
+        match base {
+            DiffBase::Disk => {
+                if reread {
+                    self.disk_text = path
+                        .and_then(|p| std::fs::read(p).ok())
+                        .and_then(|bytes| decode(bytes).ok())
+                        .map(|(_, text)| text);
+                }
+                self.diff_lines = match &self.disk_text {
+                    Some(text) => diff::line_changes(text, &self.buffer.disk_content()),
+                    None => Vec::new(),
+                };
+            }
+            DiffBase::GitHead if reread => {
+                let last_line = self.buffer.num_lines().saturating_sub(1);
+                self.diff_lines = path.and_then(diff::git_changes).unwrap_or_default();
+                for (line, _) in &mut self.diff_lines {
+                    *line = (*line).min(last_line);
+                }
+            }
+            DiffBase::GitHead => {}
+        }
+    }
+
+    /// Saves modified files that have a path once no edit has been made for
+    /// `auto_save_interval_secs`.
+    fn auto_save(&mut self) {
+        if !self.settings.auto_save
+            || self.last_edit.elapsed().as_secs() < self.settings.auto_save_interval_secs
+        {
+            return;
+        }
+        // Retry failed saves only after another interval.
+        self.last_edit = std::time::Instant::now();
+        if self.buffer.is_modified && self.buffer.path.is_some() && self.save() {
+            self.set_message("Auto-saved".into());
+        }
+        let can_edit = self.can_edit();
+        for state in &mut self.buffers {
+            if !state.buffer.is_modified || state.buffer.path.is_none() {
+                continue;
+            }
+            if can_edit {
+                prepare_save(&self.settings, &mut state.buffer, &mut state.undo);
+            }
+            state.cursor_col = state
+                .buffer
+                .grapheme_floor(state.cursor_line, state.cursor_col);
+            if let Some(path) = state.buffer.path.clone()
+                && state.buffer.save().is_ok()
+            {
+                recovery::remove(&path);
+            }
+        }
+    }
+
+    /// Periodically writes unsaved changes to the buffer's recovery file.
+    fn write_recovery_file(&mut self) {
+        if !self.settings.recovery_files
+            || !self.buffer.is_modified
+            || self.buffer.revision == self.recovery_revision
+            || self.last_recovery_write.elapsed().as_secs() < RECOVERY_INTERVAL_SECS
+        {
+            return;
+        }
+        let Some(path) = &self.buffer.path else {
+            return;
+        };
+        self.last_recovery_write = std::time::Instant::now();
+        if recovery::write(path, &self.buffer.text.to_string()).is_ok() {
+            self.recovery_revision = self.buffer.revision;
+        }
+    }
+
+    fn remove_recovery_file(&mut self) {
+        if let Some(path) = &self.buffer.path {
+            recovery::remove(path);
+        }
+        self.recovery_revision = self.buffer.revision;
+    }
+
+    /// Offers to restore a recovery file left behind by an earlier session.
+    fn check_recovery(&mut self) {
+        self.recovery_revision = self.buffer.revision;
+        if !self.settings.recovery_files || self.buffer.is_partial() {
+            return;
+        }
+        let Some(path) = &self.buffer.path else {
+            return;
+        };
+        if recovery::read_newer(path).is_some() {
+            self.mode = EditorMode::Confirm {
+                title: "Recover".into(),
+                message: format!("Unsaved changes to {} were found", self.buffer.file_name()),
+                options: vec!["Restore".into(), "Discard".into()],
+                selected: 0,
+            };
+        }
+    }
+
+    fn note_mixed_line_endings(&mut self) {
+        if self.buffer.mixed_line_endings {
+            self.set_message(format!(
+                "Mixed line endings; saving will use {}",
+                self.buffer.line_ending.as_str()
+            ));
+        }
+    }
+
+    fn restore_recovery(&mut self) {
+        let Some(text) = self.buffer.path.as_deref().and_then(recovery::read_newer) else {
+            return;
+        };
+        let old_text = self.buffer.text.to_string();
+        self.buffer.delete(0, old_text.len());
+        self.buffer.insert(0, &text);
+        self.undo.push(EditOp::Replace {
+            pos: 0,
+            old_len: old_text.len(),
+            old_text,
+            new_text: text,
+        });
+        self.recovery_revision = self.buffer.revision;
+        self.selection = None;
+        self.cursor_line = 0;
+        self.cursor_col = 0;
+    }
+
+    fn reopen_closed_buffer(&mut self) {
+        self.closed_buffers.retain(|c| c.path.exists());
+        match self.closed_buffers.last() {
+            Some(closed) => self.open_path(closed.path.clone()),
+            None => self.set_message("No recently closed file".into()),
+        }
+    }
+
+    fn render(&self, f: &mut ratatui::Frame) {
+        let a = f.area();
+        let th = 1u16;
+        let tbh = self.tab_bar_height() as u16;
+        let top = th + tbh;
+        let hh = if self.show_help { 1u16 } else { 0u16 };
+        let sh = 1u16;
+        let eh = a.height.saturating_sub(top + hh + sh);
+
+        let ta = Rect::new(a.x, a.y, a.width, th);
+        let modified_indicator = if self.buffer.is_modified {
+            " [Modified]"
+        } else {
+            ""
+        };
+        let tab_indicator = if self.tab_count() > 1 && tbh == 0 {
+            format!(" ({}/{})", self.active + 1, self.tab_count())
+        } else {
+            String::new()
+        };
+        f.render_widget(
+            TitleBar {
+                file_name: format!(
+                    " Nova - {}{}{} ",
+                    self.buffer.file_name(),
+                    modified_indicator,
+                    tab_indicator
+                ),
+                theme: self.theme.clone(),
+            },
+            ta,
+        );
+
+        if tbh > 0 {
+            f.render_widget(
+                TabBar {
+                    tabs: self.tab_labels(),
+                    active: self.active,
+                    theme: self.theme.clone(),
+                },
+                Rect::new(a.x, a.y + th, a.width, tbh),
+            );
+        }
+
+        let sa = Rect::new(a.x, a.y + top + eh, a.width, sh);
+        let status_text = match &self.mode {
+            EditorMode::Search {
+                query,
+                case_sensitive,
+                regex,
+                backward,
+            } => format!(
+                "Search{} [{}{}]: {}",
+                if *backward { " backward" } else { "" },
+                if *case_sensitive {
+                    "case sensitive"
+                } else {
+                    "ignore case"
+                },
+                if *regex { ", regex" } else { "" },
+                query
+            ),
+            EditorMode::Replace {
+                search,
+                replace,
+                regex,
+                all,
+                in_selection,
+                stage,
+                ..
+            } => {
+                let scope = format!(
+                    "{}{}{}",
+                    if *all { " all" } else { "" },
+                    if *in_selection { " in selection" } else { "" },
+                    if *regex { " [regex]" } else { "" },
+                );
+                match stage {
+                    ReplaceStage::Search => format!("Replace{}: {}", scope, search),
+                    ReplaceStage::Replace => {
+                        format!("Replace{} '{}' with: {}", scope, search, replace)
+                    }
+                    ReplaceStage::Confirm => "Replace this? (y/n/a/q)".to_string(),
+                }
+            }
+            EditorMode::GoToLine { input } => format!("Go to line: {}", input),
+            EditorMode::FileBrowser { dir, .. } => format!("Open: {}", dir.display()),
+            EditorMode::CommandPalette { query, .. } => format!("Command: {}", query),
+            EditorMode::Confirm { title, message, .. } => format!("{} - {}", title, message),
+            EditorMode::Input { title, input, .. } => format!("{}: {}", title, input),
+            _ => format!(
+                "Ln {}, Col {}",
+                self.cursor_line + 1,
+                self.cursor_display_col() + 1
+            ),
+        };
+        f.render_widget(
+            StatusBar {
+                file_name: self.buffer.file_name(),
+                modified: self.buffer.is_modified,
+                line: self.cursor_line + 1,
+                col: self.cursor_display_col() + 1,
+                total_lines: self.buffer.num_lines(),
+                size: self.buffer.total_len(),
+                encoding: self.buffer.encoding.as_str(),
+                line_ending: self.buffer.line_ending.as_str(),
+                indent: self.indent().to_string(),
+                read_only: self.read_only || self.buffer.read_only || self.buffer.is_partial(),
+                overwrite: self.overwrite,
+                language: self.buffer.language.clone(),
+                theme: self.theme.clone(),
+                search_mode: !matches!(self.mode, EditorMode::Normal),
+                search_text: status_text,
+                message: match self.pending_chord {
+                    Some(_) => "Ctrl+K was pressed. Waiting for second key...".to_string(),
+                    None => self.status_message.clone().unwrap_or_default(),
+                },
+            },
+            sa,
+        );
+
+        if self.show_help {
+            let ha = Rect::new(a.x, a.y + top + eh + sh, a.width, hh);
+            f.render_widget(
+                HelpBar {
+                    shortcuts: vec![
+                        ("Ctrl+H", "Help"),
+                        ("Ctrl+O", "Open"),
+                        ("Ctrl+S", "Save"),
+                        ("Ctrl+F", "Find"),
+                    ],
+                    visible: true,
+                    theme: self.theme.clone(),
+                    tip: self.current_tip.clone(),
+                },
+                ha,
+            );
+        }
+
+        if self.mode == EditorMode::Help {
+            self.render_help(f, a);
+            return;
+        }
+
+        let (top_height, bottom_height) = self.pane_heights();
+        let top_area = Rect::new(a.x, a.y + top, a.width, (top_height as u16).min(eh));
+        let bottom_area = Rect::new(
+            a.x,
+            top_area.bottom(),
+            a.width,
+            (bottom_height as u16).min(eh - top_area.height),
+        );
+        let (focused_area, other_area) = if self.split_bottom {
+            (bottom_area, top_area)
+        } else {
+            (top_area, bottom_area)
+        };
+        let view = |pane: Pane| EditorView {
+            buffer: &self.buffer,
+            cursor_line: pane.cursor_line,
+            cursor_col: pane.cursor_col,
+            show_line_numbers: self.show_line_numbers,
+            scroll_offset: pane.scroll_offset,
+            col_offset: pane.col_offset,
+            selection: None,
+            search_matches: Vec::new(),
+            bracket_cells: Vec::new(),
+            syntax_spans: self.visible_syntax_spans(pane.scroll_offset, eh as usize),
+            theme: self.theme.clone(),
+            cursor_blink_on: false,
+            tab_width: self.settings.tab_width,
+            word_wrap: self.word_wrap,
+            extra_cursors: Vec::new(),
+            show_whitespace: self.settings.show_whitespace,
+            highlight_trailing_whitespace: self.settings.highlight_trailing_whitespace,
+            trailing_whitespace_on_current_line: self.settings.trailing_whitespace_on_current_line,
+            highlight_current_line: self.settings.highlight_current_line,
+            show_scrollbar: self.settings.show_scrollbar,
+            line_changes: if self.diff_base.is_some() {
+                self.diff_lines.clone()
+            } else {
+                Vec::new()
+            },
+        };
+        f.render_widget(
+            EditorView {
+                selection: self.selection,
+                search_matches: self.visible_search_matches(focused_area.height as usize),
+                bracket_cells: self.bracket_cells(focused_area.height as usize),
+                cursor_blink_on: self.cursor_blink_on,
+                extra_cursors: self.extra_cursors.clone(),
+                ..view(self.pane())
+            },
+            focused_area,
+        );
+        if let Some(other) = self.split {
+            f.render_widget(view(other), other_area);
+        }
+
+        match &self.mode {
+            EditorMode::Input { title, input, .. } => {
+                self.render_input_dialog(f, a, title, input);
+            }
+            EditorMode::FileBrowser {
+                dir,
+                entries,
+                selected,
+            } => {
+                self.render_file_browser(f, a, dir, entries, *selected);
+            }
+            EditorMode::GoToLine { input } => {
+                self.render_input_dialog(f, a, "Go to Line", input);
+            }
+            EditorMode::CommandPalette { query, selected } => {
+                self.render_command_palette(f, a, query, *selected);
+            }
+            EditorMode::Stats { rows } => {
+                self.render_stats(f, a, rows);
+            }
+            EditorMode::Confirm {
+                title,
+                message,
+                options,
+                selected,
+            } => {
+                self.render_confirm_dialog(f, a, title, message, options, *selected);
+            }
+            _ => {}
+        }
+    }
+
+    /// Matches of the query being typed in Search or Replace mode, on the
+    /// lines that fit in a view `height` rows tall.
+    fn visible_search_matches(&self, height: usize) -> Vec<(usize, usize, usize)> {
+        let (query, case_sensitive, regex) = match &self.mode {
+            EditorMode::Search {
+                query,
+                case_sensitive,
+                regex,
+                ..
+            } => (query, *case_sensitive, *regex),
+            EditorMode::Replace {
+                search,
+                case_sensitive,
+                regex,
+                ..
+            } => (search, *case_sensitive, *regex),
+            _ => return Vec::new(),
+        };
+        let mut cache = self.search_highlight.borrow_mut();
+        let cached = cache.as_ref().is_some_and(|c| {
+            c.query == *query && c.case_sensitive == case_sensitive && c.regex == regex
+        });
+        if !cached {
+            *cache = Some(SearchHighlight {
+                query: query.clone(),
+                case_sensitive,
+                regex,
+                pattern: Pattern::new(query, case_sensitive, regex).ok(),
+            });
+        }
+        let Some(pattern) = cache.as_ref().and_then(|c| c.pattern.as_ref()) else {
+            return Vec::new();
+        };
+        let end = (self.scroll_offset + height).min(self.buffer.num_lines());
+        (self.scroll_offset..end)
+            .flat_map(|line| {
+                self.buffer
+                    .line_matches(line, pattern)
+                    .into_iter()
+                    .map(move |(start, end)| (line, start, end))
+            })
+            .collect()
+    }
+
+    fn visible_syntax_spans(
+        &self,
+        scroll_offset: usize,
+        height: usize,
+    ) -> Vec<(usize, usize, usize, TokenKind)> {
+        let end = (scroll_offset + height).min(self.buffer.num_lines());
+        (scroll_offset..end)
+            .flat_map(|line| {
+                self.buffer
+                    .highlight_line(line, &self.highlighter)
+                    .into_iter()
+                    .map(move |(range, kind)| (line, range.start, range.end, kind))
+            })
+            .collect()
+    }
+
+    /// Positions of the bracket on or just before the cursor and its match.
+    fn bracket_pair(&self) -> Option<(usize, usize)> {
+        self.bracket_pair_in(0..self.buffer.total_len())
+    }
+
+    /// The bracket at or just before the cursor and its partner, looking
+    /// for the partner only within the byte `range`.
+    fn bracket_pair_in(&self, range: std::ops::Range<usize>) -> Option<(usize, usize)> {
+        let pos = self
+            .buffer
+            .get_cursor_pos(self.cursor_line, self.cursor_col);
+        let candidates = [
+            Some(pos),
+            pos.checked_sub(1).filter(|_| self.cursor_col > 0),
+        ];
+        candidates
+            .into_iter()
+            .flatten()
+            .find_map(|p| Some((p, self.buffer.match_bracket_in(p, range.clone())?)))
+    }
+
+    /// The bracket pair at the cursor, if both ends are on the lines that fit
+    /// in a view `height` rows tall.
+    fn bracket_cells(&self, height: usize) -> Vec<(usize, usize)> {
+        let start = self.buffer.get_cursor_pos(self.scroll_offset, 0);
+        let end = self.buffer.get_cursor_pos(self.scroll_offset + height, 0);
+        self.bracket_pair_in(start..end)
+            .map(|(a, b)| vec![self.buffer.get_line_col(a), self.buffer.get_line_col(b)])
+            .unwrap_or_default()
+    }
+
+    /// Remembers the cursor position before a jump, for `jump`.
+    fn push_jump(&mut self) {
+        let pos = self
+            .buffer
+            .get_cursor_pos(self.cursor_line, self.cursor_col);
+        self.jumps.push(pos);
+    }
+
+    /// Goes back or forward through the positions jumps started from. They
+    /// are byte offsets, so after edits they land on the nearest valid spot.
+    fn jump(&mut self, back: bool) {
+        let current = self
+            .buffer
+            .get_cursor_pos(self.cursor_line, self.cursor_col);
+        let target = if back {
+            self.jumps.back(current)
+        } else {
+            self.jumps.forward()
+        };
+        match target {
+            Some(pos) => {
+                self.selection = None;
+                (self.cursor_line, self.cursor_col) = self.buffer.get_line_col(pos);
+                self.clamp_cursor();
+                self.update_scroll();
+            }
+            None if back => self.set_message("No earlier position".into()),
+            None => self.set_message("No later position".into()),
+        }
+    }
+
+    /// Moves the cursor onto the bracket matching the one at the cursor.
+    fn goto_bracket(&mut self) {
+        match self.bracket_pair() {
+            Some((_, target)) => {
+                self.push_jump();
+                self.selection = None;
+                (self.cursor_line, self.cursor_col) = self.buffer.get_line_col(target);
+                self.update_scroll();
+            }
+            None => self.set_message("No matching bracket".into()),
+        }
+    }
+
+    fn dialog_style(&self) -> Style {
+        Style::default()
+            .bg(self.theme.background)
+            .fg(self.theme.foreground)
+    }
+
+    fn dialog_block(&self, title: &str) -> ratatui::widgets::Block<'static> {
+        ratatui::widgets::Block::default()
+            .title(format!(" {} ", title))
+            .borders(ratatui::widgets::Borders::ALL)
+            .border_type(ratatui::widgets::BorderType::Double)
+            .style(self.dialog_style())
+    }
+
+    fn render_help(&self, f: &mut ratatui::Frame, area: Rect) {
+        let dr = centered_rect(area, 60, 20);
+        f.render_widget(ratatui::widgets::Clear, dr);
+        f.render_widget(self.dialog_block("Help - Press Ctrl+H or ESC to close"), dr);
+
+        let tr = dr.inner(Margin::new(1, 1));
+        f.render_widget(
+            Paragraph::new(HELP_TEXT)
+                .style(self.dialog_style())
+                .wrap(ratatui::widgets::Wrap { trim: true })
+                .scroll((self.help_scroll, 0)),
+            tr,
+        );
+    }
+
+    fn render_stats(&self, f: &mut ratatui::Frame, area: Rect, rows: &[(String, String)]) {
+        let label_width = rows.iter().map(|(l, _)| l.len()).max().unwrap_or(0);
+        let lines: Vec<Line> = rows
+            .iter()
+            .map(|(label, value)| Line::from(format!("{:<label_width$}  {}", label, value)))
+            .collect();
+        let dr = centered_rect(area, 40, lines.len() as u16 + 2);
+        f.render_widget(ratatui::widgets::Clear, dr);
+        f.render_widget(self.dialog_block("Statistics - Press any key to close"), dr);
+        f.render_widget(
+            Paragraph::new(lines).style(self.dialog_style()),
+            dr.inner(Margin::new(1, 1)),
+        );
+    }
+
+    fn render_input_dialog(&self, f: &mut ratatui::Frame, area: Rect, title: &str, input: &str) {
+        let width = (title.chars().count().max(input.chars().count()) + 4).max(30);
+        let dr = centered_rect(area, width as u16, 3);
+        f.render_widget(ratatui::widgets::Clear, dr);
+        f.render_widget(self.dialog_block(title), dr);
+
+        // Keep the end of long input visible when the dialog is narrow.
+        let tr = dr.inner(Margin::new(1, 1));
+        let visible = tr.width as usize;
+        let len = input.chars().count();
+        let shown: String = input.chars().skip(len.saturating_sub(visible)).collect();
+        f.render_widget(Paragraph::new(shown).style(self.dialog_style()), tr);
+    }
+
+    fn render_file_browser(
+        &self,
+        f: &mut ratatui::Frame,
+        area: Rect,
+        dir: &std::path::Path,
+        entries: &[BrowserEntry],
+        selected: usize,
+    ) {
+        let dr = centered_rect(area, 60, area.height.saturating_sub(4).min(20));
+        f.render_widget(ratatui::widgets::Clear, dr);
+        f.render_widget(self.dialog_block(&dir.display().to_string()), dr);
+
+        let tr = dr.inner(Margin::new(1, 1));
+        let rows = (tr.height as usize).max(1);
+        let first = selected.saturating_sub(rows - 1);
+        let selected_style = Style::default()
+            .bg(self.theme.accent)
+            .fg(self.theme.background);
+        let lines: Vec<Line> = entries
+            .iter()
+            .enumerate()
+            .skip(first)
+            .take(rows)
+            .map(|(i, entry)| {
+                let name = if entry.is_dir {
+                    format!("{}/", entry.name)
+                } else {
+                    entry.name.clone()
+                };
+                let style = if i == selected {
+                    selected_style
+                } else {
+                    self.dialog_style()
+                };
+                Line::from(Span::styled(name, style))
+            })
+            .collect();
+        f.render_widget(Paragraph::new(lines).style(self.dialog_style()), tr);
+    }
+
+    /// The query on the first row, then the matching commands with their keys.
+    fn render_command_palette(
+        &self,
+        f: &mut ratatui::Frame,
+        area: Rect,
+        query: &str,
+        selected: usize,
+    ) {
+        let matches = palette_matches(query);
+        let height = (matches.len() as u16 + 3).min(area.height.saturating_sub(4));
+        let dr = centered_rect(area, 50, height);
+        f.render_widget(ratatui::widgets::Clear, dr);
+        f.render_widget(self.dialog_block("Command Palette"), dr);
+
+        let tr = dr.inner(Margin::new(1, 1));
+        let width = tr.width as usize;
+        let rows = (tr.height as usize).saturating_sub(1).max(1);
+        let first = selected.saturating_sub(rows - 1);
+        let selected_style = Style::default()
+            .bg(self.theme.accent)
+            .fg(self.theme.background);
+        let mut lines = vec![Line::from(Span::styled(
+            format!("> {}", query),
+            self.dialog_style(),
+        ))];
+        lines.extend(matches.iter().enumerate().skip(first).take(rows).map(
+            |(i, &(label, command))| {
+                let key = self
+                    .keymap
+                    .iter()
+                    .filter(|&(_, &c)| c == command)
+                    .map(|(key, _)| key.to_string())
+                    .min_by_key(|key| (key.len(), key.clone()))
+                    .unwrap_or_default();
+                let gap = width.saturating_sub(label.len() + key.len());
+                let style = if i == selected {
+                    selected_style
+                } else {
+                    self.dialog_style()
+                };
+                Line::from(Span::styled(
+                    format!("{}{}{}", label, " ".repeat(gap), key),
+                    style,
+                ))
+            },
+        ));
+        f.render_widget(Paragraph::new(lines).style(self.dialog_style()), tr);
+    }
+
+    fn render_confirm_dialog(
+        &self,
+        f: &mut ratatui::Frame,
+        area: Rect,
+        title: &str,
+        message: &str,
+        options: &[String],
+        selected: usize,
+    ) {
+        let width = area.width.min(50);
+        let inner_width = width.saturating_sub(2).max(1) as usize;
+        let message_lines = message.chars().count().div_ceil(inner_width).max(1);
+        let dr = centered_rect(area, width, (message_lines + 4) as u16);
+        f.render_widget(ratatui::widgets::Clear, dr);
+        f.render_widget(self.dialog_block(title), dr);
+
+        let selected_style = Style::default()
+            .bg(self.theme.accent)
+            .fg(self.theme.background);
+        let mut option_spans = Vec::new();
+        for (i, option) in options.iter().enumerate() {
+            let style = if i == selected {
+                selected_style
+            } else {
+                self.dialog_style()
+            };
+            option_spans.push(Span::styled(format!(" {} ", option), style));
+            option_spans.push(Span::raw(" "));
+        }
+
+        let text = vec![
+            Line::from(message.to_string()),
+            Line::from(""),
+            Line::from(option_spans),
+        ];
+        let tr = dr.inner(Margin::new(1, 1));
+        f.render_widget(
+            Paragraph::new(text)
+                .style(self.dialog_style())
+                .wrap(ratatui::widgets::Wrap { trim: true }),
+            tr,
+        );
+    }
+}
+
+/// Buffer for the file at `path`, empty if it doesn't exist yet.
+fn buffer_for_path(path: std::path::PathBuf) -> io::Result<Buffer> {
+    if path.exists() {
+        Buffer::from_file(path)
+    } else {
+        Ok(Buffer::for_new_file(path))
+    }
+}
+
+/// Palette commands whose label contains the letters of `query` in order,
+/// ignoring case, so "tww" finds "Toggle Word Wrap".
+fn palette_matches(query: &str) -> Vec<(&'static str, &'static str)> {
+    let query = query.to_lowercase();
+    PALETTE_COMMANDS
+        .iter()
+        .copied()
+        .filter(|(label, _)| {
+            let label = label.to_lowercase();
+            let mut letters = label.chars();
+            query
+                .chars()
+                .filter(|c| !c.is_whitespace())
+                .all(|q| letters.any(|c| c == q))
+        })
+        .collect()
+}
+
+/// Entries of `dir` for the file browser: `..` first unless `dir` is a root,
+/// then directories, then files, each sorted by name.
+fn read_browser_entries(dir: &std::path::Path) -> io::Result<Vec<BrowserEntry>> {
+    let mut entries: Vec<BrowserEntry> = std::fs::read_dir(dir)?
+        .filter_map(|e| e.ok())
+        .map(|e| BrowserEntry {
+            name: e.file_name().to_string_lossy().into_owned(),
+            is_dir: e.path().is_dir(),
+        })
+        .collect();
+    entries.sort_by(|a, b| {
+        b.is_dir
+            .cmp(&a.is_dir)
+            .then_with(|| a.name.to_lowercase().cmp(&b.name.to_lowercase()))
+    });
+    if dir.parent().is_some() {
+        entries.insert(
+            0,
+            BrowserEntry {
+                name: "..".into(),
+                is_dir: true,
+            },
+        );
+    }
+    Ok(entries)
+}
+
+/// Centers a `width` x `height` rectangle in `area`, shrinking it to fit.
+fn centered_rect(area: Rect, width: u16, height: u16) -> Rect {
+    let width = width.min(area.width);
+    let height = height.min(area.height);
+    Rect::new(
+        area.x + (area.width - width) / 2,
+        area.y + (area.height - height) / 2,
+        width,
+        height,
+    )
+}
+
+/// A file named on the command line, with the line and column to start at.
+type FileArg = (String, Option<(usize, Option<usize>)>);
+
+/// Splits a `path:line` or `path:line:col` argument, as printed by compilers
+/// and grep. An argument that names an existing file is kept whole, even if
+/// it ends in something that looks like a line number.
+fn parse_file_arg(arg: &str) -> FileArg {
+    if !std::path::Path::new(arg).exists() {
+        let mut parts = arg.rsplitn(3, ':');
+        let last = parts.next().and_then(|n| n.parse::<usize>().ok());
+        let middle = parts.next();
+        match (last, middle, parts.next()) {
+            (Some(col), Some(line), Some(path)) if !path.is_empty() => {
+                if let Ok(line) = line.parse::<usize>() {
+                    return (path.to_string(), Some((line, Some(col))));
+                }
+                return (format!("{}:{}", path, line), Some((col, None)));
+            }
+            (Some(line), Some(path), None) if !path.is_empty() => {
+                return (path.to_string(), Some((line, None)));
+            }
+            _ => {}
+        }
+    }
+    (arg.to_string(), None)
+}
+
+fn run(
+    files: Vec<FileArg>,
+    read_only: bool,
+    to_stdout: bool,
+    piped: Option<Buffer>,
+) -> io::Result<()> {
+    enable_raw_mode()?;
+    // With stdout redirected (`nova --stdout | sort`) the screen is drawn on
+    // the terminal itself.
+    let mut o: Box<dyn io::Write> = if io::stdout().is_terminal() {
+        Box::new(stdout())
+    } else {
+        Box::new(std::fs::OpenOptions::new().write(true).open("/dev/tty")?)
+    };
+    o.execute(EnterAlternateScreen)?;
+    let b = CrosstermBackend::new(o);
+    let mut t = Terminal::new(b)?;
+
+    let (width, height) = size().unwrap_or((80, 24));
+
+    let first = files.first().map(|(file, _)| file.clone());
+    let mut e = Editor::new(first, width as usize, height as usize);
+    for (i, (file, target)) in files.iter().enumerate() {
+        if i > 0 {
+            match buffer_for_path(file.into()) {
+                Ok(buffer) => e.open_tab(buffer),
+                Err(err) => {
+                    e.set_message(format!("Cannot open {}: {}", file, err));
+                    continue;
+                }
+            }
+        }
+        if let Some((line, col)) = *target {
+            e.goto_position(line, col);
+        }
+    }
+    e.to_stdout = to_stdout;
+    if let Some(buffer) = piped {
+        e.replace_buffer(buffer);
+        // Piped text passes through unchanged unless it is edited and saved.
+        if to_stdout {
+            e.stdout_text = Some(e.buffer.disk_content());
+        }
+    }
+    e.switch_buffer(0);
+    e.read_only = read_only;
+    e.run_startup_command();
+    let mouse = e.settings.mouse_support;
+    if mouse {
+        t.backend_mut().execute(EnableMouseCapture)?;
+    }
+
+    loop {
+        t.draw(|f| e.render(f))?;
+
+        // Redraw only after an event or once something on a timer is due.
+        let ready = match e.poll_timeout() {
+            Some(timeout) => event::poll(timeout)?,
+            None => true,
+        };
+        if ready {
+            match event::read() {
+                Ok(Event::Key(k)) if k.kind == KeyEventKind::Press => {
+                    e.handle_key(&k);
+                }
+                Ok(Event::Mouse(m)) => e.handle_mouse(&m),
+                Ok(Event::Resize(w, h)) => e.resize(w as usize, h as usize),
+                _ => {}
+            }
+        }
+
+        e.update_cursor_blink();
+        e.expire_chord();
+        e.expire_message();
+        e.load_files();
+        e.write_recovery_file();
+        e.auto_save();
+        e.check_disk_change();
+        e.refresh_diff();
+        if e.should_quit {
+            break;
+        }
+    }
+
+    if !e.buffer.is_modified {
+        e.remove_recovery_file();
+    }
+    e.save_positions();
+    e.save_undo_histories();
+
+    if mouse {
+        t.backend_mut().execute(DisableMouseCapture)?;
+    }
+    disable_raw_mode()?;
+    t.backend_mut().execute(LeaveAlternateScreen)?;
+    // Only once the screen is restored, so the text isn't drawn over.
+    if let Some(text) = e.stdout_text {
+        stdout().write_all(text.as_bytes())?;
+        stdout().flush()?;
+    }
+    Ok(())
+}
+
+fn main() -> io::Result<()> {
+    let args: Vec<String> = std::env::args().collect();
+
+    let files: Vec<FileArg> = args[1..]
+        .iter()
+        .filter(|arg| !arg.starts_with('-'))
+        .map(|arg| parse_file_arg(arg))
+        .collect();
+    let read_only = args.iter().any(|arg| arg == "--readonly");
+    let to_stdout = args[1..].iter().any(|arg| arg == "-" || arg == "--stdout");
+
+    // Text piped in (`cat foo | nova`) opens as an untitled buffer. Keys are
+    // still read from the terminal, which crossterm opens directly.
+    let piped = if files.is_empty() && !io::stdin().is_terminal() {
+        let mut bytes = Vec::new();
+        io::stdin().read_to_end(&mut bytes)?;
+        Some(Buffer::with_text(
+            String::from_utf8_lossy(&bytes).replace('\0', "\u{fffd}"),
+        ))
+    } else {
+        None
+    };
+
+    if let Err(x) = run(files, read_only, to_stdout, piped) {
+        disable_raw_mode()?;
+        stdout().execute(LeaveAlternateScreen).ok();
+        eprintln!("Error: {}", x);
+        std::process::exit(1);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crossterm::event::KeyEvent;
+
+    /// An editor with default settings, whatever the user's config says.
+    fn test_editor(initial_file: Option<String>, width: usize, height: usize) -> Editor {
+        Editor::with_settings(
+            Settings::default(),
+            Positions::default(),
+            initial_file,
+            width,
+            height,
+        )
+    }
+
+    fn press(editor: &mut Editor, code: KeyCode, modifiers: KeyModifiers) {
+        editor.handle_key(&KeyEvent::new(code, modifiers));
+    }
+
+    #[test]
+    fn empty_buffer_survives_navigation() {
+        let mut editor = test_editor(None, 80, 24);
+        let len = editor.buffer.total_len();
+        editor.buffer.delete(0, len);
+        assert_eq!(editor.buffer.total_len(), 0);
+        assert_eq!(editor.buffer.num_lines(), 1);
+
+        for code in [
+            KeyCode::Down,
+            KeyCode::Right,
+            KeyCode::Up,
+            KeyCode::Left,
+            KeyCode::PageDown,
+            KeyCode::PageUp,
+            KeyCode::End,
+            KeyCode::Home,
+            KeyCode::Backspace,
+        ] {
+            press(&mut editor, code, KeyModifiers::NONE);
+        }
+        press(&mut editor, KeyCode::Char('d'), KeyModifiers::CONTROL);
+        press(&mut editor, KeyCode::Char('k'), KeyModifiers::CONTROL);
+
+        assert_eq!((editor.cursor_line, editor.cursor_col), (0, 0));
+    }
+
+    #[test]
+    fn cursor_steps_over_whole_characters() {
+        let mut editor = test_editor(None, 80, 24);
+        for c in "e\u{301}日x".chars() {
+            press(&mut editor, KeyCode::Char(c), KeyModifiers::NONE);
+        }
+        assert_eq!(editor.cursor_display_col(), 4);
+
+        press(&mut editor, KeyCode::Left, KeyModifiers::NONE);
+        press(&mut editor, KeyCode::Left, KeyModifiers::NONE);
+        assert_eq!(editor.cursor_col, 3);
+        assert_eq!(editor.cursor_display_col(), 1);
+
+        press(&mut editor, KeyCode::Char('d'), KeyModifiers::CONTROL);
+        press(&mut editor, KeyCode::Backspace, KeyModifiers::NONE);
+        assert_eq!(editor.buffer.get_line(0), "x");
+        assert_eq!(editor.cursor_col, 0);
+
+        editor.buffer.insert(0, "日本");
+        assert_eq!(editor.text_position(1 + 5 + 3, 2), (0, 3));
+    }
+
+    #[test]
+    fn shift_arrows_select_until_an_unshifted_key() {
+        let mut editor = test_editor(None, 80, 10);
+        editor.buffer.insert(0, "abc\ndef");
+        press(&mut editor, KeyCode::Right, KeyModifiers::SHIFT);
+        press(&mut editor, KeyCode::Right, KeyModifiers::SHIFT);
+        press(&mut editor, KeyCode::Down, KeyModifiers::SHIFT);
+        let sel = editor.selection.expect("selection");
+        assert_eq!(sel.kind, SelectionKind::Linear);
+        assert_eq!((sel.anchor_line, sel.anchor_col), (0, 0));
+        assert_eq!((editor.cursor_line, editor.cursor_col), (1, 2));
+        assert!(sel.contains(1, 2, 0, 2));
+        assert!(sel.contains(1, 2, 1, 1));
+        assert!(!sel.contains(1, 2, 1, 2));
+
+        press(&mut editor, KeyCode::Left, KeyModifiers::NONE);
+        assert!(editor.selection.is_none());
+
+        press(&mut editor, KeyCode::Left, KeyModifiers::SHIFT);
+        assert!(editor.selection.is_some());
+        press(&mut editor, KeyCode::Char('x'), KeyModifiers::NONE);
+        assert!(editor.selection.is_none());
+    }
+
+    #[test]
+    fn f3_repeats_the_last_search_both_ways() {
+        let mut editor = test_editor(None, 80, 10);
+        editor.buffer.insert(0, "ab\nab\nab");
+        press(&mut editor, KeyCode::F(3), KeyModifiers::NONE);
+        assert_eq!(editor.status_message.as_deref(), Some("No previous search"));
+
+        press(&mut editor, KeyCode::Char('f'), KeyModifiers::CONTROL);
+        press(&mut editor, KeyCode::Char('a'), KeyModifiers::NONE);
+        press(&mut editor, KeyCode::Char('b'), KeyModifiers::NONE);
+        press(&mut editor, KeyCode::Enter, KeyModifiers::NONE);
+        press(&mut editor, KeyCode::Esc, KeyModifiers::NONE);
+        assert_eq!((editor.cursor_line, editor.cursor_col), (1, 0));
+
+        press(&mut editor, KeyCode::F(3), KeyModifiers::NONE);
+        assert_eq!((editor.cursor_line, editor.cursor_col), (2, 0));
+        assert_eq!(editor.status_message.as_deref(), Some("3 of 3"));
+        press(&mut editor, KeyCode::F(3), KeyModifiers::SHIFT);
+        assert_eq!((editor.cursor_line, editor.cursor_col), (1, 0));
+        assert_eq!(editor.status_message.as_deref(), Some("2 of 3"));
+    }
+
+    #[test]
+    fn search_highlights_follow_the_query_and_options() {
+        let mut editor = test_editor(None, 80, 10);
+        editor.buffer.insert(0, "ab AB\nab");
+        press(&mut editor, KeyCode::Char('f'), KeyModifiers::CONTROL);
+        press(&mut editor, KeyCode::Char('a'), KeyModifiers::NONE);
+        assert_eq!(
+            editor.visible_search_matches(10),
+            [(0, 0, 1), (0, 3, 4), (1, 0, 1)]
+        );
+        press(&mut editor, KeyCode::Char('c'), KeyModifiers::CONTROL);
+        assert_eq!(editor.visible_search_matches(10), [(0, 0, 1), (1, 0, 1)]);
+        press(&mut editor, KeyCode::Char('b'), KeyModifiers::NONE);
+        assert_eq!(editor.visible_search_matches(10), [(0, 0, 2), (1, 0, 2)]);
+        press(&mut editor, KeyCode::Esc, KeyModifiers::NONE);
+        assert!(editor.visible_search_matches(10).is_empty());
+    }
+
+    #[test]
+    fn right_at_end_of_line_moves_to_the_next_line() {
+        let mut editor = test_editor(None, 80, 10);
+        editor.buffer.insert(0, "ab\ncd");
+        editor.cursor_col = 2;
+        press(&mut editor, KeyCode::Right, KeyModifiers::NONE);
+        assert_eq!((editor.cursor_line, editor.cursor_col), (1, 0));
+        press(&mut editor, KeyCode::Left, KeyModifiers::NONE);
+        assert_eq!((editor.cursor_line, editor.cursor_col), (0, 2));
+    }
+
+    #[test]
+    fn backspace_at_line_start_joins_lines_undoably() {
+        let mut editor = test_editor(None, 80, 10);
+        editor.buffer.insert(0, "ab\ncd");
+        editor.cursor_line = 1;
+        press(&mut editor, KeyCode::Backspace, KeyModifiers::NONE);
+        assert_eq!(editor.buffer.text.to_string(), "abcd\n");
+        assert_eq!((editor.cursor_line, editor.cursor_col), (0, 2));
+
+        press(&mut editor, KeyCode::Char('z'), KeyModifiers::CONTROL);
+        assert_eq!(editor.buffer.text.to_string(), "ab\ncd\n");
+        editor.cursor_line = 0;
+        editor.delete_line();
+        assert_eq!(editor.buffer.text.to_string(), "cd\n");
+    }
+
+    #[test]
+    fn block_edits_follow_display_columns_past_multibyte_text() {
+        let mut editor = test_editor(None, 80, 10);
+        editor.buffer.insert(0, "ax\néx");
+        editor.selection = Some(Selection::new(0, 1, SelectionKind::Block));
+        editor.cursor_line = 1;
+        editor.cursor_col = "é".len();
+
+        press(&mut editor, KeyCode::Char('y'), KeyModifiers::NONE);
+        assert_eq!(editor.buffer.text.to_string(), "ayx\néyx\n");
+        assert_eq!(editor.cursor_col, "éy".len());
+        press(&mut editor, KeyCode::Backspace, KeyModifiers::NONE);
+        press(&mut editor, KeyCode::Backspace, KeyModifiers::NONE);
+        assert_eq!(editor.buffer.text.to_string(), "x\nx\n");
+        assert_eq!((editor.cursor_line, editor.cursor_col), (1, 0));
+
+        press(&mut editor, KeyCode::Char('z'), KeyModifiers::CONTROL);
+        assert_eq!(editor.buffer.text.to_string(), "ax\néx\n");
+        press(&mut editor, KeyCode::Char('z'), KeyModifiers::CONTROL);
+        assert_eq!(editor.buffer.text.to_string(), "ayx\néyx\n");
+    }
+
+    #[test]
+    fn paste_replaces_the_selection_in_one_undo_step() {
+        let mut editor = test_editor(None, 80, 10);
+        editor.clipboard = Clipboard::local();
+        editor.buffer.insert(0, "one two");
+        press(&mut editor, KeyCode::Right, KeyModifiers::SHIFT);
+        press(&mut editor, KeyCode::Right, KeyModifiers::SHIFT);
+        press(&mut editor, KeyCode::Right, KeyModifiers::SHIFT);
+        press(&mut editor, KeyCode::Char('c'), KeyModifiers::CONTROL);
+        editor.cursor_col = 4;
+        editor.selection = Some(Selection::new(0, 7, SelectionKind::Linear));
+
+        press(&mut editor, KeyCode::Char('v'), KeyModifiers::CONTROL);
+        assert_eq!(editor.buffer.text.to_string(), "one one\n");
+        assert_eq!(editor.cursor_col, 7);
+        assert!(editor.selection.is_none());
+        press(&mut editor, KeyCode::Char('z'), KeyModifiers::CONTROL);
+        assert_eq!(editor.buffer.text.to_string(), "one two\n");
+    }
+
+    #[test]
+    fn insert_key_toggles_overwrite_mode() {
+        let mut editor = test_editor(None, 80, 24);
+        editor.buffer.insert(0, "abc");
+        press(&mut editor, KeyCode::Insert, KeyModifiers::NONE);
+        for c in "日xyz".chars() {
+            press(&mut editor, KeyCode::Char(c), KeyModifiers::NONE);
+        }
+        assert_eq!(editor.buffer.get_line(0), "日xyz");
+
+        press(&mut editor, KeyCode::Backspace, KeyModifiers::NONE);
+        assert_eq!(editor.buffer.get_line(0), "日xy");
+        // Undo the Backspace, the inserted "z", then the "y" that replaced "c".
+        for _ in 0..3 {
+            press(&mut editor, KeyCode::Char('z'), KeyModifiers::CONTROL);
+        }
+        assert_eq!(editor.buffer.get_line(0), "日xc");
+
+        press(&mut editor, KeyCode::Insert, KeyModifiers::NONE);
+        press(&mut editor, KeyCode::Home, KeyModifiers::NONE);
+        press(&mut editor, KeyCode::Char('a'), KeyModifiers::NONE);
+        assert_eq!(editor.buffer.get_line(0), "a日xc");
+    }
+
+    #[test]
+    fn ctrl_bracket_jumps_to_the_matching_bracket() {
+        let mut editor = test_editor(None, 80, 24);
+        editor.buffer.insert(0, "f(a, [b]\n  c)");
+        editor.cursor_col = 1;
+        press(&mut editor, KeyCode::Char(']'), KeyModifiers::CONTROL);
+        assert_eq!((editor.cursor_line, editor.cursor_col), (1, 3));
+        press(&mut editor, KeyCode::Char(']'), KeyModifiers::CONTROL);
+        assert_eq!((editor.cursor_line, editor.cursor_col), (0, 1));
+
+        editor.cursor_col = 3;
+        press(&mut editor, KeyCode::Char(']'), KeyModifiers::CONTROL);
+        assert_eq!((editor.cursor_line, editor.cursor_col), (0, 3));
+        assert_eq!(
+            editor.status_message.as_deref(),
+            Some("No matching bracket")
+        );
+    }
+
+    #[test]
+    fn ctrl_home_and_end_jump_to_the_ends_of_the_document() {
+        let mut editor = test_editor(None, 80, 10);
+        let text: String = (0..50).map(|i| format!("line {}\n", i)).collect();
+        editor.buffer.insert(0, &format!("{}last", text));
+        press(&mut editor, KeyCode::End, KeyModifiers::CONTROL);
+        assert_eq!((editor.cursor_line, editor.cursor_col), (50, 4));
+        assert!(editor.scroll_offset > 40);
+
+        press(&mut editor, KeyCode::Home, KeyModifiers::CONTROL);
+        assert_eq!((editor.cursor_line, editor.cursor_col), (0, 0));
+        assert_eq!(editor.scroll_offset, 0);
+    }
+
+    #[test]
+    fn undo_history_keeps_at_most_the_limit() {
+        let mut buffer = Buffer::new();
+        let mut undo = UndoHistory::new(2);
+        for (i, c) in ["a", "b", "c"].into_iter().enumerate() {
+            buffer.insert(i, c);
+            undo.push(EditOp::Insert {
+                pos: i,
+                text: c.to_string(),
+            });
+        }
+        assert!(undo.undo(&mut buffer));
+        assert!(undo.undo(&mut buffer));
+        assert!(!undo.undo(&mut buffer));
+        assert_eq!(buffer.get_line(0), "a");
+
+        let mut unlimited = UndoHistory::new(0);
+        for _ in 0..1500 {
+            unlimited.push(EditOp::Batch(Vec::new()));
+        }
+        assert_eq!(unlimited.ops.len(), 1500);
+    }
+
+    #[test]
+    fn saved_undo_history_is_only_restored_for_the_same_text() {
+        let mut buffer = Buffer::new();
+        let mut undo = UndoHistory::new(0);
+        buffer.insert(0, "ab");
+        undo.push(EditOp::Insert {
+            pos: 0,
+            text: "ab".into(),
+        });
+        buffer.delete(0, 1);
+        undo.push(EditOp::Batch(vec![EditOp::Delete {
+            pos: 0,
+            text: "a".into(),
+        }]));
+        let text = buffer.text.to_string();
+        let json = undo.to_json(&text);
+
+        assert!(UndoHistory::from_json(&json, "b\nchanged\n", 0).is_none());
+        let mut restored = UndoHistory::from_json(&json, &text, 0).unwrap();
+        assert!(restored.undo(&mut buffer));
+        assert!(restored.undo(&mut buffer));
+        assert_eq!(buffer.text.to_string(), "\n");
+        assert!(restored.redo(&mut buffer));
+        assert_eq!(buffer.get_line(0), "ab");
+    }
+
+    #[test]
+    fn auto_indent_follows_brackets() {
+        let mut editor = test_editor(None, 80, 24);
+        editor.settings.auto_close_brackets = false;
+        editor.buffer.insert(0, "  if x {");
+        editor.cursor_col = 8;
+        press(&mut editor, KeyCode::Enter, KeyModifiers::NONE);
+        assert_eq!(editor.buffer.get_line(1), "      ");
+        press(&mut editor, KeyCode::Char('a'), KeyModifiers::NONE);
+        press(&mut editor, KeyCode::Enter, KeyModifiers::NONE);
+        press(&mut editor, KeyCode::Char('}'), KeyModifiers::NONE);
+        assert_eq!(editor.buffer.get_line(2), "  }");
+        assert_eq!(editor.cursor_col, 3);
+
+        // Typing the closer and lining it up undo together, as does Enter
+        // with its indentation.
+        press(&mut editor, KeyCode::Char('z'), KeyModifiers::CONTROL);
+        assert_eq!(editor.buffer.get_line(2), "      ");
+        press(&mut editor, KeyCode::Char('z'), KeyModifiers::CONTROL);
+        assert_eq!(editor.buffer.num_lines(), 2);
+
+        // Between a pair, the closer gets a line of its own.
+        editor.buffer.insert(0, "f(x)\n");
+        editor.cursor_line = 0;
+        editor.cursor_col = 2;
+        press(&mut editor, KeyCode::Enter, KeyModifiers::NONE);
+        assert_eq!(editor.buffer.get_line(1), "    x)");
+        editor.buffer.delete(0, editor.buffer.total_len());
+        editor.buffer.insert(0, "f()");
+        editor.cursor_line = 0;
+        editor.cursor_col = 2;
+        press(&mut editor, KeyCode::Enter, KeyModifiers::NONE);
+        assert_eq!(editor.buffer.get_line(1), "    ");
+        assert_eq!(editor.buffer.get_line(2), ")");
+        assert_eq!((editor.cursor_line, editor.cursor_col), (1, 4));
+    }
+
+    #[test]
+    fn tab_indents_and_shift_tab_dedents_selected_lines() {
+        let mut editor = test_editor(None, 80, 24);
+        editor.settings.use_spaces = true;
+        editor.settings.indent_size = 4;
+        editor.buffer.insert(0, "a\n\n  b\nc");
+        editor.cursor_col = 1;
+        press(&mut editor, KeyCode::Down, KeyModifiers::SHIFT);
+        press(&mut editor, KeyCode::Down, KeyModifiers::SHIFT);
+        press(&mut editor, KeyCode::Down, KeyModifiers::SHIFT);
+        press(&mut editor, KeyCode::Home, KeyModifiers::SHIFT);
+        press(&mut editor, KeyCode::Tab, KeyModifiers::NONE);
+        assert_eq!(editor.buffer.text.to_string(), "    a\n\n      b\nc\n");
+        let selection = editor.selection.expect("selection kept");
+        assert_eq!((selection.anchor_line, selection.anchor_col), (0, 5));
+        assert_eq!((editor.cursor_line, editor.cursor_col), (3, 0));
+
+        press(&mut editor, KeyCode::BackTab, KeyModifiers::SHIFT);
+        press(&mut editor, KeyCode::BackTab, KeyModifiers::SHIFT);
+        assert_eq!(editor.buffer.text.to_string(), "a\n\nb\nc\n");
+        assert!(editor.selection.is_some());
+
+        press(&mut editor, KeyCode::Char('z'), KeyModifiers::CONTROL);
+        assert_eq!(editor.buffer.text.to_string(), "a\n\n  b\nc\n");
+    }
+
+    #[test]
+    fn home_toggles_between_indent_and_line_start() {
+        let mut editor = test_editor(None, 80, 24);
+        editor.buffer.insert(0, "\t\u{3000}日本\n    x");
+        editor.cursor_col = "\t\u{3000}日".len();
+        press(&mut editor, KeyCode::Home, KeyModifiers::NONE);
+        assert_eq!(editor.cursor_col, "\t\u{3000}".len());
+        press(&mut editor, KeyCode::Home, KeyModifiers::NONE);
+        assert_eq!(editor.cursor_col, 0);
+        press(&mut editor, KeyCode::Home, KeyModifiers::NONE);
+        assert_eq!(editor.cursor_col, "\t\u{3000}".len());
+
+        editor.cursor_line = 1;
+        editor.cursor_col = 2;
+        press(&mut editor, KeyCode::Home, KeyModifiers::NONE);
+        assert_eq!(editor.cursor_col, 4);
+    }
+
+    #[test]
+    fn extra_cursors_edit_every_line() {
+        let mut editor = test_editor(None, 80, 24);
+        editor.buffer.insert(0, "ab1\n日2\ncd3");
+        editor.cursor_col = 2;
+        let ctrl_alt = KeyModifiers::CONTROL | KeyModifiers::ALT;
+        press(&mut editor, KeyCode::Down, ctrl_alt);
+        press(&mut editor, KeyCode::Down, ctrl_alt);
+        assert_eq!(editor.extra_cursors, vec![(0, 2), (1, 3)]);
+
+        press(&mut editor, KeyCode::Char(','), KeyModifiers::NONE);
+        press(&mut editor, KeyCode::Char(' '), KeyModifiers::NONE);
+        assert_eq!(editor.buffer.text.to_string(), "ab, 1\n日, 2\ncd, 3\n");
+        press(&mut editor, KeyCode::Backspace, KeyModifiers::NONE);
+        press(&mut editor, KeyCode::Delete, KeyModifiers::NONE);
+        assert_eq!(editor.buffer.text.to_string(), "ab,\n日,\ncd,\n");
+        assert_eq!((editor.cursor_line, editor.cursor_col), (2, 3));
+
+        press(&mut editor, KeyCode::Char('z'), KeyModifiers::CONTROL);
+        assert_eq!(editor.buffer.text.to_string(), "ab,1\n日,2\ncd,3\n");
+        assert!(editor.extra_cursors.is_empty());
+    }
+
+    #[test]
+    fn marks_follow_edits_and_can_be_cycled() {
+        let mut editor = test_editor(None, 80, 24);
+        press(&mut editor, KeyCode::F(2), KeyModifiers::NONE);
+        assert_eq!(editor.status_message.as_deref(), Some("No marks"));
+
+        editor.buffer.insert(0, "a\nb\nc\nd");
+        editor.cursor_line = 1;
+        press(&mut editor, KeyCode::F(2), KeyModifiers::CONTROL);
+        editor.cursor_line = 3;
+        press(&mut editor, KeyCode::F(2), KeyModifiers::CONTROL);
+        assert_eq!(editor.buffer.marked_lines(), vec![1, 3]);
+
+        // Joining lines keeps a mark with its text; deleting a marked line
+        // drops the mark.
+        editor.buffer.insert(0, "x\n");
+        editor.buffer.delete(3, 1);
+        assert_eq!(editor.buffer.marked_lines(), vec![1, 3]);
+        editor.buffer.delete(6, 2);
+        assert_eq!(editor.buffer.text.to_string(), "x\nab\nc\n");
+        assert_eq!(editor.buffer.marked_lines(), vec![1]);
+
+        editor.buffer.toggle_mark(2);
+        editor.cursor_line = 2;
+        press(&mut editor, KeyCode::F(2), KeyModifiers::NONE);
+        assert_eq!(editor.cursor_line, 1);
+        press(&mut editor, KeyCode::F(2), KeyModifiers::SHIFT);
+        assert_eq!(editor.cursor_line, 2);
+        press(&mut editor, KeyCode::F(2), KeyModifiers::CONTROL);
+        assert_eq!(editor.buffer.marked_lines(), vec![1]);
+    }
+
+    #[test]
+    fn scrolling_keeps_lines_of_context_around_the_cursor() {
+        let mut editor = test_editor(None, 80, 24);
+        editor.settings.scroll_off = 3;
+        editor.buffer.insert(0, &"line\n".repeat(100));
+        let height = editor.view_height();
+        for _ in 0..height - 4 {
+            press(&mut editor, KeyCode::Down, KeyModifiers::NONE);
+        }
+        assert_eq!(editor.scroll_offset, 0);
+        press(&mut editor, KeyCode::Down, KeyModifiers::NONE);
+        assert_eq!(editor.scroll_offset, 1);
+
+        press(&mut editor, KeyCode::End, KeyModifiers::CONTROL);
+        assert_eq!(editor.scroll_offset, 101 - height);
+        for _ in 0..height - 4 {
+            press(&mut editor, KeyCode::Up, KeyModifiers::NONE);
+        }
+        assert_eq!(editor.scroll_offset, 101 - height);
+        press(&mut editor, KeyCode::Up, KeyModifiers::NONE);
+        assert_eq!(editor.scroll_offset, 100 - height);
+    }
+
+    #[test]
+    fn read_only_mode_refuses_edits_but_allows_navigation() {
+        let mut editor = test_editor(None, 80, 10);
+        editor.buffer.insert(0, "one\ntwo");
+        press(
+            &mut editor,
+            KeyCode::Char('r'),
+            KeyModifiers::ALT | KeyModifiers::SHIFT,
+        );
+        assert!(editor.read_only);
+
+        press(&mut editor, KeyCode::Char('x'), KeyModifiers::NONE);
+        press(&mut editor, KeyCode::Enter, KeyModifiers::NONE);
+        press(&mut editor, KeyCode::Char('k'), KeyModifiers::CONTROL);
+        press(&mut editor, KeyCode::Char('c'), KeyModifiers::CONTROL);
+        press(&mut editor, KeyCode::Char('z'), KeyModifiers::CONTROL);
+        assert_eq!(editor.buffer.get_line(0), "one");
+        assert_eq!(editor.buffer.num_lines(), 2);
+        assert_eq!(
+            editor.status_message.as_deref(),
+            Some("Buffer is read-only")
+        );
+
+        press(&mut editor, KeyCode::Down, KeyModifiers::NONE);
+        assert_eq!(editor.cursor_line, 1);
+
+        press(
+            &mut editor,
+            KeyCode::Char('R'),
+            KeyModifiers::ALT | KeyModifiers::SHIFT,
+        );
+        press(&mut editor, KeyCode::Char('x'), KeyModifiers::NONE);
+        assert_eq!(editor.buffer.get_line(1), "xtwo");
+    }
+
+    #[test]
+    fn read_only_mode_refuses_repeat_and_save_cleanup() {
+        let path =
+            std::env::temp_dir().join(format!("nova-test-{}-readonly.txt", std::process::id()));
+        std::fs::write(&path, "a  \nb\n").unwrap();
+        let mut editor = test_editor(Some(path.to_string_lossy().into_owned()), 80, 10);
+        editor.settings.trim_trailing_whitespace = true;
+        press(&mut editor, KeyCode::Char('x'), KeyModifiers::NONE);
+        editor.last_action = Some(RepeatableAction::DeleteLine);
+        editor.read_only = true;
+
+        assert!(editor.run_command("repeat_last", "").is_err());
+        assert_eq!(editor.buffer.text.to_string(), "xa  \nb\n");
+        press(&mut editor, KeyCode::Char('s'), KeyModifiers::CONTROL);
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "xa  \nb\n");
+        assert_eq!(editor.buffer.text.to_string(), "xa  \nb\n");
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn status_messages_outlive_keypresses_and_then_expire() {
+        let mut editor = test_editor(None, 80, 10);
+        press(&mut editor, KeyCode::F(2), KeyModifiers::NONE);
+        press(&mut editor, KeyCode::Right, KeyModifiers::NONE);
+        editor.expire_message();
+        assert_eq!(editor.status_message.as_deref(), Some("No marks"));
+
+        editor.message_time -= std::time::Duration::from_secs(MESSAGE_TIMEOUT_SECS);
+        editor.expire_message();
+        assert_eq!(editor.status_message, None);
+    }
+
+    #[test]
+    fn long_lines_scroll_sideways_without_jumping() {
+        let mut editor = test_editor(None, 40, 24);
+        editor.buffer.insert(0, &"x".repeat(100));
+        let width = editor.text_width();
+        press(&mut editor, KeyCode::End, KeyModifiers::NONE);
+        assert_eq!(editor.col_offset, 100 + SIDE_SCROLL_OFF + 1 - width);
+
+        let offset = editor.col_offset;
+        for _ in 0..width - 2 * SIDE_SCROLL_OFF - 1 {
+            press(&mut editor, KeyCode::Left, KeyModifiers::NONE);
+        }
+        assert_eq!(editor.col_offset, offset);
+        press(&mut editor, KeyCode::Left, KeyModifiers::NONE);
+        assert_eq!(editor.col_offset, offset - 1);
+
+        // Clicks land on the character shown under the mouse.
+        let left = editor.view_columns().start;
+        editor.handle_mouse(&MouseEvent {
+            kind: MouseEventKind::Down(MouseButton::Left),
+            column: left + 2,
+            row: 2,
+            modifiers: KeyModifiers::NONE,
+        });
+        assert_eq!(editor.cursor_col, offset + 1);
+    }
+
+    #[test]
+    fn split_view_keeps_a_cursor_per_pane() {
+        let mut editor = test_editor(None, 80, 24);
+        editor.buffer.insert(0, &"line\n".repeat(50));
+        let full = editor.view_height();
+
+        press(&mut editor, KeyCode::Char('\\'), KeyModifiers::ALT);
+        assert!(editor.view_height() < full / 2 + 1);
+        editor.run_command("goto_line", "40").unwrap();
+        press(&mut editor, KeyCode::Char('w'), KeyModifiers::ALT);
+        assert_eq!((editor.cursor_line, editor.split_bottom), (0, true));
+
+        // Both panes show the one buffer.
+        press(&mut editor, KeyCode::Char('x'), KeyModifiers::NONE);
+        press(&mut editor, KeyCode::Char('w'), KeyModifiers::ALT);
+        assert_eq!(editor.cursor_line, 39);
+        assert_eq!(editor.buffer.get_line(0), "xline");
+
+        // Clicking in the other pane focuses it, with its own scrolling.
+        let bottom_text = 2 + editor.tab_bar_height() + editor.pane_heights().0;
+        editor.handle_mouse(&MouseEvent {
+            kind: MouseEventKind::Down(MouseButton::Left),
+            column: 10,
+            row: bottom_text as u16 + 2,
+            modifiers: KeyModifiers::NONE,
+        });
+        assert!(editor.split_bottom);
+        assert_eq!(editor.cursor_line, 2);
+
+        press(&mut editor, KeyCode::Char('\\'), KeyModifiers::ALT);
+        assert_eq!((editor.view_height(), editor.split), (full, None));
+    }
+
+    #[test]
+    fn solid_cursor_never_blinks_and_idles_until_an_event() {
+        let mut editor = test_editor(None, 80, 24);
+        editor.status_message = None;
+        let past = std::time::Instant::now() - std::time::Duration::from_secs(1);
+        editor.settings.cursor_blink_ms = 100;
+        assert!(editor.poll_timeout() <= Some(std::time::Duration::from_millis(100)));
+        editor.last_cursor_time = past;
+        editor.update_cursor_blink();
+        assert!(!editor.cursor_blink_on);
+
+        for (style, ms) in [(CursorStyle::Solid, 100), (CursorStyle::Blink, 0)] {
+            editor.settings.cursor_style = style;
+            editor.settings.cursor_blink_ms = ms;
+            editor.last_cursor_time = past;
+            editor.update_cursor_blink();
+            assert!(editor.cursor_blink_on);
+            assert_eq!(editor.poll_timeout(), None);
+        }
+
+        // Other timers still wake the editor.
+        editor.set_message("hi".into());
+        assert!(editor.poll_timeout() > Some(std::time::Duration::from_secs(3)));
+    }
+
+    #[test]
+    fn tabs_expand_to_the_next_tab_stop() {
+        let mut editor = test_editor(None, 80, 24);
+        editor.settings.tab_width = 4;
+        editor.buffer.insert(0, "a\tx\nabcdefg");
+        editor.cursor_col = 2;
+        assert_eq!(editor.cursor_display_col(), 4);
+
+        press(&mut editor, KeyCode::Down, KeyModifiers::NONE);
+        assert_eq!((editor.cursor_line, editor.cursor_col), (1, 4));
+        press(&mut editor, KeyCode::Left, KeyModifiers::NONE);
+        press(&mut editor, KeyCode::Up, KeyModifiers::NONE);
+        assert_eq!((editor.cursor_line, editor.cursor_col), (0, 1));
+
+        // Clicking anywhere on the tab lands on it.
+        assert_eq!(editor.text_position(1 + 5 + 3, 2), (0, 1));
+
+        // Soft tabs follow indent_size, not the display width.
+        editor.settings.tab_width = 8;
+        editor.settings.indent_size = 2;
+        editor.cursor_col = 2;
+        assert_eq!(editor.cursor_display_col(), 8);
+        editor.cursor_col = 0;
+        press(&mut editor, KeyCode::Tab, KeyModifiers::NONE);
+        assert_eq!(editor.buffer.get_line(0), "  a\tx");
+    }
+
+    #[test]
+    fn tab_follows_the_detected_indentation() {
+        let mut editor = test_editor(None, 80, 24);
+        editor.buffer = Buffer::with_text("a {\n  b\n}\n".to_string());
+        editor.cursor_line = 1;
+        press(&mut editor, KeyCode::Tab, KeyModifiers::NONE);
+        assert_eq!(editor.buffer.get_line(1), "    b");
+        assert_eq!(editor.indent().to_string(), "Spaces: 2");
+
+        editor.settings.detect_indent = false;
+        editor.settings.use_spaces = false;
+        press(&mut editor, KeyCode::Tab, KeyModifiers::NONE);
+        assert_eq!(editor.buffer.get_line(1), "  \t  b");
+    }
+
+    #[test]
+    fn word_wrap_moves_by_screen_rows() {
+        // 12 cells of text beside the line numbers.
+        let mut editor = test_editor(None, 20, 10);
+        editor.word_wrap = true;
+        editor.buffer.insert(0, "abcdefghijklmnopqrstuvwxyz\nend");
+        editor.cursor_col = 14;
+
+        press(&mut editor, KeyCode::Up, KeyModifiers::NONE);
+        assert_eq!((editor.cursor_line, editor.cursor_col), (0, 2));
+        press(&mut editor, KeyCode::Down, KeyModifiers::NONE);
+        press(&mut editor, KeyCode::Down, KeyModifiers::NONE);
+        assert_eq!((editor.cursor_line, editor.cursor_col), (0, 26));
+        press(&mut editor, KeyCode::Down, KeyModifiers::NONE);
+        assert_eq!((editor.cursor_line, editor.cursor_col), (1, 2));
+
+        assert_eq!(editor.text_position(6 + 3, 3), (0, 15));
+        assert_eq!(editor.text_position(6 + 1, 5), (1, 1));
+    }
+
+    #[test]
+    fn keybindings_remap_actions() {
+        let mut editor = test_editor(None, 80, 24);
+        let overrides = [
+            ("undo", "alt+u"),
+            ("goto_line", "ctrl+shift+g"),
+            ("help", "Ctrl+Shift+G"),
+            ("bogus", "ctrl+j"),
+        ];
+        let overrides = overrides
+            .iter()
+            .map(|&(a, k)| (a.to_string(), k.to_string()))
+            .collect();
+        let (keymap, warnings) = keybindings::keymap(&overrides);
+        assert_eq!(
+            warnings,
+            [
+                "unknown action 'bogus'",
+                "'Ctrl+Shift+G' is bound to both goto_line and help"
+            ]
+        );
+        editor.keymap = keymap;
+
+        editor.insert_text("a");
+        press(&mut editor, KeyCode::Char('z'), KeyModifiers::CONTROL);
+        assert_eq!(editor.buffer.get_line(0), "a");
+        press(&mut editor, KeyCode::Char('u'), KeyModifiers::ALT);
+        assert_eq!(editor.buffer.get_line(0), "");
+
+        press(&mut editor, KeyCode::Char('g'), KeyModifiers::CONTROL);
+        assert!(editor.mode == EditorMode::Normal);
+        press(
+            &mut editor,
+            KeyCode::Char('G'),
+            KeyModifiers::CONTROL | KeyModifiers::SHIFT,
+        );
+        assert!(matches!(editor.mode, EditorMode::GoToLine { .. }));
+    }
+
+    #[test]
+    fn command_palette_filters_and_runs_commands() {
+        let mut editor = test_editor(None, 80, 24);
+        editor.word_wrap = false;
+        let type_text = |editor: &mut Editor, text: &str| {
+            for c in text.chars() {
+                press(editor, KeyCode::Char(c), KeyModifiers::NONE);
+            }
+        };
 
-        match k.code {
-            KeyCode::Esc => (None, true),
-            KeyCode::Enter => (None, true),
-            _ => (None, false),
+        press(&mut editor, KeyCode::Char('p'), KeyModifiers::CONTROL);
+        type_text(&mut editor, "tww");
+        assert_eq!(
+            palette_matches("tww"),
+            [("Toggle Word Wrap", "toggle_word_wrap")]
+        );
+        press(&mut editor, KeyCode::Enter, KeyModifiers::NONE);
+        assert!(editor.word_wrap);
+        assert!(editor.mode == EditorMode::Normal);
+
+        press(&mut editor, KeyCode::Char('p'), KeyModifiers::CONTROL);
+        type_text(&mut editor, "go to");
+        press(&mut editor, KeyCode::Enter, KeyModifiers::NONE);
+        assert!(matches!(editor.mode, EditorMode::GoToLine { .. }));
+    }
+
+    #[test]
+    fn save_as_with_new_extension_updates_language() {
+        let dir = std::env::temp_dir();
+        let txt = dir.join(format!("nova-test-{}-lang.txt", std::process::id()));
+        let rs = txt.with_extension("rs");
+
+        let mut editor = test_editor(None, 80, 24);
+        editor.replace_buffer(Buffer::for_new_file(txt));
+        assert_eq!(editor.buffer.language, "plaintext");
+
+        editor.pending_action = Some(PendingAction::SaveAs(rs.to_string_lossy().into_owned()));
+        press(&mut editor, KeyCode::Null, KeyModifiers::NONE);
+
+        assert_eq!(editor.buffer.language, "rust");
+        assert_eq!(editor.highlighter.language, "rust");
+        std::fs::remove_file(rs).ok();
+    }
+
+    #[test]
+    fn dialogs_render_on_tiny_terminals() {
+        use ratatui::backend::TestBackend;
+
+        for (w, h) in [(40, 10), (12, 5), (3, 3), (1, 1)] {
+            let mut editor = test_editor(None, w as usize, h as usize);
+            editor.mode = EditorMode::Confirm {
+                title: "Quit".into(),
+                message: "Save changes to this file before closing the editor?".into(),
+                options: vec!["Yes".into(), "No".into(), "Cancel".into()],
+                selected: 0,
+            };
+            let mut terminal = Terminal::new(TestBackend::new(w, h)).unwrap();
+            terminal.draw(|f| editor.render(f)).unwrap();
+
+            editor.open_help();
+            terminal.draw(|f| editor.render(f)).unwrap();
+
+            editor.mode = EditorMode::Normal;
+            editor.toggle_split();
+            terminal.draw(|f| editor.render(f)).unwrap();
         }
     }
 
-    fn handle_confirm_owned(
-        &mut self,
-        k: &event::KeyEvent,
-        title: String,
-        message: String,
-        options: Vec<String>,
-        mut selected: usize,
-    ) -> (String, String, Vec<String>, usize, Option<PendingAction>) {
-        self.cursor_blink_on = true;
-        self.last_cursor_time = std::time::Instant::now();
+    #[test]
+    fn resizing_keeps_the_cursor_and_help_in_view() {
+        use ratatui::backend::TestBackend;
 
-        let mut action = None;
-        match k.code {
-            KeyCode::Up => {
-                if selected > 0 {
-                    selected -= 1;
-                }
-            }
-            KeyCode::Down => {
-                if selected < options.len() - 1 {
-                    selected += 1;
-                }
-            }
-            KeyCode::Enter => match options[selected].as_str() {
-                "Yes" => {
-                    if self.buffer.path.is_some() {
-                        action = Some(PendingAction::SaveAndQuit);
-                    } else {
-                        self.quit_after_save = true;
-                        return (title, message, options, selected, action);
-                    }
-                }
-                "No" => {
-                    action = Some(PendingAction::QuitWithoutSave);
-                }
-                _ => {}
-            },
-            KeyCode::Esc => {}
-            _ => {}
+        let mut editor = test_editor(None, 80, 24);
+        editor.buffer.insert(0, &"line\n".repeat(50));
+        editor.run_command("goto_line", "15").unwrap();
+        editor.resize(80, 8);
+        assert!(editor.cursor_line < editor.scroll_offset + editor.view_height());
+
+        editor.resize(30, 10);
+        editor.open_help();
+        for _ in 0..100 {
+            press(&mut editor, KeyCode::Down, KeyModifiers::NONE);
         }
-        (title, message, options, selected, action)
+        let scroll = editor.help_scroll;
+        assert_eq!(scroll, editor.help_max_scroll());
+        editor.resize(80, 24);
+        assert!(editor.help_scroll < scroll);
+        assert_eq!(editor.help_scroll, editor.help_max_scroll());
+        let mut terminal = Terminal::new(TestBackend::new(80, 24)).unwrap();
+        terminal.draw(|f| editor.render(f)).unwrap();
     }
 
-    fn handle_input_owned(
-        &mut self,
-        k: &event::KeyEvent,
-        title: String,
-        mut input: String,
-        mut history: Vec<String>,
-    ) -> (String, String, Vec<String>, Option<PendingAction>) {
-        self.cursor_blink_on = true;
-        self.last_cursor_time = std::time::Instant::now();
+    #[test]
+    fn ctrl_n_counts_search_matches_without_moving() {
+        let mut editor = test_editor(None, 80, 24);
+        editor.buffer.insert(0, "Foo foo\nbar foo");
+        editor.run_command("search", "foo").unwrap();
+        press(&mut editor, KeyCode::Char('n'), KeyModifiers::CONTROL);
+        assert_eq!(editor.status_message.as_deref(), Some("3 matches"));
+        assert_eq!((editor.cursor_line, editor.cursor_col), (0, 0));
 
-        let mut action = None;
-        match k.code {
-            KeyCode::Enter => {
-                action = Some(PendingAction::SaveAs(input.clone()));
-                if !input.is_empty() {
-                    history.push(input.clone());
-                }
+        press(&mut editor, KeyCode::Char('c'), KeyModifiers::CONTROL);
+        press(&mut editor, KeyCode::Char('n'), KeyModifiers::CONTROL);
+        assert_eq!(editor.status_message.as_deref(), Some("2 matches"));
+        assert!(matches!(editor.mode, EditorMode::Search { .. }));
+    }
+
+    #[test]
+    fn insert_datetime_uses_the_configured_format() {
+        let mut editor = test_editor(None, 80, 24);
+        editor.buffer.insert(0, "ab");
+        editor.cursor_col = 1;
+        editor.settings.datetime_format = "[%%]".to_string();
+        editor.run_command("insert_datetime", "").unwrap();
+        assert_eq!(editor.buffer.get_line(0), "a[%]b");
+        assert_eq!(editor.cursor_col, 4);
+
+        editor.settings.datetime_format = "%Y-%m-%d".to_string();
+        editor.run_command("insert_datetime", "").unwrap();
+        let date = &editor.buffer.get_line(0)[4..14];
+        assert!(date.bytes().enumerate().all(|(i, b)| if i == 4 || i == 7 {
+            b == b'-'
+        } else {
+            b.is_ascii_digit()
+        }));
+
+        editor.settings.datetime_format = "%Q".to_string();
+        assert!(editor.run_command("insert_datetime", "").is_err());
+        editor.run_command("undo", "").unwrap();
+        assert_eq!(editor.buffer.get_line(0), "a[%]b");
+    }
+
+    #[test]
+    fn stats_count_the_buffer_and_the_selection() {
+        let mut editor = test_editor(None, 80, 24);
+        editor.buffer.insert(0, "one two\nthree é");
+        let value = |editor: &Editor, label: &str| match &editor.mode {
+            EditorMode::Stats { rows } => rows
+                .iter()
+                .find(|(l, _)| l == label)
+                .map(|(_, v)| v.clone()),
+            _ => panic!("statistics are not open"),
+        };
+
+        press(&mut editor, KeyCode::Char('I'), KeyModifiers::CONTROL);
+        assert_eq!(value(&editor, "Lines").as_deref(), Some("2"));
+        assert_eq!(value(&editor, "Words").as_deref(), Some("4"));
+        assert_eq!(value(&editor, "Characters").as_deref(), Some("16"));
+        assert_eq!(value(&editor, "Bytes").as_deref(), Some("17"));
+        assert_eq!(value(&editor, "Selected words"), None);
+
+        press(&mut editor, KeyCode::Esc, KeyModifiers::NONE);
+        press(&mut editor, KeyCode::End, KeyModifiers::SHIFT);
+        press(&mut editor, KeyCode::Char('I'), KeyModifiers::CONTROL);
+        assert_eq!(value(&editor, "Selected lines").as_deref(), Some("1"));
+        assert_eq!(value(&editor, "Selected words").as_deref(), Some("2"));
+        assert_eq!(value(&editor, "Selected characters").as_deref(), Some("7"));
+        assert!(editor.selection.is_some());
+    }
+
+    #[test]
+    fn replace_can_be_limited_to_the_selection() {
+        let mut editor = test_editor(None, 80, 24);
+        editor.buffer.insert(0, "foo\nfoo foo\nfoo");
+        editor.cursor_line = 1;
+        press(&mut editor, KeyCode::End, KeyModifiers::SHIFT);
+        let replace = |editor: &mut Editor, toggles: &str, keys: &str| {
+            press(editor, KeyCode::Char('\\'), KeyModifiers::CONTROL);
+            for c in toggles.chars() {
+                press(editor, KeyCode::Char(c), KeyModifiers::CONTROL);
             }
-            KeyCode::Esc => {}
-            KeyCode::Backspace => {
-                input.pop();
+            for c in "foo".chars() {
+                press(editor, KeyCode::Char(c), KeyModifiers::NONE);
             }
-            KeyCode::Char(c) if !c.is_control() => {
-                input.push(c);
+            press(editor, KeyCode::Enter, KeyModifiers::NONE);
+            for c in "ab".chars() {
+                press(editor, KeyCode::Char(c), KeyModifiers::NONE);
             }
-            KeyCode::Tab => {
-                input.push('\t');
+            press(editor, KeyCode::Enter, KeyModifiers::NONE);
+            for c in keys.chars() {
+                press(editor, KeyCode::Char(c), KeyModifiers::NONE);
             }
-            _ => {}
-        }
-        (title, input, history, action)
+        };
+
+        // Stepping through matches stops at the end of the selection, which
+        // moves as matches before it change length.
+        replace(&mut editor, "", "yy");
+        assert_eq!(editor.buffer.text.to_string(), "foo\nab ab\nfoo\n");
+        assert!(editor.mode == EditorMode::Normal);
+
+        // Turned off, replacing all covers the whole buffer.
+        press(&mut editor, KeyCode::Char('z'), KeyModifiers::CONTROL);
+        press(&mut editor, KeyCode::Char('z'), KeyModifiers::CONTROL);
+        editor.cursor_line = 1;
+        editor.cursor_col = 0;
+        press(&mut editor, KeyCode::End, KeyModifiers::SHIFT);
+        replace(&mut editor, "la", "");
+        assert_eq!(editor.buffer.text.to_string(), "ab\nab ab\nab\n");
     }
 
-    fn open_file(&mut self) {
-        if let Ok(ent) = std::fs::read_dir(".") {
-            for e in ent
-                .filter_map(|e| e.ok())
-                .filter(|e| e.path().is_file())
-                .take(10)
-            {
-                if let Some(ext) = e.path().extension() {
-                    let ext_str = ext.to_string_lossy().to_lowercase();
-                    let known_exts = [
-                        "txt", "rs", "js", "ts", "py", "go", "md", "json", "toml", "yaml", "c",
-                        "h", "cpp", "hpp", "sh", "bash", "zsh", "html", "css", "xml",
-                    ];
-                    if known_exts.contains(&ext_str.as_str()) {
-                        if let Some(b) = Buffer::from_file(e.path()) {
-                            self.buffer = b;
-                            self.cursor_line = 0;
-                            self.cursor_col = 0;
-                            self.scroll_offset = 0;
-                            self.undo.clear();
-                            break;
-                        }
-                    }
-                }
-            }
-        }
+    #[test]
+    fn replace_all_can_be_undone_and_redone() {
+        let mut editor = test_editor(None, 80, 24);
+        editor.buffer.insert(0, "foo bar foo\nfoo");
+        editor.insert_text("!");
+        editor.replace_all(&Pattern::literal("foo", true), "quux", 0);
+        assert_eq!(editor.buffer.text.to_string(), "!quux bar quux\nquux\n");
+
+        press(&mut editor, KeyCode::Char('z'), KeyModifiers::CONTROL);
+        assert_eq!(editor.buffer.text.to_string(), "!foo bar foo\nfoo\n");
+        press(&mut editor, KeyCode::Char('y'), KeyModifiers::CONTROL);
+        assert_eq!(editor.buffer.text.to_string(), "!quux bar quux\nquux\n");
+        press(&mut editor, KeyCode::Char('z'), KeyModifiers::CONTROL);
+        press(&mut editor, KeyCode::Char('z'), KeyModifiers::CONTROL);
+        assert_eq!(editor.buffer.text.to_string(), "foo bar foo\nfoo\n");
     }
 
-    fn render(&self, f: &mut ratatui::Frame) {
-        let a = f.area();
-        let th = 1u16;
-        let hh = if self.show_help { 1u16 } else { 0u16 };
-        let sh = 1u16;
-        let eh = a.height.saturating_sub(th + hh + sh);
+    #[test]
+    fn replace_all_keeps_cursor_on_the_same_text() {
+        let mut editor = test_editor(None, 80, 24);
+        editor.buffer.insert(0, "aaaa\naaaa\nx aaaa y");
+        editor.cursor_line = 2;
+        editor.cursor_col = 7;
+        editor.replace_all(&Pattern::literal("aaaa", true), "", 0);
+        assert_eq!(editor.buffer.text.to_string(), "\n\nx  y\n");
+        assert_eq!((editor.cursor_line, editor.cursor_col), (2, 3));
 
-        let ta = Rect::new(a.x, a.y, a.width, th);
-        let modified_indicator = if self.buffer.is_modified {
-            " [Modified]"
-        } else {
-            ""
-        };
-        f.render_widget(
-            TitleBar {
-                file_name: format!(" Nova - {}{} ", self.buffer.file_name(), modified_indicator),
-                theme: self.theme.clone(),
-            },
-            ta,
+        editor.cursor_col = 2;
+        editor.replace_all(&Pattern::literal("x  y", true), "z", 0);
+        assert_eq!((editor.cursor_line, editor.cursor_col), (2, 0));
+    }
+
+    #[test]
+    fn ctrl_backspace_deletes_words_and_joins_lines() {
+        let mut editor = test_editor(None, 80, 24);
+        editor.buffer.insert(0, "let foo = 1;\n  bar");
+        editor.cursor_line = 1;
+        editor.cursor_col = 2;
+
+        press(&mut editor, KeyCode::Backspace, KeyModifiers::CONTROL);
+        assert_eq!(editor.buffer.text.to_string(), "let foo = 1;\nbar\n");
+        press(&mut editor, KeyCode::Backspace, KeyModifiers::CONTROL);
+        assert_eq!(editor.buffer.text.to_string(), "let foo = 1;bar\n");
+        assert_eq!((editor.cursor_line, editor.cursor_col), (0, 12));
+        press(&mut editor, KeyCode::Delete, KeyModifiers::CONTROL);
+        assert_eq!(editor.buffer.text.to_string(), "let foo = 1;\n");
+
+        press(&mut editor, KeyCode::Char('z'), KeyModifiers::CONTROL);
+        assert_eq!(editor.buffer.text.to_string(), "let foo = 1;bar\n");
+    }
+
+    #[test]
+    fn ctrl_slash_toggles_comments_as_one_undo_step() {
+        let mut editor = test_editor(None, 80, 24);
+        editor.highlighter.set_language("rust");
+        editor.buffer.insert(0, "    a\n\n    b");
+        editor.selection = Some(Selection::new(0, 0, SelectionKind::Linear));
+        editor.cursor_line = 2;
+
+        press(&mut editor, KeyCode::Char('/'), KeyModifiers::CONTROL);
+        assert_eq!(editor.buffer.text.to_string(), "    // a\n\n    // b\n");
+        press(&mut editor, KeyCode::Char('7'), KeyModifiers::CONTROL);
+        assert_eq!(editor.buffer.text.to_string(), "    a\n\n    b\n");
+
+        press(&mut editor, KeyCode::Char('z'), KeyModifiers::CONTROL);
+        assert_eq!(editor.buffer.text.to_string(), "    // a\n\n    // b\n");
+    }
+
+    #[test]
+    fn stdout_mode_saves_untitled_buffers_for_stdout() {
+        let mut editor = test_editor(None, 80, 24);
+        editor.to_stdout = true;
+        press(&mut editor, KeyCode::Char('h'), KeyModifiers::NONE);
+        press(&mut editor, KeyCode::Char('q'), KeyModifiers::CONTROL);
+        assert!(matches!(editor.mode, EditorMode::Confirm { .. }));
+        press(&mut editor, KeyCode::Enter, KeyModifiers::NONE);
+        assert_eq!(editor.stdout_text.as_deref(), Some("h\n"));
+        assert!(editor.should_quit);
+    }
+
+    #[test]
+    fn file_arguments_can_name_a_line_and_column() {
+        assert_eq!(
+            parse_file_arg("no/such/main.rs:42"),
+            ("no/such/main.rs".into(), Some((42, None)))
+        );
+        assert_eq!(
+            parse_file_arg("no/such/main.rs:42:7"),
+            ("no/such/main.rs".into(), Some((42, Some(7))))
         );
+        assert_eq!(
+            parse_file_arg("no/such/a:b:3"),
+            ("no/such/a:b".into(), Some((3, None)))
+        );
+        assert_eq!(parse_file_arg("no/such/a:b"), ("no/such/a:b".into(), None));
 
-        let sa = Rect::new(a.x, a.y + th + eh, a.width, sh);
-        let status_text = match &self.mode {
-            EditorMode::Search { query, .. } => format!("Search: {}", query),
-            EditorMode::Replace {
-                search,
-                replace,
-                confirmed,
-                ..
-            } => {
-                if *confirmed {
-                    format!(
-                        "Replace '{}' with '{}'? [Enter=Yes, A=all, C=cancel]",
-                        search, replace
-                    )
-                } else {
-                    format!("Replace: {} -> {}", search, replace)
-                }
-            }
-            EditorMode::GoToLine => "Go to line:".to_string(),
-            EditorMode::Confirm { title, message, .. } => format!("{} - {}", title, message),
-            EditorMode::Input { title, input, .. } => format!("{}: {}", title, input),
-            _ => format!("Ln {}, Col {}", self.cursor_line + 1, self.cursor_col + 1),
-        };
-        f.render_widget(
-            StatusBar {
-                file_name: self.buffer.file_name(),
-                modified: self.buffer.is_modified,
-                line: self.cursor_line + 1,
-                col: self.cursor_col + 1,
-                language: self.buffer.language.clone(),
-                theme: self.theme.clone(),
-                search_mode: !matches!(self.mode, EditorMode::Normal),
-                search_text: status_text,
-            },
-            sa,
+        let dir = std::env::temp_dir().join(format!("nova-test-{}-colon", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let file = dir.join("notes:12");
+        std::fs::write(&file, "").unwrap();
+        let arg = file.to_string_lossy().into_owned();
+        assert_eq!(parse_file_arg(&arg), (arg.clone(), None));
+        std::fs::remove_dir_all(dir).unwrap();
+
+        let mut editor = test_editor(None, 80, 24);
+        editor.buffer.insert(0, "a\nb\nnaïve text");
+        editor.goto_position(3, Some(5));
+        assert_eq!((editor.cursor_line, editor.cursor_col), (2, 5));
+    }
+
+    #[test]
+    fn alt_left_and_right_walk_the_jump_list() {
+        let mut editor = test_editor(None, 80, 24);
+        editor.buffer.insert(0, "a\nb\nc\nd\ne");
+        let back = |editor: &mut Editor| press(editor, KeyCode::Left, KeyModifiers::ALT);
+        let forward = |editor: &mut Editor| press(editor, KeyCode::Right, KeyModifiers::ALT);
+
+        editor.run_command("goto_line", "3").unwrap();
+        editor.run_command("goto_line", "5").unwrap();
+        back(&mut editor);
+        assert_eq!(editor.cursor_line, 2);
+        back(&mut editor);
+        assert_eq!(editor.cursor_line, 0);
+        back(&mut editor);
+        assert_eq!(
+            editor.status_message.as_deref(),
+            Some("No earlier position")
         );
+        forward(&mut editor);
+        forward(&mut editor);
+        assert_eq!(editor.cursor_line, 4);
 
-        if self.show_help {
-            let ha = Rect::new(a.x, a.y + th + eh + sh, a.width, hh);
-            f.render_widget(
-                HelpBar {
-                    shortcuts: vec![
-                        ("Ctrl+H", "Help"),
-                        ("Ctrl+O", "Open"),
-                        ("Ctrl+S", "Save"),
-                        ("Ctrl+F", "Find"),
-                    ],
-                    visible: true,
-                    theme: self.theme.clone(),
-                    tip: self.current_tip.clone(),
-                },
-                ha,
-            );
-        }
+        // A position past the end after an edit lands on the last line.
+        back(&mut editor);
+        let len = editor.buffer.total_len();
+        editor.buffer.delete(4, len - 4);
+        forward(&mut editor);
+        assert_eq!((editor.cursor_line, editor.cursor_col), (1, 0));
+    }
 
-        if self.mode == EditorMode::Help {
-            self.render_help(f, a);
-            return;
+    #[test]
+    fn goto_line_accepts_digits_and_clamps() {
+        let mut editor = test_editor(None, 80, 24);
+        editor.buffer.insert(0, "a\nb\nc\nd");
+
+        press(&mut editor, KeyCode::Char('g'), KeyModifiers::CONTROL);
+        for c in ['3', 'x', '9', '9'] {
+            press(&mut editor, KeyCode::Char(c), KeyModifiers::NONE);
         }
+        press(&mut editor, KeyCode::Backspace, KeyModifiers::NONE);
+        assert!(matches!(&editor.mode, EditorMode::GoToLine { input } if input == "39"));
+        press(&mut editor, KeyCode::Enter, KeyModifiers::NONE);
+        assert!(editor.mode == EditorMode::Normal);
+        assert_eq!(editor.cursor_line, 3);
 
-        let ea = Rect::new(a.x, a.y + th, a.width, eh);
-        f.render_widget(
-            EditorView {
-                buffer: self.buffer.clone(),
-                cursor_line: self.cursor_line,
-                cursor_col: self.cursor_col,
-                show_line_numbers: self.show_line_numbers,
-                scroll_offset: self.scroll_offset,
-                theme: self.theme.clone(),
-                cursor_blink_on: self.cursor_blink_on,
-                word_wrap: self.word_wrap,
-                width: self.screen_width as u16,
-            },
-            ea,
+        press(&mut editor, KeyCode::Char('g'), KeyModifiers::CONTROL);
+        press(&mut editor, KeyCode::Char('2'), KeyModifiers::NONE);
+        press(&mut editor, KeyCode::Enter, KeyModifiers::NONE);
+        assert_eq!(editor.cursor_line, 1);
+    }
+
+    #[test]
+    fn mouse_drag_selects_and_scrolls() {
+        let mut editor = test_editor(None, 80, 24);
+        editor.buffer.insert(0, &"line\n".repeat(40));
+        let mouse = |kind, column, row| MouseEvent {
+            kind,
+            column,
+            row,
+            modifiers: KeyModifiers::NONE,
+        };
+        // The text starts after the border and a five column gutter.
+        editor.handle_mouse(&mouse(MouseEventKind::Down(MouseButton::Left), 7, 2));
+        assert_eq!((editor.cursor_line, editor.cursor_col), (0, 1));
+        assert!(editor.selection.is_none());
+
+        editor.handle_mouse(&mouse(MouseEventKind::Drag(MouseButton::Left), 9, 3));
+        let selection = editor.selection.unwrap();
+        assert_eq!((selection.anchor_line, selection.anchor_col), (0, 1));
+        assert_eq!((editor.cursor_line, editor.cursor_col), (1, 3));
+
+        for _ in 0..5 {
+            editor.handle_mouse(&mouse(MouseEventKind::Drag(MouseButton::Left), 9, 23));
+        }
+        assert_eq!(editor.scroll_offset, 5);
+        assert_eq!(
+            editor.cursor_line,
+            editor.scroll_offset + editor.view_height() - 1
         );
+    }
+
+    #[test]
+    fn open_prompt_browses_and_opens_files() {
+        let dir = std::env::temp_dir().join(format!("nova-test-{}-browse", std::process::id()));
+        std::fs::create_dir_all(dir.join("sub")).unwrap();
+        std::fs::write(dir.join("a.txt"), "hello\n").unwrap();
 
-        if let EditorMode::Input { title, input, .. } = &self.mode {
-            self.render_input_dialog(f, a, title, input);
-        } else if let EditorMode::GoToLine = &self.mode {
-            self.render_input_dialog(f, a, "Go to Line", "");
+        let mut editor = test_editor(None, 80, 24);
+        editor.buffer.path = Some(dir.join("a.txt"));
+        // An empty path browses the current file's directory.
+        press(&mut editor, KeyCode::Char('o'), KeyModifiers::CONTROL);
+        press(&mut editor, KeyCode::Enter, KeyModifiers::NONE);
+        let names = |editor: &Editor| match &editor.mode {
+            EditorMode::FileBrowser { entries, .. } => {
+                entries.iter().map(|e| e.name.clone()).collect::<Vec<_>>()
+            }
+            _ => Vec::new(),
+        };
+        assert_eq!(names(&editor), ["..", "sub", "a.txt"]);
+
+        press(&mut editor, KeyCode::Down, KeyModifiers::NONE);
+        press(&mut editor, KeyCode::Enter, KeyModifiers::NONE);
+        assert_eq!(names(&editor), [".."]);
+        press(&mut editor, KeyCode::Enter, KeyModifiers::NONE);
+        press(&mut editor, KeyCode::Down, KeyModifiers::NONE);
+        press(&mut editor, KeyCode::Enter, KeyModifiers::NONE);
+        assert!(editor.mode == EditorMode::Normal);
+        assert_eq!(editor.buffer.text.to_string(), "hello\n");
+
+        press(&mut editor, KeyCode::Char('o'), KeyModifiers::CONTROL);
+        for c in dir.join("new.rs").to_string_lossy().chars() {
+            press(&mut editor, KeyCode::Char(c), KeyModifiers::NONE);
         }
+        press(&mut editor, KeyCode::Enter, KeyModifiers::NONE);
+        assert_eq!(editor.buffer.path, Some(dir.join("new.rs")));
+        assert_eq!(editor.buffer.language, "rust");
+
+        std::fs::remove_dir_all(dir).unwrap();
     }
 
-    fn render_help(&self, f: &mut ratatui::Frame, area: Rect) {
-        let dw = 60u16;
-        let dh = 20u16;
-        let dx = (area.width.saturating_sub(dw)) / 2;
-        let dy = (area.height.saturating_sub(dh)) / 2;
-        let dr = Rect::new(area.x + dx, area.y + dy, dw, dh);
-
-        let bp = ratatui::widgets::Block::default()
-            .title(" Help - Press Ctrl+H or ESC to close ")
-            .borders(ratatui::widgets::Borders::ALL)
-            .border_type(ratatui::widgets::BorderType::Double)
-            .style(
-                Style::default()
-                    .bg(self.theme.background)
-                    .fg(self.theme.foreground),
-            );
-        f.render_widget(bp, dr);
+    #[test]
+    fn opening_asks_to_save_unsaved_changes() {
+        let dir = std::env::temp_dir().join(format!("nova-test-{}-open", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let first = dir.join("first.txt");
+        let second = dir.join("second.txt");
+        std::fs::write(&first, "one\n").unwrap();
+        std::fs::write(&second, "two\n").unwrap();
 
-        let content = "Key          Action              Key          Action\n\
-             ------------------------------------------------\n\
-             Ctrl+O       Open file           Ctrl+Z       Undo\n\
-             Ctrl+S       Save file           Ctrl+Y       Redo\n\
-             Ctrl+F       Find text           Ctrl+T       Change theme\n\
-             Ctrl+G       Go to line          Ctrl+B       Toggle lines\n\
-             Ctrl+\\       Replace             Ctrl+W       Toggle wrap\n\
-             Ctrl+Q       Quit                Ctrl+H       Help";
+        let mut editor = test_editor(Some(first.to_string_lossy().into_owned()), 80, 24);
+        editor.insert_text("x");
+        editor
+            .run_command("open", &second.to_string_lossy())
+            .unwrap();
+        assert!(matches!(&editor.mode, EditorMode::Confirm { title, .. } if title == "Open"));
 
-        let tr = dr.inner(Margin::new(1, 1));
-        f.render_widget(
-            Paragraph::new(content)
-                .style(
-                    Style::default()
-                        .bg(self.theme.background)
-                        .fg(self.theme.foreground),
-                )
-                .wrap(ratatui::widgets::Wrap { trim: true }),
-            tr,
-        );
+        // Cancel keeps the buffer; Save writes it before opening.
+        press(&mut editor, KeyCode::Esc, KeyModifiers::NONE);
+        assert_eq!(editor.buffer.path.as_ref(), Some(&first));
+        editor
+            .run_command("open", &second.to_string_lossy())
+            .unwrap();
+        press(&mut editor, KeyCode::Enter, KeyModifiers::NONE);
+        assert_eq!(std::fs::read_to_string(&first).unwrap(), "xone\n");
+        assert_eq!(editor.buffer.path.as_ref(), Some(&second));
+
+        std::fs::remove_dir_all(dir).unwrap();
     }
 
-    fn render_input_dialog(&self, f: &mut ratatui::Frame, area: Rect, title: &str, input: &str) {
-        let dw = 30u16;
-        let dh = 3u16;
-        let dx = (area.width.saturating_sub(dw)) / 2;
-        let dy = (area.height.saturating_sub(dh)) / 2;
-        let dr = Rect::new(area.x + dx, area.y + dy, dw, dh);
+    #[test]
+    fn tabs_keep_their_own_cursor_and_undo() {
+        let mut editor = test_editor(None, 80, 24);
+        editor.insert_text("first");
+        press(&mut editor, KeyCode::Char('n'), KeyModifiers::CONTROL);
+        editor.insert_text("second");
+        assert_eq!((editor.active, editor.tab_count()), (1, 2));
 
-        let bp = ratatui::widgets::Block::default()
-            .title(format!(" {} ", title))
-            .borders(ratatui::widgets::Borders::ALL)
-            .border_type(ratatui::widgets::BorderType::Double)
-            .style(
-                Style::default()
-                    .bg(self.theme.background)
-                    .fg(self.theme.foreground),
-            );
-        f.render_widget(bp, dr);
+        press(&mut editor, KeyCode::PageDown, KeyModifiers::CONTROL);
+        assert_eq!(editor.active, 0);
+        assert_eq!(editor.buffer.text.to_string(), "first\n");
+        assert_eq!(editor.cursor_col, 5);
+        press(&mut editor, KeyCode::Char('z'), KeyModifiers::CONTROL);
+        assert_eq!(editor.buffer.text.to_string(), "\n");
 
-        let tr = dr.inner(Margin::new(1, 1));
-        f.render_widget(
-            Paragraph::new(input.to_string()).style(
-                Style::default()
-                    .bg(self.theme.background)
-                    .fg(self.theme.foreground),
-            ),
-            tr,
-        );
+        press(&mut editor, KeyCode::PageUp, KeyModifiers::CONTROL);
+        assert_eq!(editor.buffer.text.to_string(), "second\n");
+        editor.buffer.is_modified = false;
+        editor.close_buffer();
+        assert_eq!((editor.active, editor.tab_count()), (0, 1));
+        assert!(!editor.should_quit);
     }
-}
 
-fn run(initial_file: Option<String>) -> io::Result<()> {
-    enable_raw_mode()?;
-    let mut o = stdout();
-    o.execute(EnterAlternateScreen)?;
-    let b = CrosstermBackend::new(o);
-    let mut t = Terminal::new(b)?;
+    #[test]
+    fn saving_trims_whitespace_and_adds_final_newline() {
+        let path = std::env::temp_dir().join(format!("nova-test-{}-trim.txt", std::process::id()));
+        std::fs::write(&path, "a b  \n\t\n  c\t \nd\n").unwrap();
+        let mut editor = test_editor(Some(path.to_string_lossy().into_owned()), 80, 24);
+        editor.settings.trim_trailing_whitespace = true;
+        editor.cursor_line = 2;
+        editor.cursor_col = 5;
+        press(&mut editor, KeyCode::Char('s'), KeyModifiers::CONTROL);
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "a b\n\n  c\nd\n");
+        assert_eq!((editor.cursor_line, editor.cursor_col), (2, 3));
 
-    let (width, height) = size().unwrap_or((80, 24));
+        press(&mut editor, KeyCode::Char('z'), KeyModifiers::CONTROL);
+        assert_eq!(editor.buffer.text.to_string(), "a b  \n\t\n  c\t \nd\n");
 
-    let mut e = Editor::new(initial_file, width as usize, height as usize);
+        std::fs::write(&path, "no newline").unwrap();
+        editor.settings.insert_final_newline = false;
+        press(&mut editor, KeyCode::Char('r'), KeyModifiers::CONTROL);
+        press(&mut editor, KeyCode::Enter, KeyModifiers::NONE);
+        press(&mut editor, KeyCode::Char('s'), KeyModifiers::CONTROL);
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "no newline");
+        editor.settings.insert_final_newline = true;
+        press(&mut editor, KeyCode::Char('s'), KeyModifiers::CONTROL);
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "no newline\n");
 
-    loop {
-        t.draw(|f| e.render(f))?;
+        std::fs::remove_file(path).unwrap();
+    }
 
-        if event::poll(std::time::Duration::from_millis(16))? {
-            match event::read() {
-                Ok(Event::Key(k)) => {
-                    if k.kind == KeyEventKind::Press {
-                        e.handle_key(&k);
-                    }
-                }
-                Ok(Event::Resize(w, h)) => {
-                    e.screen_width = w as usize;
-                    e.screen_height = h as usize;
-                }
-                _ => {}
-            }
-        }
+    #[test]
+    fn auto_save_writes_modified_tabs_with_a_path() {
+        let dir = std::env::temp_dir();
+        let first = dir.join(format!("nova-test-{}-autosave-a.txt", std::process::id()));
+        let second = dir.join(format!("nova-test-{}-autosave-b.txt", std::process::id()));
+        std::fs::write(&first, "a\n").unwrap();
+        std::fs::write(&second, "b\n").unwrap();
+        let mut editor = test_editor(Some(first.to_string_lossy().into_owned()), 80, 24);
+        editor.settings.auto_save = true;
+        editor.settings.auto_save_interval_secs = 0;
+        press(&mut editor, KeyCode::Char('x'), KeyModifiers::NONE);
+        editor.open_tab(Buffer::new());
+        press(&mut editor, KeyCode::Char('z'), KeyModifiers::NONE);
+        editor.open_tab(Buffer::from_file(second.clone()).unwrap());
+        press(&mut editor, KeyCode::Char('y'), KeyModifiers::NONE);
 
-        e.update_cursor_blink();
-        if e.should_quit {
-            break;
-        }
+        editor.auto_save();
+        assert_eq!(std::fs::read_to_string(&second).unwrap(), "yb\n");
+        assert!(!editor.buffer.is_modified);
+        assert_eq!(std::fs::read_to_string(&first).unwrap(), "xa\n");
+        assert!(!editor.buffers[0].buffer.is_modified);
+        assert!(editor.buffers[1].buffer.path.is_none());
+        assert!(editor.buffers[1].buffer.is_modified);
+
+        std::fs::remove_file(first).unwrap();
+        std::fs::remove_file(second).unwrap();
     }
 
-    disable_raw_mode()?;
-    t.backend_mut().execute(LeaveAlternateScreen)?;
-    Ok(())
-}
+    #[test]
+    fn alt_d_marks_lines_changed_since_disk() {
+        let path = std::env::temp_dir().join(format!("nova-test-{}-diff.txt", std::process::id()));
+        std::fs::write(&path, "a\nb\nc\n").unwrap();
+        let mut editor = test_editor(Some(path.to_string_lossy().into_owned()), 80, 24);
+        press(&mut editor, KeyCode::Char('d'), KeyModifiers::ALT);
+        assert!(editor.diff_base.is_some() && editor.diff_lines.is_empty());
 
-fn main() -> io::Result<()> {
-    let args: Vec<String> = std::env::args().collect();
+        editor.insert_text("x");
+        let len = editor.buffer.line_len(1) + 1;
+        editor
+            .buffer
+            .delete(editor.buffer.get_cursor_pos(1, 0), len);
+        editor.refresh_diff();
+        assert_eq!(
+            editor.diff_lines,
+            [(0, LineChange::Added), (1, LineChange::Removed)]
+        );
 
-    let mut initial_file: Option<String> = None;
-    for arg in &args[1..] {
-        if !arg.starts_with('-') {
-            initial_file = Some(arg.clone());
-            break;
-        }
+        press(&mut editor, KeyCode::Char('s'), KeyModifiers::CONTROL);
+        editor.refresh_diff();
+        assert!(editor.diff_lines.is_empty());
+        std::fs::remove_file(path).unwrap();
     }
 
-    if let Err(x) = run(initial_file) {
-        disable_raw_mode()?;
-        stdout().execute(LeaveAlternateScreen).ok();
-        eprintln!("Error: {}", x);
-        std::process::exit(1);
+    #[test]
+    fn ctrl_r_reloads_external_changes() {
+        let path =
+            std::env::temp_dir().join(format!("nova-test-{}-reload.txt", std::process::id()));
+        std::fs::write(&path, "old\n").unwrap();
+        let mut editor = test_editor(Some(path.to_string_lossy().into_owned()), 80, 24);
+        editor.insert_text("x");
+
+        std::fs::write(&path, "new\n").unwrap();
+        press(&mut editor, KeyCode::Char('r'), KeyModifiers::CONTROL);
+        assert!(matches!(&editor.mode, EditorMode::Confirm { title, .. } if title == "Reload"));
+        press(&mut editor, KeyCode::Enter, KeyModifiers::NONE);
+        assert_eq!(editor.buffer.text.to_string(), "new\n");
+        assert!(!editor.buffer.is_modified);
+        assert!(!editor.buffer.changed_on_disk());
+
+        press(&mut editor, KeyCode::Char('z'), KeyModifiers::CONTROL);
+        assert_eq!(editor.buffer.text.to_string(), "xold\n");
+
+        std::fs::remove_file(path).unwrap();
     }
-    Ok(())
 }
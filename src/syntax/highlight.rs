@@ -1,3 +1,23 @@
+use std::ops::Range;
+
+/// Kind of token a highlighted span belongs to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TokenKind {
+    Keyword,
+    String,
+    Number,
+    Comment,
+}
+
+const RUST_KEYWORDS: &[&str] = &[
+    "as", "async", "await", "break", "const", "continue", "crate", "dyn", "else", "enum", "extern",
+    "false", "fn", "for", "if", "impl", "in", "let", "loop", "match", "mod", "move", "mut", "pub",
+    "ref", "return", "self", "Self", "static", "struct", "super", "trait", "true", "type",
+    "unsafe", "use", "where", "while",
+];
+
+const JSON_KEYWORDS: &[&str] = &["true", "false", "null"];
+
 pub struct Highlighter {
     pub language: String,
 }
@@ -16,11 +36,32 @@ impl Highlighter {
     pub fn get_comment_prefix(&self) -> Option<&'static str> {
         match self.language.as_str() {
             "python" | "ruby" | "shell" | "bash" | "yaml" => Some("#"),
-            "rust" | "javascript" | "typescript" | "go" | "java" | "c" | "cpp" | "css" | "json" => Some("//"),
+            "rust" | "javascript" | "typescript" | "go" | "java" | "c" | "cpp" | "css" | "json" => {
+                Some("//")
+            }
             "html" => Some("<!--"),
             _ => Some("#"),
         }
     }
+
+    /// Byte ranges of the tokens in `line` worth coloring. Each line is
+    /// tokenized on its own, so constructs spanning lines (such as a block
+    /// comment) are only recognized on the line they start and end.
+    pub fn highlight_line(&self, line: &str) -> Vec<(Range<usize>, TokenKind)> {
+        match self.language.as_str() {
+            "rust" => tokenize(line, RUST_KEYWORDS, true),
+            "json" => tokenize(line, JSON_KEYWORDS, false),
+            _ => Vec::new(),
+        }
+    }
+
+    /// Closing token for languages whose comments must be terminated.
+    pub fn get_comment_suffix(&self) -> Option<&'static str> {
+        match self.language.as_str() {
+            "html" => Some("-->"),
+            _ => None,
+        }
+    }
 }
 
 impl Default for Highlighter {
@@ -28,3 +69,119 @@ impl Default for Highlighter {
         Self::new()
     }
 }
+
+fn tokenize(line: &str, keywords: &[&str], rust: bool) -> Vec<(Range<usize>, TokenKind)> {
+    let bytes = line.as_bytes();
+    let mut spans = Vec::new();
+    let mut i = 0;
+    while i < bytes.len() {
+        let start = i;
+        let b = bytes[i];
+        if bytes[i..].starts_with(b"//") {
+            spans.push((start..bytes.len(), TokenKind::Comment));
+            break;
+        } else if rust && bytes[i..].starts_with(b"/*") {
+            i = line[i + 2..]
+                .find("*/")
+                .map_or(bytes.len(), |end| i + 2 + end + 2);
+            spans.push((start..i, TokenKind::Comment));
+        } else if b == b'"' {
+            i = string_end(bytes, i, b'"');
+            spans.push((start..i, TokenKind::String));
+        } else if rust && b == b'\'' {
+            // A char literal such as 'a' or '\n'; otherwise a lifetime.
+            if let Some(end) = char_literal_end(line, i) {
+                spans.push((start..end, TokenKind::String));
+                i = end;
+            } else {
+                i += 1;
+                while i < bytes.len() && is_word_byte(bytes[i]) {
+                    i += 1;
+                }
+            }
+        } else if b.is_ascii_digit() || (!rust && b == b'-' && next_is_digit(bytes, i)) {
+            i += 1;
+            while i < bytes.len()
+                && (is_word_byte(bytes[i])
+                    || (bytes[i] == b'.' && next_is_digit(bytes, i))
+                    || (matches!(bytes[i], b'+' | b'-') && matches!(bytes[i - 1], b'e' | b'E')))
+            {
+                i += 1;
+            }
+            spans.push((start..i, TokenKind::Number));
+        } else if is_word_byte(b) {
+            while i < bytes.len() && is_word_byte(bytes[i]) {
+                i += 1;
+            }
+            if keywords.contains(&&line[start..i]) {
+                spans.push((start..i, TokenKind::Keyword));
+            }
+        } else {
+            i += line[i..].chars().next().map_or(1, char::len_utf8);
+        }
+    }
+    spans
+}
+
+/// Index just past the closing `quote` of the literal opening at `start`, or
+/// the end of the line if it is unterminated.
+fn string_end(bytes: &[u8], start: usize, quote: u8) -> usize {
+    let mut i = start + 1;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'\\' => i += 2,
+            b if b == quote => return i + 1,
+            _ => i += 1,
+        }
+    }
+    bytes.len()
+}
+
+/// End of the char literal opening at `start`, if it is one rather than a
+/// lifetime.
+fn char_literal_end(line: &str, start: usize) -> Option<usize> {
+    let rest = &line[start + 1..];
+    let end = if rest.starts_with('\\') {
+        string_end(line.as_bytes(), start, b'\'')
+    } else {
+        let c = rest.chars().next()?;
+        start + 1 + c.len_utf8() + 1
+    };
+    (line.as_bytes().get(end - 1) == Some(&b'\'') && end - start > 2).then_some(end)
+}
+
+fn next_is_digit(bytes: &[u8], i: usize) -> bool {
+    bytes.get(i + 1).is_some_and(u8::is_ascii_digit)
+}
+
+fn is_word_byte(b: u8) -> bool {
+    b.is_ascii_alphanumeric() || b == b'_' || b >= 0x80
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rust_line_tokens() {
+        let mut highlighter = Highlighter::new();
+        highlighter.set_language("rust");
+        let line = "fn f<'a>(c: char) -> u8 { let s = \"x\\\"y\"; '\\n'; 0x1f } // done";
+        let tokens: Vec<(&str, TokenKind)> = highlighter
+            .highlight_line(line)
+            .into_iter()
+            .map(|(range, kind)| (&line[range], kind))
+            .collect();
+        assert_eq!(
+            tokens,
+            vec![
+                ("fn", TokenKind::Keyword),
+                ("let", TokenKind::Keyword),
+                ("\"x\\\"y\"", TokenKind::String),
+                ("'\\n'", TokenKind::String),
+                ("0x1f", TokenKind::Number),
+                ("// done", TokenKind::Comment),
+            ]
+        );
+    }
+}
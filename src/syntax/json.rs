@@ -0,0 +1,19 @@
+use serde::Serialize;
+use serde_json::ser::{PrettyFormatter, Serializer};
+
+/// Re-serializes `text` with `indent` spaces per level, keeping key order.
+pub fn pretty(text: &str, indent: usize) -> Result<String, serde_json::Error> {
+    let value: serde_json::Value = serde_json::from_str(text)?;
+    let indent = " ".repeat(indent);
+    let mut out = Vec::new();
+    let mut ser =
+        Serializer::with_formatter(&mut out, PrettyFormatter::with_indent(indent.as_bytes()));
+    value.serialize(&mut ser)?;
+    Ok(String::from_utf8(out).unwrap_or_default())
+}
+
+/// Re-serializes `text` with all insignificant whitespace removed.
+pub fn minify(text: &str) -> Result<String, serde_json::Error> {
+    let value: serde_json::Value = serde_json::from_str(text)?;
+    serde_json::to_string(&value)
+}
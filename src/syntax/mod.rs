@@ -1,3 +1,4 @@
 pub mod highlight;
+pub mod json;
 
-pub use highlight::Highlighter;
+pub use highlight::{Highlighter, TokenKind};
@@ -2,4 +2,4 @@ pub mod theme;
 pub mod widgets;
 
 pub use theme::Theme;
-pub use widgets::{EditorView, HelpBar, StatusBar};
+pub use widgets::{EditorView, HelpBar, StatusBar, TabBar};
@@ -1,12 +1,18 @@
+use std::path::{Path, PathBuf};
+
 use ratatui::style::Color;
 
+use crate::syntax::TokenKind;
+
 #[derive(Clone, Debug)]
 pub struct Theme {
     pub name: String,
     pub background: Color,
     pub foreground: Color,
-    #[allow(dead_code)]
     pub selection: Color,
+    pub search_match_bg: Color,
+    pub search_current_bg: Color,
+    pub bracket_match: Color,
     pub cursor: Color,
     pub cursor_line: Color,
     pub line_number: Color,
@@ -20,6 +26,17 @@ pub struct Theme {
     pub title_fg: Color,
     pub accent: Color,
     pub scrollbar: Color,
+    /// Tabs and leading or trailing spaces when `show_whitespace` is on.
+    pub whitespace: Color,
+    /// Background of spaces and tabs at the ends of lines.
+    pub trailing_whitespace: Color,
+    pub syntax_keyword: Color,
+    pub syntax_string: Color,
+    pub syntax_number: Color,
+    pub syntax_comment: Color,
+    /// Gutter markers for lines added or removed since the file on disk.
+    pub diff_added: Color,
+    pub diff_removed: Color,
 }
 
 impl Theme {
@@ -29,6 +46,9 @@ impl Theme {
             background: Color::Rgb(39, 40, 34),
             foreground: Color::Rgb(249, 238, 230),
             selection: Color::Rgb(117, 113, 97),
+            search_match_bg: Color::Rgb(90, 80, 40),
+            search_current_bg: Color::Rgb(166, 126, 40),
+            bracket_match: Color::Rgb(80, 78, 68),
             cursor: Color::Rgb(249, 238, 230),
             cursor_line: Color::Rgb(50, 52, 46),
             line_number: Color::Rgb(100, 100, 100),
@@ -42,6 +62,14 @@ impl Theme {
             title_fg: Color::Rgb(255, 200, 100),
             accent: Color::Rgb(255, 200, 100),
             scrollbar: Color::Rgb(80, 75, 70),
+            whitespace: Color::Rgb(90, 88, 80),
+            trailing_whitespace: Color::Rgb(110, 40, 55),
+            syntax_keyword: Color::Rgb(249, 38, 114),
+            syntax_string: Color::Rgb(230, 219, 116),
+            syntax_number: Color::Rgb(174, 129, 255),
+            syntax_comment: Color::Rgb(117, 113, 94),
+            diff_added: Color::Rgb(166, 226, 46),
+            diff_removed: Color::Rgb(249, 38, 114),
         }
     }
 
@@ -50,7 +78,10 @@ impl Theme {
             name: "nord_frost".to_string(),
             background: Color::Rgb(46, 52, 64),
             foreground: Color::Rgb(216, 222, 233),
-            selection: Color::Rgb(59, 66, 82),
+            selection: Color::Rgb(67, 76, 94),
+            search_match_bg: Color::Rgb(92, 82, 58),
+            search_current_bg: Color::Rgb(94, 129, 172),
+            bracket_match: Color::Rgb(76, 86, 106),
             cursor: Color::Rgb(136, 192, 208),
             cursor_line: Color::Rgb(59, 66, 82),
             line_number: Color::Rgb(76, 86, 106),
@@ -64,6 +95,14 @@ impl Theme {
             title_fg: Color::Rgb(136, 192, 208),
             accent: Color::Rgb(136, 192, 208),
             scrollbar: Color::Rgb(80, 95, 110),
+            whitespace: Color::Rgb(67, 76, 94),
+            trailing_whitespace: Color::Rgb(110, 60, 70),
+            syntax_keyword: Color::Rgb(129, 161, 193),
+            syntax_string: Color::Rgb(163, 190, 140),
+            syntax_number: Color::Rgb(180, 142, 173),
+            syntax_comment: Color::Rgb(97, 110, 136),
+            diff_added: Color::Rgb(163, 190, 140),
+            diff_removed: Color::Rgb(191, 97, 106),
         }
     }
 
@@ -73,6 +112,9 @@ impl Theme {
             background: Color::Rgb(40, 42, 54),
             foreground: Color::Rgb(248, 248, 242),
             selection: Color::Rgb(69, 71, 90),
+            search_match_bg: Color::Rgb(86, 78, 50),
+            search_current_bg: Color::Rgb(150, 110, 40),
+            bracket_match: Color::Rgb(68, 71, 90),
             cursor: Color::Rgb(255, 121, 198),
             cursor_line: Color::Rgb(60, 62, 80),
             line_number: Color::Rgb(90, 90, 110),
@@ -86,6 +128,14 @@ impl Theme {
             title_fg: Color::Rgb(255, 121, 198),
             accent: Color::Rgb(189, 147, 249),
             scrollbar: Color::Rgb(100, 100, 120),
+            whitespace: Color::Rgb(80, 82, 100),
+            trailing_whitespace: Color::Rgb(120, 40, 60),
+            syntax_keyword: Color::Rgb(255, 121, 198),
+            syntax_string: Color::Rgb(241, 250, 140),
+            syntax_number: Color::Rgb(189, 147, 249),
+            syntax_comment: Color::Rgb(98, 114, 164),
+            diff_added: Color::Rgb(80, 250, 123),
+            diff_removed: Color::Rgb(255, 85, 85),
         }
     }
 
@@ -95,6 +145,9 @@ impl Theme {
             background: Color::Rgb(40, 40, 40),
             foreground: Color::Rgb(235, 219, 178),
             selection: Color::Rgb(80, 73, 69),
+            search_match_bg: Color::Rgb(102, 92, 40),
+            search_current_bg: Color::Rgb(181, 118, 20),
+            bracket_match: Color::Rgb(80, 73, 69),
             cursor: Color::Rgb(254, 128, 25),
             cursor_line: Color::Rgb(55, 53, 50),
             line_number: Color::Rgb(100, 90, 80),
@@ -108,6 +161,14 @@ impl Theme {
             title_fg: Color::Rgb(254, 128, 25),
             accent: Color::Rgb(184, 187, 38),
             scrollbar: Color::Rgb(90, 85, 80),
+            whitespace: Color::Rgb(90, 82, 74),
+            trailing_whitespace: Color::Rgb(110, 45, 40),
+            syntax_keyword: Color::Rgb(251, 73, 52),
+            syntax_string: Color::Rgb(184, 187, 38),
+            syntax_number: Color::Rgb(211, 134, 155),
+            syntax_comment: Color::Rgb(146, 131, 116),
+            diff_added: Color::Rgb(184, 187, 38),
+            diff_removed: Color::Rgb(251, 73, 52),
         }
     }
 
@@ -117,6 +178,9 @@ impl Theme {
             background: Color::Rgb(40, 44, 52),
             foreground: Color::Rgb(220, 223, 228),
             selection: Color::Rgb(57, 62, 70),
+            search_match_bg: Color::Rgb(76, 70, 45),
+            search_current_bg: Color::Rgb(160, 120, 40),
+            bracket_match: Color::Rgb(62, 68, 81),
             cursor: Color::Rgb(97, 175, 239),
             cursor_line: Color::Rgb(50, 54, 62),
             line_number: Color::Rgb(90, 95, 105),
@@ -130,33 +194,252 @@ impl Theme {
             title_fg: Color::Rgb(97, 175, 239),
             accent: Color::Rgb(97, 175, 239),
             scrollbar: Color::Rgb(80, 85, 95),
+            whitespace: Color::Rgb(75, 82, 95),
+            trailing_whitespace: Color::Rgb(100, 45, 50),
+            syntax_keyword: Color::Rgb(198, 120, 221),
+            syntax_string: Color::Rgb(152, 195, 121),
+            syntax_number: Color::Rgb(209, 154, 102),
+            syntax_comment: Color::Rgb(92, 99, 112),
+            diff_added: Color::Rgb(152, 195, 121),
+            diff_removed: Color::Rgb(224, 108, 117),
+        }
+    }
+
+    pub fn solarized_light() -> Self {
+        Self {
+            name: "solarized_light".to_string(),
+            background: Color::Rgb(253, 246, 227),
+            foreground: Color::Rgb(88, 110, 117),
+            selection: Color::Rgb(221, 214, 193),
+            search_match_bg: Color::Rgb(245, 222, 150),
+            search_current_bg: Color::Rgb(240, 190, 90),
+            bracket_match: Color::Rgb(214, 208, 186),
+            cursor: Color::Rgb(38, 139, 210),
+            cursor_line: Color::Rgb(238, 232, 213),
+            line_number: Color::Rgb(147, 161, 161),
+            line_number_current: Color::Rgb(203, 75, 22),
+            status_bar_bg: Color::Rgb(238, 232, 213),
+            status_bar_fg: Color::Rgb(7, 54, 66),
+            help_bar_bg: Color::Rgb(238, 232, 213),
+            help_bar_fg: Color::Rgb(101, 123, 131),
+            border: Color::Rgb(200, 193, 170),
+            title_bg: Color::Rgb(238, 232, 213),
+            title_fg: Color::Rgb(38, 139, 210),
+            accent: Color::Rgb(38, 139, 210),
+            scrollbar: Color::Rgb(190, 183, 160),
+            whitespace: Color::Rgb(200, 195, 175),
+            trailing_whitespace: Color::Rgb(250, 215, 200),
+            syntax_keyword: Color::Rgb(133, 153, 0),
+            syntax_string: Color::Rgb(42, 161, 152),
+            syntax_number: Color::Rgb(211, 54, 130),
+            syntax_comment: Color::Rgb(147, 161, 161),
+            diff_added: Color::Rgb(133, 153, 0),
+            diff_removed: Color::Rgb(220, 50, 47),
+        }
+    }
+
+    pub fn github_light() -> Self {
+        Self {
+            name: "github_light".to_string(),
+            background: Color::Rgb(255, 255, 255),
+            foreground: Color::Rgb(36, 41, 47),
+            selection: Color::Rgb(200, 225, 255),
+            search_match_bg: Color::Rgb(255, 236, 160),
+            search_current_bg: Color::Rgb(255, 200, 80),
+            bracket_match: Color::Rgb(220, 228, 236),
+            cursor: Color::Rgb(9, 105, 218),
+            cursor_line: Color::Rgb(246, 248, 250),
+            line_number: Color::Rgb(140, 149, 159),
+            line_number_current: Color::Rgb(36, 41, 47),
+            status_bar_bg: Color::Rgb(234, 238, 242),
+            status_bar_fg: Color::Rgb(36, 41, 47),
+            help_bar_bg: Color::Rgb(234, 238, 242),
+            help_bar_fg: Color::Rgb(87, 96, 106),
+            border: Color::Rgb(208, 215, 222),
+            title_bg: Color::Rgb(246, 248, 250),
+            title_fg: Color::Rgb(9, 105, 218),
+            accent: Color::Rgb(9, 105, 218),
+            scrollbar: Color::Rgb(175, 184, 193),
+            whitespace: Color::Rgb(200, 206, 212),
+            trailing_whitespace: Color::Rgb(255, 220, 215),
+            syntax_keyword: Color::Rgb(207, 34, 46),
+            syntax_string: Color::Rgb(10, 48, 105),
+            syntax_number: Color::Rgb(5, 80, 174),
+            syntax_comment: Color::Rgb(110, 119, 129),
+            diff_added: Color::Rgb(26, 127, 55),
+            diff_removed: Color::Rgb(207, 34, 46),
         }
     }
 
     pub fn get_theme(name: &str) -> Self {
+        Self::load(name).unwrap_or_else(|_| Self::monokai_pro())
+    }
+
+    /// A built-in theme, or `<name>.toml` from the user theme directory.
+    pub fn load(name: &str) -> Result<Self, String> {
         match name.to_lowercase().as_str() {
-            "monokai_pro" | "monokai" => Self::monokai_pro(),
-            "nord_frost" | "nord" => Self::nord_frost(),
-            "dracula_vibrant" | "dracula" => Self::dracula_vibrant(),
-            "gruvbox_soft" | "gruvbox" => Self::gruvbox_soft(),
-            "one_dark" => Self::one_dark(),
-            _ => Self::monokai_pro(),
+            "monokai_pro" | "monokai" => return Ok(Self::monokai_pro()),
+            "nord_frost" | "nord" => return Ok(Self::nord_frost()),
+            "dracula_vibrant" | "dracula" => return Ok(Self::dracula_vibrant()),
+            "gruvbox_soft" | "gruvbox" => return Ok(Self::gruvbox_soft()),
+            "one_dark" => return Ok(Self::one_dark()),
+            "solarized_light" | "solarized" => return Ok(Self::solarized_light()),
+            "github_light" | "github" => return Ok(Self::github_light()),
+            _ => {}
+        }
+        match Self::user_theme_dir().map(|dir| dir.join(format!("{}.toml", name))) {
+            Some(path) if path.is_file() => Self::from_file(&path),
+            _ => Err(format!("unknown theme '{}'", name)),
         }
     }
 
+    /// Reads a theme from a TOML file of `field = "#rrggbb"` pairs, named
+    /// after the file. Colors it leaves out are taken from monokai_pro.
+    pub fn from_file(path: &Path) -> Result<Self, String> {
+        let name = path
+            .file_stem()
+            .map(|s| s.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        let contents = std::fs::read_to_string(path)
+            .map_err(|e| format!("cannot read {}: {}", path.display(), e))?;
+        let table: toml::Table = toml::from_str(&contents)
+            .map_err(|e| format!("{}: {}", path.display(), e.message()))?;
+
+        let mut theme = Self {
+            name: name.clone(),
+            ..Self::monokai_pro()
+        };
+        for (key, value) in &table {
+            let Some(color) = theme.color_mut(key) else {
+                return Err(format!("theme {}: unknown color '{}'", name, key));
+            };
+            *color = value.as_str().and_then(parse_hex_color).ok_or_else(|| {
+                format!("theme {}: {} must be \"#rrggbb\", not {}", name, key, value)
+            })?;
+        }
+        Ok(theme)
+    }
+
+    /// Where user themes live: `themes/` next to the config file.
+    pub fn user_theme_dir() -> Option<PathBuf> {
+        dirs::config_dir().map(|p| p.join("nova").join("themes"))
+    }
+
+    fn color_mut(&mut self, field: &str) -> Option<&mut Color> {
+        Some(match field {
+            "background" => &mut self.background,
+            "foreground" => &mut self.foreground,
+            "selection" => &mut self.selection,
+            "search_match_bg" => &mut self.search_match_bg,
+            "search_current_bg" => &mut self.search_current_bg,
+            "bracket_match" => &mut self.bracket_match,
+            "cursor" => &mut self.cursor,
+            "cursor_line" => &mut self.cursor_line,
+            "line_number" => &mut self.line_number,
+            "line_number_current" => &mut self.line_number_current,
+            "status_bar_bg" => &mut self.status_bar_bg,
+            "status_bar_fg" => &mut self.status_bar_fg,
+            "help_bar_bg" => &mut self.help_bar_bg,
+            "help_bar_fg" => &mut self.help_bar_fg,
+            "border" => &mut self.border,
+            "title_bg" => &mut self.title_bg,
+            "title_fg" => &mut self.title_fg,
+            "accent" => &mut self.accent,
+            "scrollbar" => &mut self.scrollbar,
+            "whitespace" => &mut self.whitespace,
+            "trailing_whitespace" => &mut self.trailing_whitespace,
+            "syntax_keyword" => &mut self.syntax_keyword,
+            "syntax_string" => &mut self.syntax_string,
+            "syntax_number" => &mut self.syntax_number,
+            "syntax_comment" => &mut self.syntax_comment,
+            "diff_added" => &mut self.diff_added,
+            "diff_removed" => &mut self.diff_removed,
+            _ => return None,
+        })
+    }
+
+    pub fn token_color(&self, kind: TokenKind) -> Color {
+        match kind {
+            TokenKind::Keyword => self.syntax_keyword,
+            TokenKind::String => self.syntax_string,
+            TokenKind::Number => self.syntax_number,
+            TokenKind::Comment => self.syntax_comment,
+        }
+    }
+
+    /// Built-in themes, then user themes sorted by name.
     pub fn all_themes() -> Vec<String> {
-        vec![
+        let mut user_themes: Vec<String> = Self::user_theme_dir()
+            .and_then(|dir| std::fs::read_dir(dir).ok())
+            .into_iter()
+            .flatten()
+            .filter_map(|entry| {
+                let path = entry.ok()?.path();
+                let is_toml = path.extension().is_some_and(|e| e == "toml");
+                is_toml.then(|| path.file_stem()?.to_str().map(str::to_string))?
+            })
+            .collect();
+        user_themes.sort();
+
+        let mut themes = vec![
             "monokai_pro".to_string(),
             "nord_frost".to_string(),
             "dracula_vibrant".to_string(),
             "gruvbox_soft".to_string(),
             "one_dark".to_string(),
-        ]
+            "solarized_light".to_string(),
+            "github_light".to_string(),
+        ];
+        themes.extend(user_themes);
+        themes
     }
 }
 
+/// Parses `#rrggbb`.
+fn parse_hex_color(s: &str) -> Option<Color> {
+    let hex = s.strip_prefix('#')?;
+    if hex.len() != 6 || !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return None;
+    }
+    let channel = |i: usize| u8::from_str_radix(&hex[i..i + 2], 16).ok();
+    Some(Color::Rgb(channel(0)?, channel(2)?, channel(4)?))
+}
+
 impl Default for Theme {
     fn default() -> Self {
         Self::monokai_pro()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn theme_files_set_colors_and_report_bad_ones() {
+        let dir = std::env::temp_dir();
+        let path = dir.join(format!("nova-test-{}-theme.toml", std::process::id()));
+
+        std::fs::write(&path, "background = \"#102030\"\naccent = \"#FFfF00\"\n").unwrap();
+        let theme = Theme::from_file(&path).unwrap();
+        assert_eq!(theme.background, Color::Rgb(16, 32, 48));
+        assert_eq!(theme.accent, Color::Rgb(255, 255, 0));
+        assert_eq!(theme.cursor, Theme::monokai_pro().cursor);
+
+        std::fs::write(&path, "border = \"#12345\"\n").unwrap();
+        let err = Theme::from_file(&path).unwrap_err();
+        assert!(
+            err.ends_with("border must be \"#rrggbb\", not \"#12345\""),
+            "{}",
+            err
+        );
+
+        std::fs::write(&path, "bordr = \"#123456\"\n").unwrap();
+        assert!(
+            Theme::from_file(&path)
+                .unwrap_err()
+                .ends_with("unknown color 'bordr'")
+        );
+        std::fs::remove_file(path).ok();
+    }
+}
@@ -3,46 +3,185 @@ use ratatui::{
     widgets::{Block, Borders, Widget},
 };
 
+use unicode_segmentation::UnicodeSegmentation;
+use unicode_width::UnicodeWidthStr;
+
 use super::Theme;
-use crate::buffer::Buffer;
+use crate::buffer::{Buffer, LineChange, Selection, SelectionKind};
+use crate::syntax::TokenKind;
 
-pub struct EditorView {
-    pub buffer: Buffer,
+/// Renders the visible part of a buffer. Borrows the buffer so drawing a
+/// frame doesn't copy the text.
+pub struct EditorView<'a> {
+    pub buffer: &'a Buffer,
     pub cursor_line: usize,
     pub cursor_col: usize,
     pub show_line_numbers: bool,
     pub scroll_offset: usize,
+    /// Display columns scrolled off the left edge; ignored with word wrap.
+    pub col_offset: usize,
+    pub selection: Option<Selection>,
+    /// `(line, start_col, end_col)` of search matches to highlight.
+    pub search_matches: Vec<(usize, usize, usize)>,
+    /// `(line, col)` of a bracket under the cursor and its partner.
+    pub bracket_cells: Vec<(usize, usize)>,
+    /// `(line, start_col, end_col, kind)` of syntax tokens on visible lines.
+    pub syntax_spans: Vec<(usize, usize, usize, TokenKind)>,
     pub theme: Theme,
     pub cursor_blink_on: bool,
-    #[allow(dead_code)]
+    /// Tabs are drawn up to the next multiple of this many columns.
+    pub tab_width: usize,
+    /// Wraps long lines at the width of the area instead of scrolling.
     pub word_wrap: bool,
-    #[allow(dead_code)]
-    pub width: u16,
+    /// `(line, col)` of cursors besides the main one.
+    pub extra_cursors: Vec<(usize, usize)>,
+    /// Draw tabs and leading or trailing spaces with visible glyphs.
+    pub show_whitespace: bool,
+    /// Spaces and tabs ending a line get the `trailing_whitespace`
+    /// background; on the cursor's line only with the option below.
+    pub highlight_trailing_whitespace: bool,
+    pub trailing_whitespace_on_current_line: bool,
+    /// Paint the cursor's line, gutter included, with the `cursor_line` color.
+    pub highlight_current_line: bool,
+    /// Draw a scrollbar at the right edge when the text doesn't fit.
+    pub show_scrollbar: bool,
+    /// `(line, change)` of lines that differ from the file on disk, shown
+    /// as `+` or `-` at the right edge of the gutter.
+    pub line_changes: Vec<(usize, LineChange)>,
 }
 
-impl EditorView {
-    pub fn new() -> Self {
-        Self {
-            buffer: Buffer::new(),
-            cursor_line: 0,
-            cursor_col: 0,
-            show_line_numbers: true,
-            scroll_offset: 0,
-            theme: Theme::monokai_pro(),
-            cursor_blink_on: true,
-            word_wrap: false,
-            width: 80,
+/// Width of the line number column, including its separator.
+pub fn gutter_width(line_count: usize, show_line_numbers: bool) -> u16 {
+    if show_line_numbers && line_count > 0 {
+        (line_count.to_string().len() + 3).max(5) as u16
+    } else {
+        2
+    }
+}
+
+/// Columns of an editor view `view_width` cells wide, counted from its
+/// left edge, that hold text: after the border and line number gutter and
+/// before the scrollbar and border. Drawing and mouse clicks both use it.
+pub fn text_columns(
+    view_width: u16,
+    line_count: usize,
+    show_line_numbers: bool,
+) -> std::ops::Range<u16> {
+    let start = 1 + gutter_width(line_count, show_line_numbers);
+    start..view_width.saturating_sub(2).max(start)
+}
+
+/// A byte count in the largest unit that keeps it at least 1, e.g. "4.2 KB".
+pub fn format_size(bytes: usize) -> String {
+    const UNITS: [&str; 4] = ["KB", "MB", "GB", "TB"];
+    if bytes < 1024 {
+        return format!("{} B", bytes);
+    }
+    let mut size = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while size >= 1024.0 && unit + 1 < UNITS.len() {
+        size /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", size, UNITS[unit])
+}
+
+/// Terminal cells taken by one grapheme drawn at cell `x`. A tab reaches
+/// the next multiple of `tab_width`, and every other grapheme gets at least
+/// one cell so the cursor can sit on it.
+fn grapheme_width(grapheme: &str, x: usize, tab_width: usize) -> usize {
+    if grapheme == "\t" {
+        let tab_width = tab_width.max(1);
+        tab_width - x % tab_width
+    } else {
+        grapheme.width().max(1)
+    }
+}
+
+/// Terminal cells taken by `text`.
+pub fn display_width(text: &str, tab_width: usize) -> usize {
+    text.graphemes(true)
+        .fold(0, |x, g| x + grapheme_width(g, x, tab_width))
+}
+
+/// Byte column of the grapheme drawn at cell `display_col` of `text`, or the
+/// end of `text` past its last cell.
+pub fn byte_col_at(text: &str, display_col: usize, tab_width: usize) -> usize {
+    let mut x = 0;
+    for (i, g) in text.grapheme_indices(true) {
+        x += grapheme_width(g, x, tab_width);
+        if display_col < x {
+            return i;
         }
     }
+    text.len()
 }
 
-impl Default for EditorView {
-    fn default() -> Self {
-        Self::new()
+/// Display columns `left..right` spanned by a block selection, measured on
+/// the anchor's row and the cursor's row.
+pub fn block_columns(
+    buffer: &Buffer,
+    sel: &Selection,
+    cursor_line: usize,
+    cursor_col: usize,
+    tab_width: usize,
+) -> (usize, usize) {
+    let x = |line: usize, col: usize| {
+        let col = buffer.grapheme_floor(line, col);
+        display_width(&buffer.get_line(line)[..col], tab_width)
+    };
+    let anchor = x(sel.anchor_line, sel.anchor_col);
+    let cursor = x(cursor_line, cursor_col);
+    (anchor.min(cursor), anchor.max(cursor))
+}
+
+/// Byte range of the graphemes of `text` drawn in display columns
+/// `left..right`, or `None` if `text` ends before `left`.
+pub fn block_byte_range(
+    text: &str,
+    left: usize,
+    right: usize,
+    tab_width: usize,
+) -> Option<std::ops::Range<usize>> {
+    if display_width(text, tab_width) < left {
+        return None;
+    }
+    Some(byte_col_at(text, left, tab_width)..byte_col_at(text, right, tab_width))
+}
+
+/// Byte offsets where the screen rows of `text` start when it's wrapped at
+/// `width` cells. Rows break after the last whitespace that fits, or mid-word
+/// for words longer than a row. A line that exactly fills its last row gets
+/// an empty row after it, so the cursor has somewhere to go at the end.
+pub fn wrap_rows(text: &str, width: usize, tab_width: usize) -> Vec<usize> {
+    let width = width.max(1);
+    let mut starts = vec![0];
+    let mut x = 0;
+    // Where the current row could break: just after its last whitespace.
+    let mut word_start = None;
+    for (i, g) in text.grapheme_indices(true) {
+        if x > 0 && x + grapheme_width(g, x, tab_width) > width {
+            let start = word_start.take().unwrap_or(i);
+            x = display_width(&text[start..i], tab_width);
+            if x + grapheme_width(g, x, tab_width) > width {
+                starts.push(i);
+                x = 0;
+            } else {
+                starts.push(start);
+            }
+        }
+        x += grapheme_width(g, x, tab_width);
+        if g.chars().all(char::is_whitespace) {
+            word_start = Some(i + g.len());
+        }
+    }
+    if x >= width {
+        starts.push(text.len());
     }
+    starts
 }
 
-impl Widget for EditorView {
+impl Widget for EditorView<'_> {
     fn render(self, area: ratatui::layout::Rect, buf: &mut ratatui::buffer::Buffer) {
         // Create a bordered block
         let block = Block::default()
@@ -59,11 +198,7 @@ impl Widget for EditorView {
         }
 
         let line_count = self.buffer.num_lines();
-        let line_number_width = if self.show_line_numbers && line_count > 0 {
-            (line_count.to_string().len() + 3).max(5) as u16
-        } else {
-            2
-        };
+        let line_number_width = gutter_width(line_count, self.show_line_numbers);
 
         // Clear the editor area
         let clear_style = ratatui::style::Style::default()
@@ -75,153 +210,272 @@ impl Widget for EditorView {
             buf.set_string(inner.x, inner.y + y, &clear_str, clear_style);
         }
 
-        // Render visible lines
+        // Render visible lines, several screen rows each when wrapped
         let visible_lines = inner.height as usize;
-
-        for y in 0..visible_lines {
-            let line_idx = self.scroll_offset + y;
-
-            if line_idx >= line_count {
-                break;
+        let columns = text_columns(area.width, line_count, self.show_line_numbers);
+        let max_visible = columns.len();
+        let text_start = area.x + columns.start;
+        let text_end = (area.x + columns.end) as usize;
+
+        let marked_lines = self.buffer.marked_lines();
+        // `(top, bottom, left, right)` of a block selection in display columns.
+        let block = self
+            .selection
+            .filter(|s| s.kind == SelectionKind::Block)
+            .map(|s| {
+                let (top, bottom, ..) = s.block_bounds(self.cursor_line, self.cursor_col);
+                let (left, right) = block_columns(
+                    self.buffer,
+                    &s,
+                    self.cursor_line,
+                    self.cursor_col,
+                    self.tab_width,
+                );
+                (top, bottom, left, right)
+            });
+
+        // `(line, byte range, last row of the line)` for every screen row.
+        let mut texts = Vec::new();
+        let mut rows = Vec::new();
+        while rows.len() < visible_lines && self.scroll_offset + texts.len() < line_count {
+            let line_idx = self.scroll_offset + texts.len();
+            let line_text = self.buffer.get_line(line_idx);
+            let starts = if self.word_wrap {
+                wrap_rows(&line_text, max_visible, self.tab_width)
+            } else {
+                vec![0]
+            };
+            for (i, &start) in starts.iter().enumerate() {
+                let end = starts.get(i + 1).copied().unwrap_or(line_text.len());
+                rows.push((line_idx, start..end, i + 1 == starts.len()));
             }
+            texts.push(line_text);
+        }
+        rows.truncate(visible_lines);
 
-            let line_text = self.buffer.get_line(line_idx);
+        for (y, (line_idx, range, last_row)) in rows.into_iter().enumerate() {
+            let line_text = &texts[line_idx - self.scroll_offset];
             let is_current_line = line_idx == self.cursor_line;
+            let line_bg = if is_current_line && self.highlight_current_line {
+                self.theme.cursor_line
+            } else {
+                self.theme.background
+            };
+            let pos_y = inner.y + y as u16;
 
-            // Render line number with separator
+            // Render line number with separator; rows continuing a wrapped line
+            // get a marker instead
             if self.show_line_numbers {
+                let number = if range.start == 0 {
+                    (line_idx + 1).to_string()
+                } else {
+                    "↪".to_string()
+                };
                 let line_num_str = format!(
                     "{:>width$} │",
-                    line_idx + 1,
+                    number,
                     width = (line_number_width as usize - 2)
                 );
 
                 for (x, c) in line_num_str.chars().enumerate() {
                     let pos_x = inner.x + x as u16;
-                    let pos_y = inner.y + y as u16;
                     if pos_x < inner.x + line_number_width {
-                        let style = if is_current_line {
-                            ratatui::style::Style::default()
-                                .bg(self.theme.cursor_line)
-                                .fg(self.theme.line_number_current)
+                        let fg = if is_current_line {
+                            self.theme.line_number_current
                         } else {
-                            ratatui::style::Style::default()
-                                .bg(self.theme.background)
-                                .fg(self.theme.line_number)
+                            self.theme.line_number
                         };
+                        let style = ratatui::style::Style::default().bg(line_bg).fg(fg);
                         buf[(pos_x, pos_y)].set_char(c).set_style(style);
                     }
                 }
+            } else if range.start > 0 {
+                buf[(inner.x, pos_y)].set_char('↪').set_style(
+                    ratatui::style::Style::default()
+                        .bg(line_bg)
+                        .fg(self.theme.line_number),
+                );
             }
 
-            // Render line content
-            let text_start = inner.x + line_number_width;
+            // Marked lines get a dot at the left edge of the gutter
+            if range.start == 0 && marked_lines.contains(&line_idx) {
+                buf[(inner.x, pos_y)].set_char('●').set_style(
+                    ratatui::style::Style::default()
+                        .bg(line_bg)
+                        .fg(self.theme.accent),
+                );
+            }
 
-            // Horizontal scroll - keep cursor visible
-            let max_visible = (inner.width.saturating_sub(line_number_width + 1)) as usize;
-            let line_len = line_text.len();
-            let display_col = if line_len > max_visible {
-                if self.cursor_col > max_visible * 2 / 3 {
-                    (self.cursor_col.saturating_sub(max_visible / 3))
-                        .min(line_len.saturating_sub(max_visible))
-                } else {
-                    0
-                }
+            if range.start == 0
+                && let Some(&(_, change)) = self.line_changes.iter().find(|&&(l, _)| l == line_idx)
+            {
+                let (symbol, fg) = match change {
+                    LineChange::Added => ('+', self.theme.diff_added),
+                    LineChange::Removed => ('-', self.theme.diff_removed),
+                };
+                buf[(inner.x + line_number_width - 1, pos_y)]
+                    .set_char(symbol)
+                    .set_style(ratatui::style::Style::default().bg(line_bg).fg(fg));
+            }
+
+            let display_col = if self.word_wrap { 0 } else { self.col_offset };
+
+            // Spaces before `indent_end` or from `trailing_start` on are shown.
+            let indent_end = line_text.len() - line_text.trim_start_matches([' ', '\t']).len();
+            let trailing_start = line_text.trim_end_matches([' ', '\t']).len();
+            let trailing_bg = if self.highlight_trailing_whitespace
+                && (!is_current_line || self.trailing_whitespace_on_current_line)
+            {
+                self.theme.trailing_whitespace
             } else {
-                0
+                line_bg
             };
 
-            let visible_text: String = line_text
-                .chars()
-                .skip(display_col)
-                .take(max_visible)
+            let line_spans: Vec<_> = self
+                .syntax_spans
+                .iter()
+                .filter(|&&(l, ..)| l == line_idx)
                 .collect();
-            let pos_y = inner.y + y as u16;
-            let cursor_rel_col = self.cursor_col.saturating_sub(display_col);
 
-            for (x, c) in visible_text.chars().enumerate() {
-                let col = text_start as usize + x;
-                if col < (inner.x + inner.width - 1) as usize {
-                    let abs_col = display_col + x;
-                    let is_cursor = is_current_line && abs_col == self.cursor_col;
+            let block_range = block.and_then(|(top, bottom, left, right)| {
+                (top..=bottom)
+                    .contains(&line_idx)
+                    .then(|| block_byte_range(line_text, left, right, self.tab_width))
+                    .flatten()
+            });
+
+            let mut x = 0;
+            for (i, g) in line_text[range.clone()].grapheme_indices(true) {
+                let abs_col = range.start + i;
+                let width = grapheme_width(g, x, self.tab_width);
+                let cell_x = x;
+                x += width;
+                // Wide characters cut off by the scroll aren't drawn at all.
+                if cell_x < display_col {
+                    continue;
+                }
+                let col = text_start as usize + cell_x - display_col;
+                if col + width > text_end {
+                    break;
+                }
+                let is_cursor = (is_current_line && abs_col == self.cursor_col)
+                    || self.extra_cursors.contains(&(line_idx, abs_col));
+                let is_selected = match &block_range {
+                    Some(cols) => cols.contains(&abs_col),
+                    None => self.selection.is_some_and(|s| {
+                        s.kind == SelectionKind::Linear
+                            && s.contains(self.cursor_line, self.cursor_col, line_idx, abs_col)
+                    }),
+                };
 
-                    let style = if is_cursor && self.cursor_blink_on {
-                        ratatui::style::Style::default()
-                            .bg(self.theme.cursor)
-                            .fg(self.theme.background)
+                let search_match = self
+                    .search_matches
+                    .iter()
+                    .find(|&&(l, start, end)| l == line_idx && (start..end).contains(&abs_col));
+
+                let fg = line_spans
+                    .iter()
+                    .find(|&&&(_, start, end, _)| (start..end).contains(&abs_col))
+                    .map_or(self.theme.foreground, |&&(.., kind)| {
+                        self.theme.token_color(kind)
+                    });
+
+                let style = if is_cursor && self.cursor_blink_on {
+                    ratatui::style::Style::default()
+                        .bg(self.theme.cursor)
+                        .fg(self.theme.background)
+                } else if self.bracket_cells.contains(&(line_idx, abs_col)) {
+                    ratatui::style::Style::default()
+                        .bg(self.theme.bracket_match)
+                        .fg(self.theme.foreground)
+                } else if let Some(&(_, start, _)) = search_match {
+                    let bg = if is_current_line && start == self.cursor_col {
+                        self.theme.search_current_bg
                     } else {
-                        ratatui::style::Style::default()
-                            .bg(if is_current_line {
-                                self.theme.cursor_line
-                            } else {
-                                self.theme.background
-                            })
-                            .fg(self.theme.foreground)
+                        self.theme.search_match_bg
                     };
+                    ratatui::style::Style::default()
+                        .bg(bg)
+                        .fg(self.theme.foreground)
+                } else if is_selected {
+                    ratatui::style::Style::default()
+                        .bg(self.theme.selection)
+                        .fg(self.theme.foreground)
+                } else if abs_col >= trailing_start {
+                    ratatui::style::Style::default().bg(trailing_bg).fg(fg)
+                } else {
+                    ratatui::style::Style::default().bg(line_bg).fg(fg)
+                };
 
-                    buf[(col as u16, pos_y)].set_char(c).set_style(style);
+                let whitespace = match g {
+                    _ if !self.show_whitespace => None,
+                    "\t" => Some("→"),
+                    " " if abs_col < indent_end || abs_col >= trailing_start => Some("·"),
+                    _ => None,
+                };
+                let (symbol, style) = match whitespace {
+                    Some(symbol) if is_cursor && self.cursor_blink_on => (symbol, style),
+                    Some(symbol) => (symbol, style.fg(self.theme.whitespace)),
+                    None if g.contains(char::is_control) => (" ", style),
+                    None => (g, style),
+                };
+                buf[(col as u16, pos_y)].set_symbol(symbol).set_style(style);
+                // A wide character covers the cells after it.
+                for hidden in 1..width {
+                    buf[(col as u16 + hidden as u16, pos_y)].reset();
                 }
             }
 
-            // Render cursor on empty line or at end of line
-            if is_current_line {
-                let cursor_pos = text_start + cursor_rel_col as u16;
-                if cursor_pos < inner.x + inner.width - 1 {
-                    let existing_char = if cursor_rel_col < line_text.len() {
-                        line_text.chars().nth(cursor_rel_col)
+            // Render cursor at end of line
+            let cursor_at_end = (is_current_line && self.cursor_col >= line_text.len())
+                || self
+                    .extra_cursors
+                    .iter()
+                    .any(|&(l, c)| l == line_idx && c >= line_text.len());
+            if last_row && cursor_at_end {
+                let row_x = display_width(&line_text[range], self.tab_width);
+                let cursor_pos = (text_start as usize + row_x).saturating_sub(display_col);
+                if cursor_pos < text_end {
+                    let style = if self.cursor_blink_on {
+                        ratatui::style::Style::default()
+                            .bg(self.theme.cursor)
+                            .fg(self.theme.background)
                     } else {
-                        None
+                        ratatui::style::Style::default()
+                            .bg(line_bg)
+                            .fg(self.theme.foreground)
                     };
-
-                    if self.cursor_blink_on {
-                        let cursor_char = existing_char.unwrap_or(' ');
-                        let style = ratatui::style::Style::default()
-                            .bg(self.theme.cursor)
-                            .fg(self.theme.background);
-
-                        buf[(cursor_pos, pos_y)]
-                            .set_char(cursor_char)
-                            .set_style(style);
-                    } else if existing_char.is_none() {
-                        buf[(cursor_pos, pos_y)].set_char(' ').set_style(
-                            ratatui::style::Style::default()
-                                .bg(self.theme.cursor_line)
-                                .fg(self.theme.foreground),
-                        );
-                    }
+                    buf[(cursor_pos as u16, pos_y)]
+                        .set_char(' ')
+                        .set_style(style);
                 }
             }
 
             // Draw vertical border on right
-            if line_idx < line_count {
-                let right_x = inner.x + inner.width - 1;
-                buf[(right_x, pos_y)]
-                    .set_char('│')
-                    .set_style(ratatui::style::Style::default().fg(self.theme.border));
-            }
+            let right_x = inner.x + inner.width - 1;
+            buf[(right_x, pos_y)]
+                .set_char('│')
+                .set_style(ratatui::style::Style::default().fg(self.theme.border));
         }
 
-        // Render scrollbar
-        if line_count > visible_lines {
-            let scrollbar_height = inner.height as f64;
-            let total_lines = line_count;
-            let scroll_ratio = (self.scroll_offset as f64 / total_lines as f64) * scrollbar_height;
-            let thumb_size =
-                ((visible_lines as f64 / total_lines as f64) * scrollbar_height).max(1.0) as u16;
-            let thumb_pos = scroll_ratio as u16;
-
-            for y in 0..inner.height {
-                let pos_y = inner.y + y as u16;
-                let thumb_start = thumb_pos as u16;
-                let thumb_end = thumb_start + thumb_size;
-                let style = if y >= thumb_start && y < thumb_end {
-                    ratatui::style::Style::default().fg(self.theme.accent)
+        // The thumb is as tall as the share of lines in view, and reaches
+        // the bottom when the last line does.
+        if self.show_scrollbar && line_count > visible_lines {
+            let max_scroll = line_count - visible_lines;
+            let thumb_size = (visible_lines * visible_lines / line_count).clamp(1, visible_lines);
+            let track = visible_lines - thumb_size;
+            let thumb_start =
+                (track * self.scroll_offset.min(max_scroll) + max_scroll / 2) / max_scroll;
+
+            for y in 0..visible_lines {
+                let (symbol, fg) = if (thumb_start..thumb_start + thumb_size).contains(&y) {
+                    ('█', self.theme.accent)
                 } else {
-                    ratatui::style::Style::default().fg(self.theme.scrollbar)
+                    ('│', self.theme.scrollbar)
                 };
-                buf[(inner.x + inner.width - 1, pos_y)]
-                    .set_char('█')
-                    .set_style(style);
+                buf[(inner.x + inner.width - 1, inner.y + y as u16)]
+                    .set_char(symbol)
+                    .set_style(ratatui::style::Style::default().fg(fg));
             }
         }
     }
@@ -253,6 +507,10 @@ impl Widget for TitleBar {
             .bg(self.theme.title_bg)
             .fg(self.theme.title_fg);
 
+        if area.width < 2 || area.height == 0 {
+            return;
+        }
+
         let title = format!(" {} ", self.file_name);
 
         // Draw left border
@@ -281,15 +539,131 @@ impl Widget for TitleBar {
     }
 }
 
+/// Longest file name shown on a tab before it is cut short with `…`.
+const MAX_TAB_NAME: usize = 20;
+
+pub struct TabBar {
+    /// File name and modified flag of each open buffer, in tab order.
+    pub tabs: Vec<(String, bool)>,
+    pub active: usize,
+    pub theme: Theme,
+}
+
+impl TabBar {
+    pub fn new() -> Self {
+        Self {
+            tabs: Vec::new(),
+            active: 0,
+            theme: Theme::monokai_pro(),
+        }
+    }
+
+    fn label(name: &str, modified: bool) -> String {
+        let name = if name.chars().count() > MAX_TAB_NAME {
+            let short: String = name.chars().take(MAX_TAB_NAME - 1).collect();
+            format!("{}…", short)
+        } else {
+            name.to_string()
+        };
+        format!(" {} {} ", name, if modified { "●" } else { " " })
+    }
+}
+
+impl Default for TabBar {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Widget for TabBar {
+    fn render(self, area: ratatui::layout::Rect, buf: &mut ratatui::buffer::Buffer) {
+        if area.width < 2 || area.height == 0 {
+            return;
+        }
+        let style = ratatui::style::Style::default()
+            .bg(self.theme.title_bg)
+            .fg(self.theme.title_fg);
+        let active_style = ratatui::style::Style::default()
+            .bg(self.theme.accent)
+            .fg(self.theme.background);
+
+        for x in 0..area.width {
+            buf[(area.x + x, area.y)].set_char(' ').set_style(style);
+        }
+
+        // Tabs are separated by `│`; leave room for a `…` on either side.
+        let labels: Vec<String> = self
+            .tabs
+            .iter()
+            .map(|(name, modified)| Self::label(name, *modified))
+            .collect();
+        let width = area.width as usize - 2;
+        let span = |range: std::ops::Range<usize>| -> usize {
+            labels[range.clone()]
+                .iter()
+                .map(|l| l.chars().count() + 1)
+                .sum::<usize>()
+                + usize::from(range.start > 0)
+                + usize::from(range.end < labels.len())
+        };
+        // Drop tabs from the left until the active one fits.
+        let mut first = 0;
+        while first < self.active && span(first..self.active + 1) > width {
+            first += 1;
+        }
+
+        let mut x = 1;
+        let mut put = |x: &mut usize, c: char, style| {
+            if *x < width + 1 {
+                buf[(area.x + *x as u16, area.y)]
+                    .set_char(c)
+                    .set_style(style);
+                *x += 1;
+            }
+        };
+        if first > 0 {
+            put(&mut x, '…', style);
+        }
+        for (i, label) in labels.iter().enumerate().skip(first) {
+            let needed = label.chars().count() + 1 + usize::from(i + 1 < labels.len());
+            if x + needed > width + 1 && i != self.active {
+                put(&mut x, '…', style);
+                break;
+            }
+            let tab_style = if i == self.active {
+                active_style
+            } else {
+                style
+            };
+            for c in label.chars() {
+                put(&mut x, c, tab_style);
+            }
+            put(&mut x, '│', style);
+        }
+    }
+}
+
 pub struct StatusBar {
     pub file_name: String,
     pub modified: bool,
     pub line: usize,
     pub col: usize,
+    pub total_lines: usize,
+    /// Size of the buffer in bytes.
+    pub size: usize,
+    /// Files are only ever loaded as UTF-8, but the bar shows it anyway.
+    pub encoding: &'static str,
+    pub line_ending: &'static str,
+    /// What Tab inserts, e.g. "Spaces: 4"; left out when empty.
+    pub indent: String,
+    pub read_only: bool,
+    /// Overwrite mode is on ("OVR" instead of "INS").
+    pub overwrite: bool,
     pub language: String,
     pub theme: Theme,
     pub search_mode: bool,
     pub search_text: String,
+    pub message: String,
 }
 
 impl StatusBar {
@@ -299,10 +673,18 @@ impl StatusBar {
             modified: false,
             line: 1,
             col: 1,
+            total_lines: 1,
+            size: 0,
+            encoding: "UTF-8",
+            line_ending: "LF",
+            indent: String::new(),
+            read_only: false,
+            overwrite: false,
             language: "plaintext".to_string(),
             theme: Theme::monokai_pro(),
             search_mode: false,
             search_text: String::new(),
+            message: String::new(),
         }
     }
 }
@@ -315,6 +697,9 @@ impl Default for StatusBar {
 
 impl Widget for StatusBar {
     fn render(self, area: ratatui::layout::Rect, buf: &mut ratatui::buffer::Buffer) {
+        if area.width < 2 || area.height == 0 {
+            return;
+        }
         let width = area.width as usize;
 
         let style = ratatui::style::Style::default()
@@ -331,10 +716,13 @@ impl Widget for StatusBar {
         }
 
         let (left, right) = if self.search_mode {
-            (
-                format!(" Search: {}", self.search_text),
-                "ESC cancel | ENTER go".to_string(),
-            )
+            // Messages such as a match count replace the key hints.
+            let right = if self.message.is_empty() {
+                "ESC cancel | ENTER go".to_string()
+            } else {
+                format!("{} ", self.message)
+            };
+            (format!(" Search: {}", self.search_text), right)
         } else {
             let file_icon = if self.modified { "●" } else { "○" };
             let file_info = if self.file_name.is_empty() || self.file_name == "[No Name]" {
@@ -342,17 +730,42 @@ impl Widget for StatusBar {
             } else {
                 self.file_name.clone()
             };
-            (
-                format!(" {} {} ", file_icon, file_info),
-                format!(
-                    " Ln {:>width$} Col {:>width2$} │ {:^10} ",
-                    self.line,
-                    self.col,
-                    self.language.to_uppercase(),
-                    width = 4,
-                    width2 = 3
-                ),
-            )
+            let left = if self.message.is_empty() {
+                format!(" {} {} ", file_icon, file_info)
+            } else {
+                format!(" {} {} │ {} ", file_icon, file_info, self.message)
+            };
+            let position = format!(
+                " {} │ Ln {:>width$}/{} Col {:>width2$} │ ",
+                if self.overwrite { "OVR" } else { "INS" },
+                self.line,
+                self.total_lines,
+                self.col,
+                width = 4,
+                width2 = 3
+            );
+            let mut cluster = format!("{} | {}", self.encoding, self.line_ending);
+            if !self.indent.is_empty() {
+                cluster.push_str(&format!(" | {}", self.indent));
+            }
+            if self.read_only {
+                cluster.push_str(" | RO");
+            }
+            let language = format!("{:^10} ", self.language.to_uppercase());
+            // On narrow terminals the file size goes first, then the cluster.
+            let full = format!(
+                "{}{} │ {} │ {}",
+                position,
+                cluster,
+                format_size(self.size),
+                language
+            );
+            let without_size = format!("{}{} │ {}", position, cluster, language);
+            let right = [full, without_size]
+                .into_iter()
+                .find(|r| r.chars().count() <= width / 2)
+                .unwrap_or_else(|| format!("{}{}", position, language));
+            (left, right)
         };
 
         // Left side
@@ -368,7 +781,7 @@ impl Widget for StatusBar {
         }
 
         // Right side
-        let right_start = width.saturating_sub(right.len());
+        let right_start = width.saturating_sub(right.chars().count());
         for (x, c) in right.chars().enumerate() {
             let pos = right_start + x;
             if pos < width {
@@ -412,7 +825,7 @@ impl Default for HelpBar {
 
 impl Widget for HelpBar {
     fn render(self, area: ratatui::layout::Rect, buf: &mut ratatui::buffer::Buffer) {
-        if !self.visible {
+        if !self.visible || area.width < 2 || area.height == 0 {
             return;
         }
 
@@ -462,7 +875,7 @@ impl Widget for HelpBar {
             }
 
             // Separator between groups
-            if x_pos < area.width as usize - 3 {
+            if x_pos + 3 < area.width as usize {
                 buf[(area.x + x_pos as u16, area.y)]
                     .set_char(' ')
                     .set_style(style);
@@ -496,3 +909,209 @@ impl Widget for HelpBar {
             .set_style(style);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn render_tabs(tabs: &[(&str, bool)], active: usize, width: u16) -> String {
+        let area = ratatui::layout::Rect::new(0, 0, width, 1);
+        let mut buf = ratatui::buffer::Buffer::empty(area);
+        TabBar {
+            tabs: tabs.iter().map(|&(n, m)| (n.to_string(), m)).collect(),
+            active,
+            theme: Theme::monokai_pro(),
+        }
+        .render(area, &mut buf);
+        (0..width).map(|x| buf[(x, 0)].symbol()).collect()
+    }
+
+    /// A 20x4 view of `buffer` with the cursor at the start of line 1.
+    fn editor_view(buffer: &Buffer) -> EditorView<'_> {
+        EditorView {
+            buffer,
+            cursor_line: 1,
+            cursor_col: 0,
+            show_line_numbers: false,
+            scroll_offset: 0,
+            col_offset: 0,
+            selection: None,
+            search_matches: vec![],
+            bracket_cells: vec![],
+            syntax_spans: vec![],
+            theme: Theme::monokai_pro(),
+            cursor_blink_on: true,
+            tab_width: 4,
+            word_wrap: false,
+            extra_cursors: vec![],
+            show_whitespace: false,
+            highlight_trailing_whitespace: false,
+            trailing_whitespace_on_current_line: false,
+            highlight_current_line: true,
+            show_scrollbar: true,
+            line_changes: vec![],
+        }
+    }
+
+    fn render_view(view: EditorView) -> ratatui::buffer::Buffer {
+        let area = ratatui::layout::Rect::new(0, 0, 20, 4);
+        let mut buf = ratatui::buffer::Buffer::empty(area);
+        view.render(area, &mut buf);
+        buf
+    }
+
+    #[test]
+    fn whitespace_is_drawn_only_when_enabled() {
+        let mut buffer = Buffer::new();
+        buffer.insert(0, " \tif a  b  \nx");
+        let row = |show_whitespace: bool| {
+            let buf = render_view(EditorView {
+                show_whitespace,
+                ..editor_view(&buffer)
+            });
+            (0..20).map(|x| buf[(x, 1)].symbol()).collect::<String>()
+        };
+        assert!(row(true).contains("·→  if a  b··"));
+        assert!(row(false).contains("    if a  b  "));
+    }
+
+    #[test]
+    fn trailing_whitespace_is_highlighted_off_the_cursor_line() {
+        let mut buffer = Buffer::new();
+        buffer.insert(0, "a \nb\t ");
+        let theme = Theme::monokai_pro();
+        let bg = |on_current_line: bool, x: u16, y: u16| {
+            let buf = render_view(EditorView {
+                highlight_trailing_whitespace: true,
+                trailing_whitespace_on_current_line: on_current_line,
+                ..editor_view(&buffer)
+            });
+            buf[(x, y)].bg
+        };
+        assert_eq!(bg(false, 4, 1), theme.trailing_whitespace);
+        assert_eq!(bg(false, 3, 1), theme.background);
+        // Line 1 holds the cursor.
+        assert_eq!(bg(false, 7, 2), theme.cursor_line);
+        assert_eq!(bg(true, 7, 2), theme.trailing_whitespace);
+    }
+
+    #[test]
+    fn wrapping_breaks_after_whitespace() {
+        assert_eq!(wrap_rows("one two three", 9, 4), vec![0, 8]);
+        assert_eq!(wrap_rows("one two", 4, 4), vec![0, 4]);
+        assert_eq!(wrap_rows("abcdefghij kl", 4, 4), vec![0, 4, 8, 11]);
+
+        let mut buffer = Buffer::new();
+        buffer.insert(0, "one two three");
+        let buf = render_view(EditorView {
+            cursor_line: 0,
+            show_line_numbers: true,
+            word_wrap: true,
+            ..editor_view(&buffer)
+        });
+        let row = |y: u16| (0..20).map(|x| buf[(x, y)].symbol()).collect::<String>();
+        assert!(row(1).starts_with("│  1 │one two "));
+        assert!(row(2).starts_with("│  ↪ │three"));
+    }
+
+    #[test]
+    fn text_starts_where_text_columns_says() {
+        let mut buffer = Buffer::new();
+        buffer.insert(0, &"x\n".repeat(1200));
+        for (show_line_numbers, word_wrap) in [(true, false), (true, true), (false, true)] {
+            let buf = render_view(EditorView {
+                cursor_line: 0,
+                scroll_offset: 1100,
+                show_line_numbers,
+                word_wrap,
+                ..editor_view(&buffer)
+            });
+            let columns = text_columns(20, buffer.num_lines(), show_line_numbers);
+            assert_eq!(buf[(columns.start, 1)].symbol(), "x");
+            assert_eq!(buf[(columns.start + 1, 1)].symbol(), " ");
+        }
+    }
+
+    #[test]
+    fn scrollbar_thumb_spans_the_track() {
+        let mut buffer = Buffer::new();
+        buffer.insert(0, "1\n2\n3\n4\n5\n6\n7\n8");
+        let column = |scroll_offset: usize, show_scrollbar: bool| {
+            let buf = render_view(EditorView {
+                scroll_offset,
+                show_scrollbar,
+                ..editor_view(&buffer)
+            });
+            (1..3).map(|y| buf[(18, y)].symbol()).collect::<String>()
+        };
+        assert_eq!(column(0, true), "█│");
+        assert_eq!(column(2, true), "█│");
+        assert_eq!(column(3, true), "│█");
+        assert_eq!(column(6, true), "│█");
+        assert_eq!(column(6, false), "││");
+    }
+
+    #[test]
+    fn current_line_highlight_follows_the_setting() {
+        let mut buffer = Buffer::new();
+        buffer.insert(0, "a\nbc");
+        let theme = Theme::monokai_pro();
+        for highlight_current_line in [true, false] {
+            let buf = render_view(EditorView {
+                show_line_numbers: true,
+                highlight_current_line,
+                ..editor_view(&buffer)
+            });
+            let expected = if highlight_current_line {
+                theme.cursor_line
+            } else {
+                theme.background
+            };
+            // The gutter and the "c" after the cursor on line 1.
+            assert_eq!(buf[(1, 2)].bg, expected);
+            assert_eq!(buf[(7, 2)].symbol(), "c");
+            assert_eq!(buf[(7, 2)].bg, expected);
+            assert_eq!(buf[(7, 1)].bg, theme.background);
+        }
+    }
+
+    #[test]
+    fn status_bar_shows_size_when_it_fits() {
+        assert_eq!(format_size(512), "512 B");
+        assert_eq!(format_size(4300), "4.2 KB");
+        assert_eq!(format_size(3 * 1024 * 1024), "3.0 MB");
+
+        let render = |width: u16| {
+            let area = ratatui::layout::Rect::new(0, 0, width, 1);
+            let mut buf = ratatui::buffer::Buffer::empty(area);
+            StatusBar {
+                line: 12,
+                col: 5,
+                total_lines: 340,
+                size: 4300,
+                language: "rust".to_string(),
+                ..StatusBar::new()
+            }
+            .render(area, &mut buf);
+            (0..width)
+                .map(|x| buf[(x, 0)].symbol().to_string())
+                .collect::<String>()
+        };
+        assert!(
+            render(140).ends_with(" INS │ Ln   12/340 Col   5 │ UTF-8 | LF │ 4.2 KB │    RUST   │")
+        );
+        assert!(render(110).ends_with(" INS │ Ln   12/340 Col   5 │ UTF-8 | LF │    RUST   │"));
+        assert!(render(60).ends_with(" INS │ Ln   12/340 Col   5 │    RUST   │"));
+    }
+
+    #[test]
+    fn tab_bar_elides_tabs_that_do_not_fit() {
+        let tabs = [
+            ("main.rs", true),
+            ("lib.rs", false),
+            ("a_very_long_file_name.rs", false),
+        ];
+        assert_eq!(render_tabs(&tabs, 0, 30), "  main.rs ● │ lib.rs   │…     ");
+        assert_eq!(render_tabs(&tabs, 2, 30), " … a_very_long_file_na…   │   ");
+    }
+}